@@ -0,0 +1,834 @@
+// Image container formats for generated images
+//
+// All encoders are hand-written or built on pure-Rust compression so they
+// compile unchanged for wasm32.
+//
+// Images can carry key/value text metadata. PNG stores it in tEXt/iTXt
+// chunks; BMP and QOI have no metadata blocks, so it is appended after the
+// image data as a trailer (JSON, u32 LE length, METADATA_TRAILER_MAGIC),
+// which decoders ignore.
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+const METADATA_TRAILER_MAGIC: &[u8; 8] = b"ICSDMETA";
+
+// Upper bound for inflating compressed PNG text chunks
+const MAX_TEXT_CHUNK_SIZE: usize = 1024 * 1024;
+
+// 8-bit RGB pixels, row-major from the top-left corner
+#[derive(Clone, Debug)]
+pub struct RgbImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl RgbImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; (width * height * 3) as usize],
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, rgb: [u8; 3]) {
+        let offset = ((y * self.width + x) * 3) as usize;
+        self.pixels[offset..offset + 3].copy_from_slice(&rgb);
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub enum OutputFormat {
+    #[default]
+    Bmp,
+    Png,
+    Qoi,
+}
+
+impl OutputFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Bmp => "image/bmp",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Qoi => "image/qoi",
+        }
+    }
+}
+
+pub fn encode(image: &RgbImage, format: OutputFormat, metadata: &[(String, String)]) -> Vec<u8> {
+    match format {
+        OutputFormat::Bmp => append_metadata_trailer(encode_bmp(image), metadata),
+        OutputFormat::Png => encode_png(image, metadata),
+        OutputFormat::Qoi => append_metadata_trailer(encode_qoi(image), metadata),
+    }
+}
+
+// Extract the text metadata embedded in an encoded image
+pub fn read_metadata(data: &[u8]) -> Result<Vec<(String, String)>, String> {
+    if data.starts_with(PNG_SIGNATURE) {
+        read_png_text_chunks(data)
+    } else if data.starts_with(b"BM") || data.starts_with(b"qoif") {
+        read_metadata_trailer(data)
+    } else {
+        Err("Unsupported image format".to_string())
+    }
+}
+
+fn append_metadata_trailer(mut data: Vec<u8>, metadata: &[(String, String)]) -> Vec<u8> {
+    if metadata.is_empty() {
+        return data;
+    }
+
+    let json = serde_json::to_vec(metadata).unwrap_or_default();
+    data.extend_from_slice(&json);
+    data.extend_from_slice(&(json.len() as u32).to_le_bytes());
+    data.extend_from_slice(METADATA_TRAILER_MAGIC);
+    data
+}
+
+fn read_metadata_trailer(data: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let Some(rest) = data.strip_suffix(METADATA_TRAILER_MAGIC) else {
+        return Ok(Vec::new());
+    };
+    let (rest, len) = rest
+        .split_last_chunk::<4>()
+        .ok_or_else(|| "Truncated metadata trailer".to_string())?;
+    let len = u32::from_le_bytes(*len) as usize;
+    let json = rest
+        .get(rest.len().saturating_sub(len)..)
+        .filter(|json| json.len() == len)
+        .ok_or_else(|| "Truncated metadata trailer".to_string())?;
+
+    serde_json::from_slice(json).map_err(|e| format!("Invalid metadata trailer: {}", e))
+}
+
+// Uncompressed 24-bit BMP
+pub fn encode_bmp(image: &RgbImage) -> Vec<u8> {
+    let (width, height) = (image.width, image.height);
+    let row_padding = (4 - (width * 3) % 4) % 4;
+    let pixel_data_size = (width * 3 + row_padding) * height;
+
+    let mut bmp_data = Vec::with_capacity(54 + pixel_data_size as usize);
+
+    // BMP file header (14 bytes)
+    bmp_data.extend_from_slice(b"BM"); // Signature
+    let file_size = 54 + pixel_data_size; // Header + pixel data
+    bmp_data.extend_from_slice(&file_size.to_le_bytes());
+    bmp_data.extend_from_slice(&[0, 0, 0, 0]); // Reserved
+    bmp_data.extend_from_slice(&54u32.to_le_bytes()); // Offset to pixel data
+
+    // DIB header (40 bytes)
+    bmp_data.extend_from_slice(&40u32.to_le_bytes()); // Header size
+    bmp_data.extend_from_slice(&width.to_le_bytes());
+    bmp_data.extend_from_slice(&height.to_le_bytes());
+    bmp_data.extend_from_slice(&1u16.to_le_bytes()); // Color planes
+    bmp_data.extend_from_slice(&24u16.to_le_bytes()); // Bits per pixel
+    bmp_data.extend_from_slice(&0u32.to_le_bytes()); // Compression
+    bmp_data.extend_from_slice(&pixel_data_size.to_le_bytes()); // Image size
+    bmp_data.extend_from_slice(&2835u32.to_le_bytes()); // X pixels per meter
+    bmp_data.extend_from_slice(&2835u32.to_le_bytes()); // Y pixels per meter
+    bmp_data.extend_from_slice(&0u32.to_le_bytes()); // Colors used
+    bmp_data.extend_from_slice(&0u32.to_le_bytes()); // Important colors
+
+    // Pixel data (BGR format, bottom-to-top)
+    let row_len = (width * 3) as usize;
+    for row in image.pixels.chunks_exact(row_len).rev() {
+        for rgb in row.chunks_exact(3) {
+            bmp_data.extend_from_slice(&[rgb[2], rgb[1], rgb[0]]);
+        }
+        bmp_data.resize(bmp_data.len() + row_padding as usize, 0);
+    }
+
+    bmp_data
+}
+
+// PNG (8-bit truecolour, adaptive filtering, zlib-compressed)
+pub fn encode_png(image: &RgbImage, metadata: &[(String, String)]) -> Vec<u8> {
+    let mut png_data = Vec::new();
+    png_data.extend_from_slice(PNG_SIGNATURE);
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&image.width.to_be_bytes());
+    ihdr.extend_from_slice(&image.height.to_be_bytes());
+    ihdr.push(8); // Bit depth
+    ihdr.push(2); // Colour type: truecolour
+    ihdr.push(0); // Compression method: deflate
+    ihdr.push(0); // Filter method: adaptive
+    ihdr.push(0); // Interlace method: none
+    write_png_chunk(&mut png_data, b"IHDR", &ihdr);
+
+    for (keyword, text) in metadata {
+        write_png_text_chunk(&mut png_data, keyword, text);
+    }
+
+    let filtered = filter_png_scanlines(image);
+    let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&filtered, 6);
+    write_png_chunk(&mut png_data, b"IDAT", &compressed);
+
+    write_png_chunk(&mut png_data, b"IEND", &[]);
+    png_data
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    out.extend_from_slice(chunk_type);
+    out.extend_from_slice(data);
+
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(chunk_type);
+    hasher.update(data);
+    out.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+// ASCII text goes into tEXt; anything else into an uncompressed UTF-8 iTXt
+fn write_png_text_chunk(out: &mut Vec<u8>, keyword: &str, text: &str) {
+    let mut data = Vec::with_capacity(keyword.len() + text.len() + 5);
+    data.extend_from_slice(keyword.as_bytes());
+    data.push(0);
+
+    if text.is_ascii() {
+        data.extend_from_slice(text.as_bytes());
+        write_png_chunk(out, b"tEXt", &data);
+    } else {
+        data.push(0); // Compression flag
+        data.push(0); // Compression method
+        data.push(0); // Empty language tag
+        data.push(0); // Empty translated keyword
+        data.extend_from_slice(text.as_bytes());
+        write_png_chunk(out, b"iTXt", &data);
+    }
+}
+
+fn read_png_text_chunks(data: &[u8]) -> Result<Vec<(String, String)>, String> {
+    let mut entries = Vec::new();
+    let mut offset = PNG_SIGNATURE.len();
+
+    while offset + 8 <= data.len() {
+        let (chunk_type, body, next) = read_png_chunk(data, offset)?;
+        match chunk_type {
+            b"tEXt" => {
+                let (keyword, text) = split_null(body)?;
+                entries.push((latin1(keyword), latin1(text)));
+            }
+            b"zTXt" => {
+                let (keyword, rest) = split_null(body)?;
+                let text = inflate_text(rest.get(1..).unwrap_or_default())?;
+                entries.push((latin1(keyword), latin1(&text)));
+            }
+            b"iTXt" => {
+                let (keyword, rest) = split_null(body)?;
+                let (&compressed, rest) = rest
+                    .split_first()
+                    .ok_or_else(|| "Truncated iTXt chunk".to_string())?;
+                let (_language, rest) = split_null(rest.get(1..).unwrap_or_default())?;
+                let (_translated, text) = split_null(rest)?;
+                let text = if compressed == 1 {
+                    inflate_text(text)?
+                } else {
+                    text.to_vec()
+                };
+                entries.push((latin1(keyword), String::from_utf8_lossy(&text).into_owned()));
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        offset = next;
+    }
+
+    Ok(entries)
+}
+
+// Type and body of the chunk at `offset`, and the offset of the next chunk.
+// The length comes from the file, so the end is computed with checked_add
+// to reject lengths that overflow usize on wasm32.
+fn read_png_chunk(data: &[u8], offset: usize) -> Result<(&[u8], &[u8], usize), String> {
+    let len = read_u32_be(data, offset)? as usize;
+    let chunk_type = data
+        .get(offset + 4..offset + 8)
+        .ok_or_else(|| "Truncated PNG chunk".to_string())?;
+    let start = offset + 8;
+    let body = start
+        .checked_add(len)
+        .and_then(|end| data.get(start..end))
+        .ok_or_else(|| "Truncated PNG chunk".to_string())?;
+    // The body ends inside `data`, so adding the CRC cannot overflow
+    Ok((chunk_type, body, start + len + 4))
+}
+
+fn split_null(bytes: &[u8]) -> Result<(&[u8], &[u8]), String> {
+    let pos = bytes
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| "Malformed PNG text chunk".to_string())?;
+    Ok((&bytes[..pos], &bytes[pos + 1..]))
+}
+
+fn latin1(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn inflate_text(compressed: &[u8]) -> Result<Vec<u8>, String> {
+    miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(compressed, MAX_TEXT_CHUNK_SIZE)
+        .map_err(|_| "Invalid compressed PNG text chunk".to_string())
+}
+
+// Filter each scanline with whichever PNG filter gives the smallest sum of
+// absolute residuals, the heuristic recommended by the PNG specification
+fn filter_png_scanlines(image: &RgbImage) -> Vec<u8> {
+    const BPP: usize = 3;
+    let row_len = (image.width * 3) as usize;
+    let zero_row = vec![0u8; row_len];
+
+    let mut out = Vec::with_capacity((row_len + 1) * image.height as usize);
+    let mut candidate = vec![0u8; row_len];
+    let mut best = vec![0u8; row_len];
+
+    for (y, row) in image.pixels.chunks_exact(row_len).enumerate() {
+        let prior = if y == 0 {
+            &zero_row[..]
+        } else {
+            &image.pixels[(y - 1) * row_len..y * row_len]
+        };
+
+        let mut best_filter = 0u8;
+        let mut best_score = u64::MAX;
+        for filter in 0..5u8 {
+            for i in 0..row_len {
+                let a = if i >= BPP { row[i - BPP] } else { 0 };
+                let b = prior[i];
+                let c = if i >= BPP { prior[i - BPP] } else { 0 };
+                let predicted = match filter {
+                    0 => 0,
+                    1 => a,
+                    2 => b,
+                    3 => ((a as u16 + b as u16) / 2) as u8,
+                    _ => paeth_predictor(a, b, c),
+                };
+                candidate[i] = row[i].wrapping_sub(predicted);
+            }
+
+            let score: u64 = candidate
+                .iter()
+                .map(|&v| (v as i8).unsigned_abs() as u64)
+                .sum();
+            if score < best_score {
+                best_score = score;
+                best_filter = filter;
+                best.copy_from_slice(&candidate);
+            }
+        }
+
+        out.push(best_filter);
+        out.extend_from_slice(&best);
+    }
+
+    out
+}
+
+fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+// QOI, "The Quite OK Image Format" (https://qoiformat.org)
+pub fn encode_qoi(image: &RgbImage) -> Vec<u8> {
+    const QOI_OP_INDEX: u8 = 0x00;
+    const QOI_OP_DIFF: u8 = 0x40;
+    const QOI_OP_LUMA: u8 = 0x80;
+    const QOI_OP_RUN: u8 = 0xc0;
+    const QOI_OP_RGB: u8 = 0xfe;
+
+    let mut qoi_data = Vec::with_capacity(14 + image.pixels.len() + 8);
+    qoi_data.extend_from_slice(b"qoif");
+    qoi_data.extend_from_slice(&image.width.to_be_bytes());
+    qoi_data.extend_from_slice(&image.height.to_be_bytes());
+    qoi_data.push(3); // Channels: RGB
+    qoi_data.push(0); // Colour space: sRGB with linear alpha
+
+    let mut index = [[0u8; 4]; 64];
+    let mut previous = [0u8; 3];
+    let mut run = 0u8;
+    let pixel_count = image.pixels.len() / 3;
+
+    for (i, px) in image.pixels.chunks_exact(3).enumerate() {
+        let px = [px[0], px[1], px[2]];
+
+        if px == previous {
+            run += 1;
+            if run == 62 || i == pixel_count - 1 {
+                qoi_data.push(QOI_OP_RUN | (run - 1));
+                run = 0;
+            }
+            continue;
+        }
+
+        if run > 0 {
+            qoi_data.push(QOI_OP_RUN | (run - 1));
+            run = 0;
+        }
+
+        // Alpha is always 255, contributing 255 * 11 to the hash
+        let hash = (px[0] as usize * 3 + px[1] as usize * 5 + px[2] as usize * 7 + 255 * 11) % 64;
+        let rgba = [px[0], px[1], px[2], 255];
+        if index[hash] == rgba {
+            qoi_data.push(QOI_OP_INDEX | hash as u8);
+        } else {
+            index[hash] = rgba;
+
+            let dr = px[0].wrapping_sub(previous[0]) as i8;
+            let dg = px[1].wrapping_sub(previous[1]) as i8;
+            let db = px[2].wrapping_sub(previous[2]) as i8;
+            let dr_dg = dr.wrapping_sub(dg);
+            let db_dg = db.wrapping_sub(dg);
+
+            if (-2..=1).contains(&dr) && (-2..=1).contains(&dg) && (-2..=1).contains(&db) {
+                qoi_data.push(
+                    QOI_OP_DIFF | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8,
+                );
+            } else if (-32..=31).contains(&dg)
+                && (-8..=7).contains(&dr_dg)
+                && (-8..=7).contains(&db_dg)
+            {
+                qoi_data.push(QOI_OP_LUMA | (dg + 32) as u8);
+                qoi_data.push(((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8);
+            } else {
+                qoi_data.push(QOI_OP_RGB);
+                qoi_data.extend_from_slice(&px);
+            }
+        }
+
+        previous = px;
+    }
+
+    qoi_data.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+    qoi_data
+}
+
+// Decoding, for init images. Covers what the canister writes plus the common
+// variants other tools produce: 8/16-bit and palette PNG, 24/32-bit BMP and
+// RGB(A) QOI. Alpha is dropped.
+
+// Upper bound on decoded image size, to refuse decompression bombs
+const MAX_DECODED_PIXELS: u64 = 4096 * 4096;
+
+// Format of an encoded image, from its signature
+pub fn detect_format(data: &[u8]) -> Option<OutputFormat> {
+    if data.starts_with(PNG_SIGNATURE) {
+        Some(OutputFormat::Png)
+    } else if data.starts_with(b"BM") {
+        Some(OutputFormat::Bmp)
+    } else if data.starts_with(b"qoif") {
+        Some(OutputFormat::Qoi)
+    } else {
+        None
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<RgbImage, String> {
+    match detect_format(data) {
+        Some(OutputFormat::Png) => decode_png(data),
+        Some(OutputFormat::Bmp) => decode_bmp(data),
+        Some(OutputFormat::Qoi) => decode_qoi(data),
+        None => Err("Unsupported image format".to_string()),
+    }
+}
+
+fn checked_image(width: u32, height: u32) -> Result<RgbImage, String> {
+    if width == 0 || height == 0 || width as u64 * height as u64 > MAX_DECODED_PIXELS {
+        return Err(format!("Unsupported image size {}x{}", width, height));
+    }
+    Ok(RgbImage::new(width, height))
+}
+
+fn read_u32_be(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "Truncated image".to_string())
+}
+
+fn read_u32_le(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| "Truncated image".to_string())
+}
+
+fn decode_png(data: &[u8]) -> Result<RgbImage, String> {
+    let mut header = None;
+    let mut palette: &[u8] = &[];
+    let mut compressed = Vec::new();
+
+    let mut offset = PNG_SIGNATURE.len();
+    while offset + 8 <= data.len() {
+        let (chunk_type, body, next) = read_png_chunk(data, offset)?;
+        match chunk_type {
+            b"IHDR" if body.len() == 13 => header = Some(body),
+            b"PLTE" => palette = body,
+            b"IDAT" => compressed.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {}
+        }
+        offset = next;
+    }
+
+    let header = header.ok_or_else(|| "PNG has no header".to_string())?;
+    let width = read_u32_be(header, 0)?;
+    let height = read_u32_be(header, 4)?;
+    let (depth, colour_type, interlace) = (header[8], header[9], header[12]);
+    let channels = match (colour_type, depth) {
+        (0, 8 | 16) => 1,
+        (2, 8 | 16) => 3,
+        (3, 8) => 1,
+        (4, 8 | 16) => 2,
+        (6, 8 | 16) => 4,
+        _ => {
+            return Err(format!(
+                "Unsupported PNG colour type {} at bit depth {}",
+                colour_type, depth
+            ));
+        }
+    };
+    if interlace != 0 {
+        return Err("Interlaced PNGs are not supported".to_string());
+    }
+    let mut image = checked_image(width, height)?;
+
+    let bpp = channels * depth as usize / 8;
+    let row_len = width as usize * bpp;
+    let raw = miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(
+        &compressed,
+        (row_len + 1) * height as usize,
+    )
+    .map_err(|_| "Invalid PNG image data".to_string())?;
+    if raw.len() != (row_len + 1) * height as usize {
+        return Err("PNG image data has the wrong size".to_string());
+    }
+
+    let mut prior = vec![0u8; row_len];
+    let mut row = vec![0u8; row_len];
+    for (y, scanline) in raw.chunks_exact(row_len + 1).enumerate() {
+        let filter = scanline[0];
+        for i in 0..row_len {
+            let a = if i >= bpp { row[i - bpp] } else { 0 };
+            let b = prior[i];
+            let c = if i >= bpp { prior[i - bpp] } else { 0 };
+            let predicted = match filter {
+                0 => 0,
+                1 => a,
+                2 => b,
+                3 => ((a as u16 + b as u16) / 2) as u8,
+                4 => paeth_predictor(a, b, c),
+                _ => return Err(format!("Invalid PNG filter {}", filter)),
+            };
+            row[i] = scanline[i + 1].wrapping_add(predicted);
+        }
+
+        for x in 0..width as usize {
+            // High byte of each sample
+            let sample = |channel: usize| row[x * bpp + channel * depth as usize / 8];
+            let rgb = match colour_type {
+                0 | 4 => [sample(0); 3],
+                3 => {
+                    let entry = sample(0) as usize * 3;
+                    palette
+                        .get(entry..entry + 3)
+                        .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+                        .ok_or_else(|| "PNG palette index out of range".to_string())?
+                }
+                _ => [sample(0), sample(1), sample(2)],
+            };
+            image.set_pixel(x as u32, y as u32, rgb);
+        }
+        std::mem::swap(&mut prior, &mut row);
+    }
+
+    Ok(image)
+}
+
+fn decode_bmp(data: &[u8]) -> Result<RgbImage, String> {
+    let pixel_offset = read_u32_le(data, 10)? as usize;
+    let width = read_u32_le(data, 18)? as i32;
+    let height = read_u32_le(data, 22)? as i32;
+    let bits = data
+        .get(28..30)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| "Truncated image".to_string())?;
+    let compression = read_u32_le(data, 30)?;
+    if !matches!(bits, 24 | 32) || compression != 0 {
+        return Err(format!(
+            "Unsupported BMP: {} bits per pixel, compression {}",
+            bits, compression
+        ));
+    }
+    if width <= 0 {
+        return Err("Invalid BMP width".to_string());
+    }
+
+    // Rows are stored bottom-up unless the height is negative
+    let top_down = height < 0;
+    let (width, height) = (width as u32, height.unsigned_abs());
+    let mut image = checked_image(width, height)?;
+    let bytes_per_pixel = bits as usize / 8;
+    let stride = (width as usize * bytes_per_pixel).div_ceil(4) * 4;
+
+    for row in 0..height as usize {
+        let start = pixel_offset + row * stride;
+        let pixels = data
+            .get(start..start + width as usize * bytes_per_pixel)
+            .ok_or_else(|| "Truncated BMP pixel data".to_string())?;
+        let y = if top_down {
+            row
+        } else {
+            height as usize - 1 - row
+        };
+        for (x, bgr) in pixels.chunks_exact(bytes_per_pixel).enumerate() {
+            image.set_pixel(x as u32, y as u32, [bgr[2], bgr[1], bgr[0]]);
+        }
+    }
+
+    Ok(image)
+}
+
+fn decode_qoi(data: &[u8]) -> Result<RgbImage, String> {
+    let width = read_u32_be(data, 4)?;
+    let height = read_u32_be(data, 8)?;
+    let mut image = checked_image(width, height)?;
+
+    let mut index = [[0u8; 4]; 64];
+    let mut pixel = [0u8, 0, 0, 255];
+    let mut offset = 14;
+    let mut run = 0u8;
+    let next = |offset: &mut usize| -> Result<u8, String> {
+        let byte = *data
+            .get(*offset)
+            .ok_or_else(|| "Truncated QOI data".to_string())?;
+        *offset += 1;
+        Ok(byte)
+    };
+
+    for i in 0..image.pixels.len() / 3 {
+        if run > 0 {
+            run -= 1;
+        } else {
+            let op = next(&mut offset)?;
+            match op {
+                0xfe => {
+                    for channel in pixel.iter_mut().take(3) {
+                        *channel = next(&mut offset)?;
+                    }
+                }
+                0xff => {
+                    for channel in pixel.iter_mut() {
+                        *channel = next(&mut offset)?;
+                    }
+                }
+                _ => match op >> 6 {
+                    0 => pixel = index[op as usize],
+                    1 => {
+                        pixel[0] = pixel[0].wrapping_add((op >> 4) & 3).wrapping_sub(2);
+                        pixel[1] = pixel[1].wrapping_add((op >> 2) & 3).wrapping_sub(2);
+                        pixel[2] = pixel[2].wrapping_add(op & 3).wrapping_sub(2);
+                    }
+                    2 => {
+                        let dg = (op & 0x3f).wrapping_sub(32);
+                        let second = next(&mut offset)?;
+                        pixel[0] = pixel[0]
+                            .wrapping_add(dg)
+                            .wrapping_add(second >> 4)
+                            .wrapping_sub(8);
+                        pixel[1] = pixel[1].wrapping_add(dg);
+                        pixel[2] = pixel[2]
+                            .wrapping_add(dg)
+                            .wrapping_add(second & 0x0f)
+                            .wrapping_sub(8);
+                    }
+                    _ => run = op & 0x3f,
+                },
+            }
+            let hash = (pixel[0] as usize * 3
+                + pixel[1] as usize * 5
+                + pixel[2] as usize * 7
+                + pixel[3] as usize * 11)
+                % 64;
+            index[hash] = pixel;
+        }
+        image.pixels[i * 3..i * 3 + 3].copy_from_slice(&pixel[..3]);
+    }
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_image() -> RgbImage {
+        let mut image = RgbImage::new(7, 5);
+        for y in 0..5 {
+            for x in 0..7 {
+                let v = (x * 37 + y * 11) as u8;
+                // Flat runs, small steps and jumps exercise every encoding op
+                let rgb = if y == 2 {
+                    [9, 9, 9]
+                } else {
+                    [v, v.wrapping_mul(3), 255 - v]
+                };
+                image.set_pixel(x, y, rgb);
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let image = sample_image();
+        let metadata = [("key".to_string(), "value".to_string())];
+        for format in [OutputFormat::Bmp, OutputFormat::Png, OutputFormat::Qoi] {
+            let decoded = decode(&encode(&image, format, &metadata)).unwrap();
+            assert_eq!((decoded.width, decoded.height), (7, 5));
+            assert_eq!(decoded.pixels, image.pixels, "{:?}", format);
+        }
+        assert!(decode(b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn text_chunks_read_back() {
+        let image = sample_image();
+        let metadata = [
+            ("Software".to_string(), "ic-stable-diff/0.1".to_string()),
+            (
+                "parameters".to_string(),
+                "café au lait, 20 steps".to_string(),
+            ),
+            ("empty".to_string(), String::new()),
+        ];
+        let png = encode(&image, OutputFormat::Png, &metadata);
+        // ASCII text goes into tEXt and the rest into iTXt
+        assert!(png.windows(4).any(|w| w == b"tEXt"));
+        assert!(png.windows(4).any(|w| w == b"iTXt"));
+        for format in [OutputFormat::Bmp, OutputFormat::Png, OutputFormat::Qoi] {
+            let encoded = encode(&image, format, &metadata);
+            assert_eq!(read_metadata(&encoded).unwrap(), metadata, "{:?}", format);
+            assert!(
+                read_metadata(&encode(&image, format, &[]))
+                    .unwrap()
+                    .is_empty()
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_chunk_lengths_are_rejected() {
+        let mut png = encode_png(&sample_image(), &[]);
+        // Replace the IHDR length with one running far past the end
+        png[8..12].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(read_metadata(&png).is_err());
+        assert!(decode(&png).is_err());
+        assert!(read_png_chunk(&png, usize::MAX - 16).is_err());
+    }
+
+    fn grey_rows(rows: &[[u8; 5]]) -> RgbImage {
+        RgbImage {
+            width: 5,
+            height: rows.len() as u32,
+            pixels: rows.iter().flatten().flat_map(|&v| [v; 3]).collect(),
+        }
+    }
+
+    #[test]
+    fn png_rows_pick_the_cheapest_filter() {
+        // Each row is cheapest under a different filter: None, Sub, Up,
+        // Average and Paeth
+        let image = grey_rows(&[
+            [0; 5],
+            [100; 5],
+            [100; 5],
+            [50, 75, 87, 93, 96],
+            [93, 87, 96, 50, 250],
+        ]);
+        let png = encode_png(&image, &[]);
+
+        let idat = png.windows(4).position(|w| w == b"IDAT").unwrap();
+        let len = u32::from_be_bytes(png[idat - 4..idat].try_into().unwrap()) as usize;
+        let raw =
+            miniz_oxide::inflate::decompress_to_vec_zlib(&png[idat + 4..idat + 4 + len]).unwrap();
+        let filters: Vec<u8> = raw.chunks_exact(16).map(|scanline| scanline[0]).collect();
+        assert_eq!(filters, [0, 1, 2, 3, 4]);
+        // The Average row predicts every sample exactly
+        assert_eq!(raw[3 * 16 + 1..4 * 16], [0; 15]);
+
+        assert_eq!(decode(&png).unwrap().pixels, image.pixels);
+    }
+
+    #[test]
+    fn qoi_uses_each_op() {
+        let mut image = RgbImage::new(7, 1);
+        let pixels = [
+            [0, 0, 0],
+            [1, 0, 255],
+            [11, 8, 3],
+            [200, 10, 90],
+            [1, 0, 255],
+            [1, 0, 255],
+            [1, 0, 255],
+        ];
+        for (x, rgb) in pixels.into_iter().enumerate() {
+            image.set_pixel(x as u32, 0, rgb);
+        }
+        let qoi = encode_qoi(&image);
+
+        let mut expected = b"qoif".to_vec();
+        expected.extend_from_slice(&7u32.to_be_bytes());
+        expected.extend_from_slice(&1u32.to_be_bytes());
+        expected.extend_from_slice(&[3, 0]);
+        expected.extend_from_slice(&[
+            0xc0, // Run of the implicit black start pixel
+            0x79, // Diff (+1, 0, -1)
+            0xa8, 0xa4, // Luma (dg 8, dr-dg 2, db-dg -4)
+            0xfe, 200, 10, 90,   // Full RGB
+            0x31, // Index 49, seen two pixels back
+            0xc1, // Run of two, closed at the last pixel
+        ]);
+        expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(qoi, expected);
+        assert_eq!(decode(&qoi).unwrap().pixels, image.pixels);
+
+        // Runs are split at the 62-pixel maximum
+        let flat = RgbImage::new(70, 1);
+        let qoi = encode_qoi(&flat);
+        assert_eq!(qoi[14..qoi.len() - 8], [0xfd, 0xc7]);
+        assert_eq!(decode(&qoi).unwrap().pixels, flat.pixels);
+    }
+
+    #[test]
+    fn decodes_grey_alpha_png() {
+        // 2x1 grey+alpha, unfiltered
+        let raw = [0u8, 10, 255, 200, 0];
+        let mut ihdr = Vec::new();
+        ihdr.extend_from_slice(&2u32.to_be_bytes());
+        ihdr.extend_from_slice(&1u32.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 4, 0, 0, 0]);
+        let mut png = PNG_SIGNATURE.to_vec();
+        write_png_chunk(&mut png, b"IHDR", &ihdr);
+        write_png_chunk(
+            &mut png,
+            b"IDAT",
+            &miniz_oxide::deflate::compress_to_vec_zlib(&raw, 6),
+        );
+        write_png_chunk(&mut png, b"IEND", &[]);
+
+        let image = decode(&png).unwrap();
+        assert_eq!(image.pixels, [10, 10, 10, 200, 200, 200]);
+    }
+}
@@ -0,0 +1,254 @@
+// Inpainting masks and latent blending
+//
+// An inpainting request is an img2img request with a mask selecting the
+// pixels to regenerate. The mask is reduced to latent resolution, where a
+// latent pixel is regenerated if any pixel of its 8x8 cell is. After every
+// scheduler step the latents outside that region are replaced by the init
+// latents noised to the level of the next step, so the UNet keeps seeing the
+// known surroundings while it fills in the rest. Decoding does not reproduce
+// the known pixels exactly, so they are copied back from the init image once
+// the image is decoded.
+
+use crate::image_codec::RgbImage;
+use crate::scheduler::{Scheduler, SchedulerState};
+use crate::tensor::Tensor;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+// Pixel rectangle of an inpainting mask, from its top-left corner
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub struct MaskRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Pixels of the init image to regenerate
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mask {
+    pub width: u32,
+    pub height: u32,
+    masked: Vec<bool>,
+}
+
+impl Mask {
+    // White regenerates and black keeps, as in common inpainting tools;
+    // pixels are thresholded on their luma
+    pub fn from_image(image: &RgbImage) -> Self {
+        let masked = image
+            .pixels
+            .chunks(3)
+            .map(|rgb| {
+                let luma = 299 * rgb[0] as u32 + 587 * rgb[1] as u32 + 114 * rgb[2] as u32;
+                luma >= 128 * 1000
+            })
+            .collect();
+        Self {
+            width: image.width,
+            height: image.height,
+            masked,
+        }
+    }
+
+    pub fn from_rectangles(width: u32, height: u32, rects: &[MaskRect]) -> Result<Self, String> {
+        let mut masked = vec![false; (width * height) as usize];
+        for rect in rects {
+            let fits = rect.width > 0
+                && rect.height > 0
+                && rect
+                    .x
+                    .checked_add(rect.width)
+                    .is_some_and(|right| right <= width)
+                && rect
+                    .y
+                    .checked_add(rect.height)
+                    .is_some_and(|bottom| bottom <= height);
+            if !fits {
+                return Err(format!(
+                    "Mask rectangle {}x{} at ({}, {}) is empty or outside the {}x{} image",
+                    rect.width, rect.height, rect.x, rect.y, width, height
+                ));
+            }
+            for y in rect.y..rect.y + rect.height {
+                let row = (y * width) as usize;
+                masked[row + rect.x as usize..row + (rect.x + rect.width) as usize].fill(true);
+            }
+        }
+        Ok(Self {
+            width,
+            height,
+            masked,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.masked.contains(&true)
+    }
+
+    // `[1, 1, h / scale, w / scale]` with 1 over every cell holding a masked
+    // pixel and 0 elsewhere
+    pub fn latent_mask(&self, scale: u32) -> Tensor {
+        let (width, height) = (self.width / scale, self.height / scale);
+        let mut cells = vec![0.0; (width * height) as usize];
+        for (i, _) in self.masked.iter().enumerate().filter(|&(_, &m)| m) {
+            let (x, y) = (i as u32 % self.width / scale, i as u32 / self.width / scale);
+            if x < width && y < height {
+                cells[(y * width + x) as usize] = 1.0;
+            }
+        }
+        Tensor::new(&[1, 1, height as usize, width as usize], cells)
+    }
+
+    // Copy every unmasked pixel of `original` into `generated`
+    pub fn preserve_unmasked(
+        &self,
+        generated: &mut RgbImage,
+        original: &RgbImage,
+    ) -> Result<(), String> {
+        let size = (self.width, self.height);
+        if (generated.width, generated.height) != size || (original.width, original.height) != size
+        {
+            return Err(format!(
+                "Cannot apply a {}x{} mask to a {}x{} image",
+                self.width, self.height, generated.width, generated.height
+            ));
+        }
+        let pixels = generated
+            .pixels
+            .chunks_mut(3)
+            .zip(original.pixels.chunks(3));
+        for ((out, known), &masked) in pixels.zip(&self.masked) {
+            if !masked {
+                out.copy_from_slice(known);
+            }
+        }
+        Ok(())
+    }
+}
+
+// What the pipeline needs to blend the known latents back in, checkpointed
+// with the rest of the pipeline state
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct InpaintState {
+    // Encoded init image and the noise drawn for it, [1, c, h, w]
+    pub init_latents: Tensor,
+    pub noise: Tensor,
+    // [1, 1, h, w], 1 where latents are regenerated
+    pub mask: Tensor,
+}
+
+impl InpaintState {
+    // Replace the unmasked latents after the step into `step_index` with the
+    // init latents at that step's noise level, or as they are once the last
+    // step has run
+    pub fn blend(&self, scheduler: &SchedulerState, latents: &Tensor, step_index: usize) -> Tensor {
+        let known = if step_index < scheduler.timesteps().len() {
+            scheduler.add_noise(&self.init_latents, &self.noise, step_index)
+        } else {
+            self.init_latents.clone()
+        };
+
+        let mask = self.mask.values();
+        let plane = mask.len();
+        let data = latents
+            .values()
+            .iter()
+            .zip(known.values().iter())
+            .enumerate()
+            .map(|(i, (&generated, &known))| {
+                if mask[i % plane] > 0.0 {
+                    generated
+                } else {
+                    known
+                }
+            })
+            .collect();
+        Tensor::new(latents.shape(), data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::TorchGenerator;
+    use crate::scheduler::{SchedulerConfig, SchedulerKind};
+
+    #[test]
+    fn rectangles_mark_whole_latent_cells() {
+        let rect = MaskRect {
+            x: 6,
+            y: 0,
+            width: 3,
+            height: 1,
+        };
+        let mask = Mask::from_rectangles(24, 16, &[rect]).unwrap();
+        assert!(!mask.is_empty());
+        // Pixels 6..9 straddle the first two cells of the top row
+        assert_eq!(
+            mask.latent_mask(8).values()[..],
+            [1.0, 1.0, 0.0, 0.0, 0.0, 0.0]
+        );
+
+        let outside = MaskRect { x: 22, ..rect };
+        assert!(Mask::from_rectangles(24, 16, &[outside]).is_err());
+        let empty = MaskRect { width: 0, ..rect };
+        assert!(Mask::from_rectangles(24, 16, &[empty]).is_err());
+        assert!(Mask::from_rectangles(24, 16, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn mask_images_keep_unmasked_pixels_exactly() {
+        let mut mask_image = RgbImage::new(2, 2);
+        mask_image.set_pixel(1, 0, [255, 255, 255]);
+        mask_image.set_pixel(0, 1, [0, 0, 200]);
+        let mask = Mask::from_image(&mask_image);
+
+        let original = RgbImage {
+            width: 2,
+            height: 2,
+            pixels: (0..12).collect(),
+        };
+        let mut generated = RgbImage {
+            width: 2,
+            height: 2,
+            pixels: vec![99; 12],
+        };
+        mask.preserve_unmasked(&mut generated, &original).unwrap();
+        assert_eq!(generated.pixels, [0, 1, 2, 99, 99, 99, 6, 7, 8, 9, 10, 11]);
+        assert!(
+            mask.preserve_unmasked(&mut RgbImage::new(1, 2), &original)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn blending_restores_the_noised_init_latents() {
+        let scheduler = SchedulerState::new(
+            SchedulerKind::Ddim,
+            SchedulerConfig::default(),
+            4,
+            TorchGenerator::new(0),
+            0.0,
+        );
+        let mut generator = TorchGenerator::new(3);
+        let state = InpaintState {
+            init_latents: Tensor::new(&[1, 2, 1, 2], generator.randn(4)),
+            noise: Tensor::new(&[1, 2, 1, 2], generator.randn(4)),
+            mask: Tensor::new(&[1, 1, 1, 2], vec![0.0, 1.0]),
+        };
+        let latents = Tensor::full(&[1, 2, 1, 2], 5.0);
+
+        let known = scheduler.add_noise(&state.init_latents, &state.noise, 2);
+        let blended = state.blend(&scheduler, &latents, 2);
+        assert_eq!(
+            blended.values()[..],
+            [known.values()[0], 5.0, known.values()[2], 5.0]
+        );
+
+        // After the last step the init latents come back unchanged
+        let last = state.blend(&scheduler, &latents, 4);
+        let init = state.init_latents.values();
+        assert_eq!(last.values()[..], [init[0], 5.0, init[2], 5.0]);
+    }
+}
@@ -1,16 +1,109 @@
-use candid::{CandidType, Decode, Encode, Nat};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
 use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpResponse};
+use ic_cdk::api::management_canister::main::raw_rand;
 use ic_cdk::api::time;
 use ic_cdk::{init, post_upgrade, pre_upgrade, query, update};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+mod image_codec;
+mod inpaint;
+mod model_store;
+mod preview;
+mod prompt;
+mod rng;
+mod safetensors;
+mod scheduler;
+mod task_record;
+mod tensor;
+mod tensor_store;
+mod text_encoder;
+mod tokenizer;
+mod unet;
+mod vae;
+
+use image_codec::{OutputFormat, RgbImage};
+use inpaint::{InpaintState, Mask, MaskRect};
+use model_store::{
+    ModelFileInfo, ModelStore, TAESD_WEIGHTS, TEXT_ENCODER_WEIGHTS, TOKENIZER_MERGES,
+    TOKENIZER_VOCAB, UNET_WEIGHTS, VAE_WEIGHTS,
+};
+use preview::{PreviewDecoder, TaesdDecoder};
+use rng::TorchGenerator;
+use scheduler::{
+    PredictionType, Scheduler, SchedulerConfig, SchedulerKind, SchedulerState, strength_start_step,
+};
+use task_record::StorableGenerationTask;
+use tensor::Tensor;
+use text_encoder::{ClipTextEncoder, MockTextEncoder, TextEncoder};
+use tokenizer::{
+    CHUNK_LENGTH, ClipTokenizer, EncodedPrompt, MAX_CHUNKS, SimpleTokenizer, Token, Tokenizer,
+};
+use unet::{MockUNet, UNet, UNet2DConditionModel};
+use vae::{
+    AutoencoderDecoder, AutoencoderEncoder, MockVAEDecoder, MockVAEEncoder, TiledPass, VAEDecoder,
+    VAEEncoder,
+};
 
 // Memory management
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdStore = StableBTreeMap<String, StorableGenerationTask, Memory>;
+type PipelineStore = StableBTreeMap<String, StorablePipelineState, Memory>;
+type ImageStore = StableBTreeMap<ChunkKey, Vec<u8>, Memory>;
+type PreviewStore = StableBTreeMap<String, StoredRecord<TaskPreview>, Memory>;
+type UploadStore = StableBTreeMap<String, StoredRecord<UploadRecord>, Memory>;
+
+// Instructions a worker tick spends on pipeline work before checkpointing.
+// Timer callbacks run under the 40 billion instruction limit of update
+// messages; the margin covers the preview, the checkpoint write and a unit
+// of work costing more than the largest one measured in the tick.
+const TICK_INSTRUCTION_BUDGET: u64 = 20_000_000_000;
+
+// Images are split into chunks of this size in IMAGE_STORE
+const IMAGE_CHUNK_SIZE: usize = 1024 * 1024;
+
+// Init images kept per uploader and in total. A caller's oldest uploads that
+// no queued task needs are evicted to make room for their next one; with
+// ingress messages capped at 2 MiB, uploads never hold more than 128 MiB of
+// stable memory.
+const MAX_UPLOADS_PER_CALLER: usize = 8;
+const MAX_UPLOADS: usize = 64;
+
+// The VAE maps each latent cell to an 8x8 pixel block
+const VAE_SCALE_FACTOR: u32 = 8;
+
+// Parameters used when a request leaves them unset
+const DEFAULT_DIMENSION: u32 = 512;
+// Edge of the square VAE tiles, in pixels, unless the request or the
+// controller sets one
+const DEFAULT_VAE_TILE_SIZE: u32 = 256;
+const MIN_VAE_TILE_SIZE: u32 = 64;
+const DEFAULT_INFERENCE_STEPS: u32 = 20;
+const DEFAULT_GUIDANCE_SCALE: f32 = 7.5;
+// img2img strength when an init image is given without one; inpainting
+// repaints the masked region from scratch by default
+const DEFAULT_STRENGTH: f32 = 0.8;
+const DEFAULT_INPAINT_STRENGTH: f32 = 1.0;
+
+// Worker ticks started on a task without finishing, i.e. trapped, before it
+// is failed
+const MAX_TICK_ATTEMPTS: u32 = 3;
+// A queued tick that has not run by then was rolled back by a trap
+const WORKER_STALL_TIMEOUT: Duration = Duration::from_secs(120);
+// How often the watchdog checks for a stalled worker
+const WORKER_WATCHDOG_INTERVAL: Duration = Duration::from_secs(60);
+
+// Recorded in image metadata to identify what produced an image
+const MODEL_VERSION: &str = concat!("ic-stable-diff/", env!("CARGO_PKG_VERSION"));
+
+// Image metadata key holding the machine-readable ImageMetadata JSON
+const METADATA_KEY: &str = "ic-stable-diff";
 
 // HTTP Request structure for IC
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -22,7 +115,27 @@ pub struct HttpRequest {
 }
 
 // Data structures
+
+// Starting image of an img2img request
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub enum InitImage {
+    // Id returned by upload_init_image
+    Upload(String),
+    // Image of a completed task
+    Task(String),
+}
+
+// Region of the init image an inpainting request regenerates
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub enum InpaintMask {
+    // Id returned by upload_init_image of an image the size of the init
+    // image; white pixels are regenerated and black ones kept
+    Upload(String),
+    // Pixel rectangles to regenerate
+    Rectangles(Vec<MaskRect>),
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
 pub struct GenerationRequest {
     pub prompt: String,
     pub negative_prompt: Option<String>,
@@ -31,6 +144,48 @@ pub struct GenerationRequest {
     pub num_inference_steps: Option<u32>,
     pub guidance_scale: Option<f32>,
     pub seed: Option<u64>,
+    pub output_format: Option<OutputFormat>,
+    pub scheduler: Option<SchedulerKind>,
+    // DDIM stochasticity, from 0 (deterministic) to 1 (DDPM-like)
+    pub eta: Option<f32>,
+    // Edge of the tiles the VAE decodes at a time, in pixels
+    pub vae_tile_size: Option<u32>,
+    // img2img: denoise this image instead of pure noise
+    pub init_image: Option<InitImage>,
+    // Share of the schedule run on the noised init image, from just above 0
+    // (keep it nearly as is) to 1 (only its composition survives)
+    pub strength: Option<f32>,
+    // Inpainting: only regenerate this part of the init image
+    pub mask: Option<InpaintMask>,
+}
+
+impl GenerationRequest {
+    // Fill every unset parameter except the seed with the value generation
+    // uses, so the stored request fully describes the image
+    fn fill_defaults(&mut self, config: &CanisterConfig) {
+        self.width.get_or_insert(DEFAULT_DIMENSION);
+        self.height.get_or_insert(DEFAULT_DIMENSION);
+        self.num_inference_steps
+            .get_or_insert(DEFAULT_INFERENCE_STEPS);
+        self.guidance_scale.get_or_insert(DEFAULT_GUIDANCE_SCALE);
+        self.output_format.get_or_insert_default();
+        if *self.scheduler.get_or_insert_default() == SchedulerKind::Ddim {
+            self.eta.get_or_insert(0.0);
+        }
+        self.vae_tile_size
+            .get_or_insert(config.vae_tile_size.unwrap_or(DEFAULT_VAE_TILE_SIZE));
+        if self.init_image.is_some() {
+            self.strength.get_or_insert(self.default_strength());
+        }
+    }
+
+    fn default_strength(&self) -> f32 {
+        if self.mask.is_some() {
+            DEFAULT_INPAINT_STRENGTH
+        } else {
+            DEFAULT_STRENGTH
+        }
+    }
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
@@ -40,21 +195,368 @@ pub struct GenerationTask {
     pub created_at: u64,
     pub completed_at: Option<u64>,
     pub request: GenerationRequest,
-    pub result: Option<Vec<u8>>, // Base64 encoded image
+    pub image: Option<ImageRef>,
     pub error: Option<String>,
+    pub progress: Option<TaskProgress>,
+    // Set when the request was accepted but something about it was adjusted,
+    // such as a prompt longer than one CLIP chunk
+    pub warning: Option<String>,
+    // Worker ticks claimed for this task since the last one that finished;
+    // only a trapping tick leaves it raised
+    pub attempts: Option<u32>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct TaskProgress {
+    pub step: u32,
+    pub total_steps: u32,
+    // Set once denoising is done and the VAE decodes the image in tiles
+    pub tiles_decoded: Option<u32>,
+    pub total_tiles: Option<u32>,
+}
+
+// Generation parameters embedded in every encoded image
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ImageMetadata {
+    pub task_id: String,
+    pub model_version: String,
+    pub canister_id: String,
+    pub request: GenerationRequest,
+}
+
+impl ImageMetadata {
+    fn to_text_entries(&self) -> Vec<(String, String)> {
+        vec![
+            ("Software".to_string(), self.model_version.clone()),
+            ("parameters".to_string(), self.summary()),
+            (
+                METADATA_KEY.to_string(),
+                serde_json::to_string(self).unwrap_or_default(),
+            ),
+        ]
+    }
+
+    fn from_text_entries(entries: &[(String, String)]) -> Result<Self, String> {
+        let (_, json) = entries
+            .iter()
+            .find(|(key, _)| key == METADATA_KEY)
+            .ok_or_else(|| "Image carries no generation metadata".to_string())?;
+        serde_json::from_str(json).map_err(|e| format!("Invalid generation metadata: {}", e))
+    }
+
+    // Human-readable parameters in the layout popular diffusion UIs use
+    fn summary(&self) -> String {
+        let request = &self.request;
+        let mut summary = request.prompt.clone();
+        if let Some(ref negative_prompt) = request.negative_prompt {
+            summary.push_str(&format!("\nNegative prompt: {}", negative_prompt));
+        }
+
+        let mut params = Vec::new();
+        if let Some(steps) = request.num_inference_steps {
+            params.push(format!("Steps: {}", steps));
+        }
+        if let Some(guidance_scale) = request.guidance_scale {
+            params.push(format!("CFG scale: {}", guidance_scale));
+        }
+        if let Some(seed) = request.seed {
+            params.push(format!("Seed: {}", seed));
+        }
+        if let Some(eta) = request.eta {
+            params.push(format!("Eta: {}", eta));
+        }
+        if let Some(strength) = request.strength {
+            params.push(format!("Denoising strength: {}", strength));
+        }
+        if let (Some(width), Some(height)) = (request.width, request.height) {
+            params.push(format!("Size: {}x{}", width, height));
+        }
+        params.push(format!("Model: {}", self.model_version));
+        params.push(format!("Task: {}", self.task_id));
+        params.push(format!("Canister: {}", self.canister_id));
+
+        summary.push_str(&format!("\n{}", params.join(", ")));
+        summary
+    }
+}
+
+// Reference to an image held in IMAGE_STORE
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ImageRef {
+    pub image_id: String,
+    pub size: u64,
+    pub content_type: String,
+    pub sha256: String,
+    pub chunk_count: u32,
+}
+
+// An init image in UPLOAD_STORE
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UploadRecord {
+    pub image: ImageRef,
+    pub uploader: Principal,
+    pub uploaded_at: u64,
 }
 
-// Storable wrapper for GenerationTask
+// Controller-adjustable canister settings
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
-pub struct StorableGenerationTask(pub GenerationTask);
+pub struct CanisterConfig {
+    pub max_image_dimension: u32,
+    // VAE tile size for requests that do not set one
+    pub vae_tile_size: Option<u32>,
+    // What the loaded UNet was trained to predict; epsilon unless set, e.g.
+    // to VPrediction for SD 2.x 768 checkpoints
+    pub prediction_type: Option<PredictionType>,
+}
+
+impl Default for CanisterConfig {
+    fn default() -> Self {
+        Self {
+            max_image_dimension: 1024,
+            vae_tile_size: None,
+            prediction_type: None,
+        }
+    }
+}
 
-impl Storable for StorableGenerationTask {
-    fn to_bytes(&self) -> Cow<[u8]> {
+impl Storable for CanisterConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
+    // Settings that no longer decode fall back to the defaults rather than
+    // trapping every message that reads them, upgrades included
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap_or_else(|error| {
+            ic_cdk::println!("Stored canister config is unreadable: {}", error);
+            Self::default()
+        })
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Outcome of the post-upgrade task record migration
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+pub struct MigrationReport {
+    pub scanned: u64,
+    pub converted: u64,
+    pub images_moved: u64,
+    pub unreadable: u64,
+    pub completed_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct TokenizerInfo {
+    pub name: String,
+    pub vocab_size: u64,
+    pub merges: u64,
+}
+
+// Tokens of a prompt as the pipeline sees them, including start/end tokens
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct TokenizeResult {
+    pub tokenizer: String,
+    pub tokens: Vec<Token>,
+    // Emphasis weight of each token
+    pub weights: Vec<f32>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct TextEncoderInfo {
+    pub name: String,
+    pub vocab_size: u64,
+    pub hidden_size: u64,
+    pub num_layers: u64,
+    pub max_positions: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct UNetInfo {
+    pub name: String,
+    pub in_channels: u64,
+    pub block_out_channels: Vec<u64>,
+    pub layers_per_block: u64,
+    pub mid_block: bool,
+    pub cross_attention_dim: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct VAEInfo {
+    pub name: String,
+    pub latent_channels: u64,
+    pub block_out_channels: Vec<u64>,
+    pub layers_per_block: u64,
+    pub scaling_factor: f32,
+    // False when the weights only hold the decoder, which rules out img2img
+    pub has_encoder: bool,
+}
+
+// Key of one chunk of an image in IMAGE_STORE. The map orders keys by the
+// derived Ord, id first and then index, so an image's chunks are adjacent
+// and in order whatever the byte layout below.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChunkKey {
+    pub id: String,
+    pub index: u32,
+}
+
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.index.to_be_bytes().to_vec();
+        bytes.extend_from_slice(self.id.as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (index, id) = bytes.split_at(4);
+        Self {
+            id: String::from_utf8(id.to_vec()).unwrap(),
+            index: u32::from_be_bytes(index.try_into().unwrap()),
+        }
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Diffusion state checkpointed between worker ticks
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PipelineState {
+    pub width: u32,
+    pub height: u32,
+    pub guidance_scale: f32,
+    // [1, tokens, dim]
+    pub text_embeddings: Tensor,
+    pub negative_embeddings: Tensor,
+    // [1, latent_channels, height / 8, width / 8]
+    pub latents: Tensor,
+    pub scheduler: SchedulerState,
+    pub step_index: u32,
+    // Noise predicted for the prompt at `step_index` by a tick that ended
+    // before the unconditional forward
+    pub prompt_noise: Option<Tensor>,
+    // First step run; img2img skips the noisiest part of the schedule
+    pub start_step: u32,
+    // VAE tile edge in pixels
+    pub tile_size: u32,
+    // Started once every scheduler step has run
+    pub decode: Option<TiledPass>,
+    // Set for inpainting requests
+    pub inpaint: Option<InpaintState>,
+}
+
+impl PipelineState {
+    fn is_finished(&self) -> bool {
+        self.step_index as usize >= self.scheduler.timesteps().len()
+    }
+
+    // Steps run so far and in total, not counting those img2img skips
+    fn steps(&self) -> (u32, u32) {
+        let total = self.scheduler.timesteps().len() as u32;
+        (self.step_index - self.start_step, total - self.start_step)
+    }
+
+    fn progress(&self) -> TaskProgress {
+        let (step, total_steps) = self.steps();
+        TaskProgress {
+            step,
+            total_steps,
+            tiles_decoded: self.decode.as_ref().map(TiledPass::tiles_done),
+            total_tiles: self.decode.as_ref().map(TiledPass::total_tiles),
+        }
+    }
+}
+
+// Prompt embeddings and init image encoding computed so far, checkpointed
+// between worker ticks until the initial latents can be drawn
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct Preparation {
+    // Weighted `[1, tokens, dim]` embeddings of the prompt's chunks followed
+    // by the negative prompt's
+    pub chunks: Vec<Tensor>,
+    // Tiled VAE encode of an img2img init image, started once the prompts
+    // are encoded
+    pub init_encode: Option<InitImageEncode>,
+}
+
+// The init image is decoded once, when its encode starts, and kept with the
+// tiles so later ticks only pay for the tiles they run
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct InitImageEncode {
+    // `[1, 3, h, w]` in [-1, 1]
+    pub pixels: Tensor,
+    pub tiles: TiledPass,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum PipelineCheckpoint {
+    Preparing(Box<Preparation>),
+    Running(Box<PipelineState>),
+}
+
+impl PipelineCheckpoint {
+    fn progress(&self) -> Option<TaskProgress> {
+        match self {
+            PipelineCheckpoint::Preparing(_) => None,
+            PipelineCheckpoint::Running(state) => Some(state.progress()),
+        }
+    }
+}
+
+// Checkpoints are transient, so one written by an incompatible release reads
+// back as None and its task restarts from the first step
+#[derive(Clone, Debug)]
+pub struct StorablePipelineState(pub Option<PipelineCheckpoint>);
+
+impl Storable for StorablePipelineState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(
+            Decode!(bytes.as_ref(), Option<PipelineCheckpoint>)
+                .ok()
+                .flatten(),
+        )
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+// Latest approximate render of a task's latents, kept while it is processing
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct TaskPreview {
+    pub task_id: String,
+    // Scheduler steps completed when the preview was rendered
+    pub step: u32,
+    pub total_steps: u32,
+    pub decoder: String,
+    pub width: u32,
+    pub height: u32,
+    pub content_type: String,
+    pub image: Vec<u8>,
+}
+
+// Candid record in a stable map that reads back as None once its bytes no
+// longer decode, e.g. after an incompatible type change, instead of trapping
+// the message that touches it
+#[derive(Clone, Debug)]
+pub struct StoredRecord<T>(pub Option<T>);
+
+impl<T: CandidType + for<'de> Deserialize<'de>> Storable for StoredRecord<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        match self.0 {
+            Some(ref value) => Cow::Owned(Encode!(value).unwrap()),
+            None => Cow::Borrowed(&[]),
+        }
+    }
+
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+        Self(Decode!(bytes.as_ref(), T).ok())
     }
 
     const BOUND: ic_stable_structures::storable::Bound =
@@ -80,40 +582,13 @@ pub struct ApiResponse<T> {
 // Stable Diffusion Model Components
 #[derive(Clone)]
 pub struct StableDiffusionModel {
-    pub tokenizer: SimpleTokenizer,
+    pub tokenizer: Tokenizer,
     pub text_encoder: TextEncoder,
     pub unet: UNet,
     pub vae_decoder: VAEDecoder,
-    pub scheduler: DDIMScheduler,
-}
-
-#[derive(Clone)]
-pub struct SimpleTokenizer {
-    vocab_size: usize,
-    max_length: usize,
-}
-
-#[derive(Clone)]
-pub struct TextEncoder {
-    embedding_dim: usize,
-}
-
-#[derive(Clone)]
-pub struct UNet {
-    in_channels: usize,
-    out_channels: usize,
-}
-
-#[derive(Clone)]
-pub struct VAEDecoder {
-    latent_channels: usize,
-}
-
-#[derive(Clone)]
-pub struct DDIMScheduler {
-    num_train_timesteps: usize,
-    beta_start: f32,
-    beta_end: f32,
+    pub vae_encoder: VAEEncoder,
+    pub preview_decoder: PreviewDecoder,
+    pub scheduler_config: SchedulerConfig,
 }
 
 // Global state
@@ -127,418 +602,1967 @@ thread_local! {
         )
     );
 
-    static MODEL: RefCell<Option<StableDiffusionModel>> = RefCell::new(None);
+    static PIPELINE_STORE: RefCell<PipelineStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+        )
+    );
 
-    static TASK_COUNTER: RefCell<u64> = RefCell::new(0);
-}
+    static IMAGE_STORE: RefCell<ImageStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        )
+    );
 
-impl SimpleTokenizer {
-    fn new() -> Self {
-        Self {
-            vocab_size: 49408, // CLIP tokenizer vocab size
-            max_length: 77,    // CLIP max sequence length
-        }
-    }
+    static PREVIEW_STORE: RefCell<PreviewStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        )
+    );
+
+    // Init images uploaded for img2img, by upload id; the bytes live in
+    // IMAGE_STORE under the same id
+    static UPLOAD_STORE: RefCell<UploadStore> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        )
+    );
 
-    fn encode(&self, text: &str) -> Vec<u32> {
-        // Simplified tokenization - in real implementation, use proper CLIP tokenizer
-        let mut tokens = vec![49406]; // Start token
+    // Uploaded model files: contents in a raw region, indexed by file name
+    static MODEL_STORE: RefCell<ModelStore<Memory>> = RefCell::new(
+        MEMORY_MANAGER.with(|m| {
+            let m = m.borrow();
+            ModelStore::init(m.get(MemoryId::new(9)), m.get(MemoryId::new(10)))
+        })
+    );
 
-        // Simple word splitting and hashing for demo
-        for word in text.split_whitespace().take(self.max_length - 2) {
-            let token_id =
-                (word.chars().map(|c| c as u32).sum::<u32>() % (self.vocab_size as u32 - 2)) + 1;
-            tokens.push(token_id);
-        }
+    static MODEL: RefCell<Option<StableDiffusionModel>> = const { RefCell::new(None) };
 
-        tokens.push(49407); // End token
+    // Last issued task number, kept in stable memory so ids stay unique
+    // across upgrades
+    static TASK_COUNTER: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+            0,
+        )
+        .expect("failed to initialize task counter"),
+    );
 
-        // Pad to max_length
-        while tokens.len() < self.max_length {
-            tokens.push(0);
-        }
+    static CONFIG: RefCell<StableCell<CanisterConfig, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+            CanisterConfig::default(),
+        )
+        .expect("failed to initialize config"),
+    );
 
-        tokens.truncate(self.max_length);
-        tokens
-    }
+    static LAST_MIGRATION: RefCell<Option<MigrationReport>> = const { RefCell::new(None) };
+
+    // When the worker's current tick was queued, while one is. A tick that
+    // traps never clears it, so a value older than WORKER_STALL_TIMEOUT
+    // means the worker has to be restarted.
+    static WORKER_QUEUED_AT: Cell<Option<u64>> = const { Cell::new(None) };
 }
 
-impl TextEncoder {
+impl StableDiffusionModel {
     fn new() -> Self {
-        Self { embedding_dim: 768 }
+        Self {
+            tokenizer: match load_stored_tokenizer() {
+                Some(Ok(tokenizer)) => Tokenizer::Clip(tokenizer),
+                Some(Err(error)) => {
+                    ic_cdk::println!("Stored tokenizer files are invalid: {}", error);
+                    Tokenizer::Simple(SimpleTokenizer::new())
+                }
+                None => Tokenizer::Simple(SimpleTokenizer::new()),
+            },
+            text_encoder: match load_stored_text_encoder() {
+                Some(Ok(encoder)) => TextEncoder::Clip(Box::new(encoder)),
+                Some(Err(error)) => {
+                    ic_cdk::println!("Stored text encoder weights are invalid: {}", error);
+                    TextEncoder::Mock(MockTextEncoder::new())
+                }
+                None => TextEncoder::Mock(MockTextEncoder::new()),
+            },
+            unet: match load_stored_unet() {
+                Some(Ok(unet)) => UNet::Conditional(Box::new(unet)),
+                Some(Err(error)) => {
+                    ic_cdk::println!("Stored UNet weights are invalid: {}", error);
+                    UNet::Mock(MockUNet::new())
+                }
+                None => UNet::Mock(MockUNet::new()),
+            },
+            vae_decoder: match load_stored_vae() {
+                Some(Ok(decoder)) => VAEDecoder::Autoencoder(Box::new(decoder)),
+                Some(Err(error)) => {
+                    ic_cdk::println!("Stored VAE weights are invalid: {}", error);
+                    VAEDecoder::Mock(MockVAEDecoder::new())
+                }
+                None => VAEDecoder::Mock(MockVAEDecoder::new()),
+            },
+            vae_encoder: match load_stored_vae_encoder() {
+                Some(Ok(encoder)) => VAEEncoder::Autoencoder(Box::new(encoder)),
+                Some(Err(error)) => {
+                    ic_cdk::println!("Stored VAE encoder weights are invalid: {}", error);
+                    VAEEncoder::Mock(MockVAEEncoder::new())
+                }
+                None => VAEEncoder::Mock(MockVAEEncoder::new()),
+            },
+            preview_decoder: match load_stored_taesd() {
+                Some(Ok(decoder)) => PreviewDecoder::Taesd(Box::new(decoder)),
+                Some(Err(error)) => {
+                    ic_cdk::println!("Stored TAESD weights are invalid: {}", error);
+                    PreviewDecoder::Linear
+                }
+                None => PreviewDecoder::Linear,
+            },
+            scheduler_config: SchedulerConfig::default(),
+        }
     }
 
-    fn encode(&self, tokens: &[u32]) -> Vec<f32> {
-        // Simplified text encoding - returns mock embeddings
-        // In real implementation, this would use actual CLIP text encoder weights
-        let mut embeddings = Vec::with_capacity(tokens.len() * self.embedding_dim);
-
-        for &token in tokens {
-            for i in 0..self.embedding_dim {
-                let value = (token as f32 + i as f32) / 1000.0;
-                embeddings.push(value.sin()); // Simple deterministic "embedding"
-            }
+    // Tokenize both prompts, padding the shorter one with empty chunks so
+    // the conditional and unconditional embeddings have the same length.
+    // Also returns a warning for prompts that had to be chunked or cut.
+    fn tokenize(
+        &self,
+        request: &GenerationRequest,
+    ) -> (EncodedPrompt, EncodedPrompt, Option<String>) {
+        let fragments = prompt::parse_prompt_attention(&request.prompt);
+        let negative_fragments =
+            prompt::parse_prompt_attention(request.negative_prompt.as_deref().unwrap_or(""));
+        let mut prompt = self.tokenizer.encode_chunks(&fragments, 1);
+        let negative = self
+            .tokenizer
+            .encode_chunks(&negative_fragments, prompt.chunks.len());
+        if negative.chunks.len() > prompt.chunks.len() {
+            prompt = self
+                .tokenizer
+                .encode_chunks(&fragments, negative.chunks.len());
         }
 
-        embeddings
+        let warnings: Vec<String> = [("Prompt", &prompt), ("Negative prompt", &negative)]
+            .into_iter()
+            .filter_map(|(name, encoded)| chunking_warning(name, encoded))
+            .collect();
+        let warning = (!warnings.is_empty()).then(|| warnings.join("; "));
+        (prompt, negative, warning)
     }
-}
 
-impl UNet {
-    fn new() -> Self {
-        Self {
-            in_channels: 4,
-            out_channels: 4,
+    // Encode prompt chunks, then the tiles of an img2img init image, while
+    // the tick's budget allows, returning the pipeline state once the last
+    // one is in. Each chunk is a text encoder pass of its own and each tile a
+    // VAE encoder pass.
+    fn prepare(
+        &self,
+        request: &GenerationRequest,
+        preparation: &mut Preparation,
+        budget: &mut TickBudget,
+    ) -> Result<Option<PipelineState>, String> {
+        let (prompt, negative, _) = self.tokenize(request);
+        let chunks: Vec<_> = prompt.chunks.iter().chain(&negative.chunks).collect();
+        for (tokens, weights) in chunks.iter().skip(preparation.chunks.len()) {
+            let mut chunk = self.text_encoder.encode(tokens)?;
+            prompt::apply_token_weights(&mut chunk, weights);
+            let dim = chunk.len() / tokens.len();
+            preparation
+                .chunks
+                .push(Tensor::new(&[1, tokens.len(), dim], chunk));
+            let work_left = preparation.chunks.len() < chunks.len() || request.init_image.is_some();
+            if work_left && !budget.another_fits() {
+                return Ok(None);
+            }
         }
-    }
 
-    fn forward(&self, latents: &[f32], timestep: u32, text_embeddings: &[f32]) -> Vec<f32> {
-        // Simplified UNet forward pass
-        // In real implementation, this would be the actual diffusion model
-        let mut noise_pred = latents.to_vec();
+        let init_encode = match (&request.init_image, &mut preparation.init_encode) {
+            (None, _) => None,
+            (Some(_), Some(encode)) => Some(encode),
+            (Some(init_image), encode @ None) => {
+                let width = request.width.unwrap_or(DEFAULT_DIMENSION);
+                let height = request.height.unwrap_or(DEFAULT_DIMENSION);
+                let image = load_init_image(init_image)?;
+                if (image.width, image.height) != (width, height) {
+                    return Err(format!(
+                        "Init image is {}x{}, the request asks for {}x{}",
+                        image.width, image.height, width, height
+                    ));
+                }
+                let pixels = vae::from_image(&image);
+                let tile = request.vae_tile_size.unwrap_or(DEFAULT_VAE_TILE_SIZE);
+                let tiles = TiledPass::encode(pixels.shape(), (tile / VAE_SCALE_FACTOR) as usize);
+                Some(encode.insert(InitImageEncode { pixels, tiles }))
+            }
+        };
+        let init_moments = match init_encode {
+            Some(encode) => {
+                while !encode.tiles.is_finished() {
+                    let moments = |tile: &Tensor| self.vae_encoder.moments(tile);
+                    encode.tiles.run_tiles(&encode.pixels, 1, moments)?;
+                    if !encode.tiles.is_finished() && !budget.another_fits() {
+                        return Ok(None);
+                    }
+                }
+                Some(encode.tiles.output())
+            }
+            None => None,
+        };
 
-        // Simple noise prediction based on timestep and text conditioning
-        let conditioning_strength =
-            (text_embeddings.iter().sum::<f32>() / text_embeddings.len() as f32) * 0.1;
-        let time_factor = (timestep as f32 / 1000.0).cos();
+        let (text, negative) = preparation.chunks.split_at(prompt.chunks.len());
+        let concat = |chunks: &[Tensor]| Tensor::concat(&chunks.iter().collect::<Vec<_>>(), 1);
+        self.begin_generation(request, concat(text), concat(negative), init_moments)
+            .map(Some)
+    }
 
-        for (i, pred) in noise_pred.iter_mut().enumerate() {
-            *pred += conditioning_strength * time_factor * ((i as f32).sin() * 0.1);
+    // Sample the initial latents for the encoded prompts, and for img2img
+    // from the latent distribution of the encoded init image. The returned
+    // state is advanced by `run_steps` and turned into an image by `decode`.
+    fn begin_generation(
+        &self,
+        request: &GenerationRequest,
+        text_embeddings: Tensor,
+        negative_embeddings: Tensor,
+        init_moments: Option<Tensor>,
+    ) -> Result<PipelineState, String> {
+        // Set defaults
+        let width = request.width.unwrap_or(DEFAULT_DIMENSION);
+        let height = request.height.unwrap_or(DEFAULT_DIMENSION);
+        let num_steps = request
+            .num_inference_steps
+            .unwrap_or(DEFAULT_INFERENCE_STEPS);
+        let guidance_scale = request.guidance_scale.unwrap_or(DEFAULT_GUIDANCE_SCALE);
+        // Submission draws a seed for every request that does not set one
+        let seed = request
+            .seed
+            .ok_or_else(|| "Request has no seed".to_string())?;
+        let eta = request.eta.unwrap_or(0.0);
+
+        if num_steps == 0 {
+            return Err("num_inference_steps must be at least 1".to_string());
         }
 
-        noise_pred
-    }
-}
+        // Initial latents are drawn first so a seed matches torch.randn on a
+        // generator seeded the same way; the scheduler continues the stream.
+        // img2img samples the encoded init image before the noise, in the
+        // same order as diffusers.
+        let mut generator = TorchGenerator::new(seed);
+        let latent_shape = [
+            1,
+            self.unet.in_channels(),
+            (height / VAE_SCALE_FACTOR) as usize,
+            (width / VAE_SCALE_FACTOR) as usize,
+        ];
+        let init_latents = match init_moments {
+            Some(ref moments) => {
+                let latents = self.vae_encoder.sample(moments, &mut generator)?;
+                latents.check_shape(&latent_shape)?;
+                Some(latents)
+            }
+            None => None,
+        };
+        let noise = Tensor::new(
+            &latent_shape,
+            generator.randn(latent_shape.iter().product()),
+        );
+
+        let scheduler_config = SchedulerConfig {
+            prediction_type: get_config().prediction_type.unwrap_or_default(),
+            ..self.scheduler_config.clone()
+        };
+        let scheduler = SchedulerState::new(
+            request.scheduler.unwrap_or_default(),
+            scheduler_config,
+            num_steps as usize,
+            generator,
+            eta,
+        );
+
+        // img2img noises the init latents to the level of its first step
+        // and skips the steps before it
+        let (latents, start_step) = match init_latents {
+            Some(ref init_latents) => {
+                let strength = request
+                    .strength
+                    .unwrap_or_else(|| request.default_strength());
+                let start_step = strength_start_step(num_steps as usize, strength);
+                if start_step >= num_steps as usize {
+                    return Err(format!(
+                        "strength {} leaves none of the {} steps to run",
+                        strength, num_steps
+                    ));
+                }
+                let latents = scheduler.add_noise(init_latents, &noise, start_step);
+                (latents, start_step as u32)
+            }
+            None => (noise.scale(scheduler.init_noise_sigma()), 0),
+        };
 
-impl VAEDecoder {
-    fn new() -> Self {
-        Self { latent_channels: 4 }
+        let inpaint = match (load_mask(request, width, height)?, init_latents) {
+            (Some(mask), Some(init_latents)) => Some(InpaintState {
+                init_latents,
+                noise,
+                mask: mask.latent_mask(VAE_SCALE_FACTOR),
+            }),
+            (Some(_), None) => return Err("Inpainting needs an init_image".to_string()),
+            (None, _) => None,
+        };
+
+        Ok(PipelineState {
+            width,
+            height,
+            guidance_scale,
+            text_embeddings,
+            negative_embeddings,
+            latents,
+            scheduler,
+            step_index: start_step,
+            prompt_noise: None,
+            start_step,
+            tile_size: request.vae_tile_size.unwrap_or(DEFAULT_VAE_TILE_SIZE),
+            decode: None,
+            inpaint,
+        })
     }
 
-    fn decode(&self, latents: &[f32]) -> Vec<u8> {
-        let width = 64u32; // Smaller size for demo
-        let height = 64u32;
+    // Advance the diffusion process while the tick's budget allows. Each
+    // UNet forward is a unit of work, so a tick can end between the two
+    // forwards of a guided step.
+    fn run_steps(&self, state: &mut PipelineState, budget: &mut TickBudget) -> Result<(), String> {
+        while !state.is_finished() {
+            let step_index = state.step_index as usize;
+            let timestep = state.scheduler.timesteps()[step_index];
+            let model_input = state
+                .scheduler
+                .scale_model_input(&state.latents, step_index);
 
-        // Generate a pattern based on latents that looks more like generated art
-        let latent_sum = latents.iter().sum::<f32>() / latents.len() as f32;
-        let latent_variance = latents
-            .iter()
-            .map(|&x| (x - latent_sum).powi(2))
-            .sum::<f32>()
-            / latents.len() as f32;
+            // Predict noise with positive prompt
+            let noise_pred_pos = match state.prompt_noise.take() {
+                Some(noise) => noise,
+                None => {
+                    let noise =
+                        self.unet
+                            .forward(&model_input, timestep, &state.text_embeddings)?;
+                    if !budget.another_fits() {
+                        state.prompt_noise = Some(noise);
+                        return Ok(());
+                    }
+                    noise
+                }
+            };
 
-        // Create a simple bitmap (BMP format for simplicity)
-        self.create_bmp(width, height, latents, latent_sum, latent_variance)
-    }
+            // Predict noise with negative prompt
+            let noise_pred_neg =
+                self.unet
+                    .forward(&model_input, timestep, &state.negative_embeddings)?;
 
-    fn create_bmp(
-        &self,
-        width: u32,
-        height: u32,
-        latents: &[f32],
-        avg: f32,
-        variance: f32,
-    ) -> Vec<u8> {
-        let mut bmp_data = Vec::new();
-
-        // BMP file header (14 bytes)
-        bmp_data.extend_from_slice(b"BM"); // Signature
-        let file_size = 54 + (width * height * 3); // Header + pixel data
-        bmp_data.extend_from_slice(&file_size.to_le_bytes());
-        bmp_data.extend_from_slice(&[0, 0, 0, 0]); // Reserved
-        bmp_data.extend_from_slice(&54u32.to_le_bytes()); // Offset to pixel data
-
-        // DIB header (40 bytes)
-        bmp_data.extend_from_slice(&40u32.to_le_bytes()); // Header size
-        bmp_data.extend_from_slice(&width.to_le_bytes());
-        bmp_data.extend_from_slice(&height.to_le_bytes());
-        bmp_data.extend_from_slice(&1u16.to_le_bytes()); // Color planes
-        bmp_data.extend_from_slice(&24u16.to_le_bytes()); // Bits per pixel
-        bmp_data.extend_from_slice(&0u32.to_le_bytes()); // Compression
-        bmp_data.extend_from_slice(&(width * height * 3).to_le_bytes()); // Image size
-        bmp_data.extend_from_slice(&2835u32.to_le_bytes()); // X pixels per meter
-        bmp_data.extend_from_slice(&2835u32.to_le_bytes()); // Y pixels per meter
-        bmp_data.extend_from_slice(&0u32.to_le_bytes()); // Colors used
-        bmp_data.extend_from_slice(&0u32.to_le_bytes()); // Important colors
-
-        // Generate pixel data (BGR format, bottom-to-top)
-        let row_padding = (4 - (width * 3) % 4) % 4;
-
-        for y in (0..height).rev() {
-            // BMP stores rows bottom-to-top
-            for x in 0..width {
-                // Create interesting patterns based on latents
-                let latent_idx = ((x + y * width) as usize) % latents.len();
-                let latent_val = latents[latent_idx];
-
-                // Generate colors based on position and latent values
-                let norm_x = x as f32 / width as f32;
-                let norm_y = y as f32 / height as f32;
-
-                let r = ((norm_x * 255.0) + (latent_val * 50.0) + (avg * 100.0)).clamp(0.0, 255.0)
-                    as u8;
-                let g = ((norm_y * 255.0) + (variance * 200.0) + (latent_val * 30.0))
-                    .clamp(0.0, 255.0) as u8;
-                let b = (((norm_x + norm_y) * 127.5) + (latent_val * 70.0)).clamp(0.0, 255.0) as u8;
-
-                // BMP uses BGR order
-                bmp_data.push(b);
-                bmp_data.push(g);
-                bmp_data.push(r);
+            // Apply classifier-free guidance
+            let noise_pred = noise_pred_neg.add(
+                &noise_pred_pos
+                    .sub(&noise_pred_neg)
+                    .scale(state.guidance_scale),
+            );
+
+            // Scheduler step
+            state.latents = state
+                .scheduler
+                .step(&noise_pred, step_index, &state.latents);
+
+            // Inpainting keeps the known latents outside the mask
+            if let Some(ref inpaint) = state.inpaint {
+                state.latents = inpaint.blend(&state.scheduler, &state.latents, step_index + 1);
             }
 
-            // Add row padding
-            for _ in 0..row_padding {
-                bmp_data.push(0);
+            state.step_index += 1;
+            if !budget.another_fits() {
+                break;
             }
         }
-
-        bmp_data
+        Ok(())
     }
-}
 
-impl DDIMScheduler {
-    fn new() -> Self {
-        Self {
-            num_train_timesteps: 1000,
-            beta_start: 0.00085,
-            beta_end: 0.012,
+    // Decode VAE tiles while the tick's budget allows, returning the image
+    // once the last one is in
+    fn decode_tiles(
+        &self,
+        state: &mut PipelineState,
+        budget: &mut TickBudget,
+    ) -> Result<Option<RgbImage>, String> {
+        let latents = &state.latents;
+        let decode = state.decode.get_or_insert_with(|| {
+            let tile = if self.vae_decoder.supports_tiling() {
+                (state.tile_size / VAE_SCALE_FACTOR) as usize
+            } else {
+                usize::MAX
+            };
+            TiledPass::decode(latents.shape(), tile)
+        });
+        while !decode.is_finished() {
+            decode.run_tiles(latents, 1, |tile| self.vae_decoder.forward(tile))?;
+            if !budget.another_fits() {
+                break;
+            }
         }
-    }
-
-    fn step(&self, noise_pred: &[f32], timestep: u32, latents: &[f32]) -> Vec<f32> {
-        // Simplified DDIM sampling step
-        let alpha = 1.0
-            - self.beta_start
-            - (self.beta_end - self.beta_start)
-                * (timestep as f32 / self.num_train_timesteps as f32);
-        let beta = 1.0 - alpha;
 
-        latents
-            .iter()
-            .zip(noise_pred.iter())
-            .map(|(&latent, &noise)| latent - beta.sqrt() * noise)
-            .collect()
+        Ok(decode
+            .is_finished()
+            .then(|| vae::to_image(&decode.output())))
     }
 
-    fn get_timesteps(&self, num_inference_steps: usize) -> Vec<u32> {
-        let step_size = self.num_train_timesteps / num_inference_steps;
-        (0..num_inference_steps)
-            .map(|i| (self.num_train_timesteps - i * step_size - 1) as u32)
-            .collect()
+    // Approximate the image the current latents decode to. Noisy latents
+    // are scaled like the next UNet input so early previews are not washed
+    // out by the noise.
+    fn render_preview(&self, task_id: &str, state: &PipelineState) -> Result<TaskPreview, String> {
+        let step = state.step_index as usize;
+        let latents = if step < state.scheduler.timesteps().len() {
+            state.scheduler.scale_model_input(&state.latents, step)
+        } else {
+            state.latents.clone()
+        };
+        let image = vae::to_image(&self.preview_decoder.forward(&latents)?);
+        let format = OutputFormat::Png;
+        let (step, total_steps) = state.steps();
+
+        Ok(TaskPreview {
+            task_id: task_id.to_string(),
+            step,
+            total_steps,
+            decoder: self.preview_decoder.name().to_string(),
+            width: image.width,
+            height: image.height,
+            content_type: format.content_type().to_string(),
+            image: image_codec::encode(&image, format, &[]),
+        })
     }
 }
 
-impl StableDiffusionModel {
-    fn new() -> Self {
-        Self {
-            tokenizer: SimpleTokenizer::new(),
-            text_encoder: TextEncoder::new(),
-            unet: UNet::new(),
-            vae_decoder: VAEDecoder::new(),
-            scheduler: DDIMScheduler::new(),
-        }
-    }
-
-    fn generate_image(&self, request: &GenerationRequest) -> Result<Vec<u8>, String> {
-        // Set defaults
-        let width = request.width.unwrap_or(512);
-        let height = request.height.unwrap_or(512);
-        let num_steps = request.num_inference_steps.unwrap_or(20);
-        let guidance_scale = request.guidance_scale.unwrap_or(7.5);
-        let seed = request.seed.unwrap_or(42);
-
-        // Tokenize and encode text
-        let tokens = self.tokenizer.encode(&request.prompt);
-        let text_embeddings = self.text_encoder.encode(&tokens);
-
-        // Handle negative prompt
-        let negative_embeddings = if let Some(ref neg_prompt) = request.negative_prompt {
-            let neg_tokens = self.tokenizer.encode(neg_prompt);
-            self.text_encoder.encode(&neg_tokens)
-        } else {
-            let empty_tokens = self.tokenizer.encode("");
-            self.text_encoder.encode(&empty_tokens)
+// Helper functions
+fn generate_task_id() -> String {
+    TASK_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let mut next = *counter.get();
+
+        // Never hand out an id that is already taken
+        let task_id = loop {
+            next += 1;
+            let candidate = format!("task_{}", next);
+            if !TASK_STORE.with(|store| store.borrow().contains_key(&candidate)) {
+                break candidate;
+            }
         };
 
-        // Initialize random latents
-        let latent_size = (width / 8) * (height / 8) * 4; // VAE downsampling factor of 8
-        let mut latents = self.generate_random_latents(latent_size as usize, seed);
-
-        // Diffusion process
-        let timesteps = self.scheduler.get_timesteps(num_steps as usize);
+        counter.set(next).expect("failed to persist task counter");
+        task_id
+    })
+}
 
-        for &timestep in &timesteps {
-            // Predict noise with positive prompt
-            let noise_pred_pos = self.unet.forward(&latents, timestep, &text_embeddings);
+fn parse_task_number(task_id: &str) -> Option<u64> {
+    task_id.strip_prefix("task_")?.parse().ok()
+}
 
-            // Predict noise with negative prompt
-            let noise_pred_neg = self.unet.forward(&latents, timestep, &negative_embeddings);
+// Ensure the stable counter is ahead of every stored task id, e.g. after
+// upgrading from a release that kept the counter on the heap
+fn reconcile_task_counter() {
+    let highest = TASK_STORE.with(|store| {
+        store
+            .borrow()
+            .keys()
+            .filter_map(|id| parse_task_number(&id))
+            .max()
+            .unwrap_or(0)
+    });
 
-            // Apply classifier-free guidance
-            let noise_pred: Vec<f32> = noise_pred_neg
-                .iter()
-                .zip(noise_pred_pos.iter())
-                .map(|(&neg, &pos)| neg + guidance_scale * (pos - neg))
-                .collect();
+    TASK_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        let current = *counter.get();
+        if current < highest {
+            ic_cdk::println!(
+                "Task counter {} is behind stored task_{}; advancing",
+                current,
+                highest
+            );
+            counter
+                .set(highest)
+                .expect("failed to persist task counter");
+        }
+    });
+}
 
-            // Scheduler step
-            latents = self.scheduler.step(&noise_pred, timestep, &latents);
+fn get_current_time() -> u64 {
+    time()
+}
+
+// Describe how a prompt longer than one CLIP chunk was encoded
+fn chunking_warning(name: &str, prompt: &EncodedPrompt) -> Option<String> {
+    if prompt.truncated() {
+        Some(format!(
+            "{} has {} tokens; only the first {} were used",
+            name,
+            prompt.token_count,
+            MAX_CHUNKS * CHUNK_LENGTH
+        ))
+    } else if prompt.token_count > CHUNK_LENGTH {
+        Some(format!(
+            "{} has {} tokens and was encoded in {} chunks of {}",
+            name,
+            prompt.token_count,
+            prompt.chunks.len(),
+            CHUNK_LENGTH
+        ))
+    } else {
+        None
+    }
+}
+
+// Draw a seed from the management canister's randomness. Seeds are kept to
+// 32 bits since PyTorch-compatible generators ignore the upper half.
+async fn random_seed() -> Result<u64, String> {
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|(code, msg)| format!("Failed to draw a random seed: {:?} {}", code, msg))?;
+    let seed_bytes = bytes
+        .get(..4)
+        .and_then(|bytes| <[u8; 4]>::try_from(bytes).ok())
+        .ok_or_else(|| "raw_rand returned too few bytes".to_string())?;
+    Ok(u32::from_le_bytes(seed_bytes) as u64)
+}
+
+fn get_config() -> CanisterConfig {
+    CONFIG.with(|config| config.borrow().get().clone())
+}
+
+// Checks the values generation will use, so unset parameters are checked
+// against the limits with their defaults
+fn validate_request(request: &GenerationRequest, config: &CanisterConfig) -> Result<(), String> {
+    let dimensions = [("width", request.width), ("height", request.height)];
+    for (name, value) in dimensions {
+        validate_dimension(name, value.unwrap_or(DEFAULT_DIMENSION), config)?;
+    }
+    // Timesteps are spaced evenly over the training schedule, which has no
+    // room for more steps than it has timesteps
+    let max_steps = SchedulerConfig::default().num_train_timesteps as u32;
+    let steps = request
+        .num_inference_steps
+        .unwrap_or(DEFAULT_INFERENCE_STEPS);
+    if !(1..=max_steps).contains(&steps) {
+        return Err(format!(
+            "num_inference_steps must be between 1 and {}",
+            max_steps
+        ));
+    }
+    // A NaN or infinite scale turns every latent into NaN
+    if let Some(guidance_scale) = request.guidance_scale
+        && !(guidance_scale.is_finite() && guidance_scale >= 0.0)
+    {
+        return Err("guidance_scale must be a finite number of at least 0".to_string());
+    }
+    if let Some(eta) = request.eta
+        && !(0.0..=1.0).contains(&eta)
+    {
+        return Err("eta must be between 0 and 1".to_string());
+    }
+    if let Some(tile_size) = request.vae_tile_size {
+        validate_tile_size(tile_size)?;
+    }
+    if request.init_image.is_some() {
+        let strength = request
+            .strength
+            .unwrap_or_else(|| request.default_strength());
+        if !(strength > 0.0 && strength <= 1.0) {
+            return Err("strength must be greater than 0 and at most 1".to_string());
+        }
+        let steps = steps as usize;
+        if strength_start_step(steps, strength) >= steps {
+            return Err(format!(
+                "strength {} leaves none of the {} steps to run",
+                strength, steps
+            ));
+        }
+    } else if request.strength.is_some() {
+        return Err("strength only applies with an init_image".to_string());
+    } else if request.mask.is_some() {
+        return Err("mask only applies with an init_image".to_string());
+    }
+    Ok(())
+}
+
+fn validate_dimension(name: &str, value: u32, config: &CanisterConfig) -> Result<(), String> {
+    if value == 0 || !value.is_multiple_of(VAE_SCALE_FACTOR) {
+        return Err(format!(
+            "{} must be a positive multiple of {}",
+            name, VAE_SCALE_FACTOR
+        ));
+    }
+    if value > config.max_image_dimension {
+        return Err(format!(
+            "{} must not exceed {}",
+            name, config.max_image_dimension
+        ));
+    }
+    Ok(())
+}
+
+// Check that an img2img request's init image and inpainting mask exist and
+// take the output size from the image where the request leaves it unset
+fn resolve_init_image(request: &mut GenerationRequest) -> Result<(), String> {
+    let Some(ref init_image) = request.init_image else {
+        return Ok(());
+    };
+    let image = load_init_image(init_image)?;
+    for (name, value, actual) in [
+        ("width", &mut request.width, image.width),
+        ("height", &mut request.height, image.height),
+    ] {
+        if *value.get_or_insert(actual) != actual {
+            return Err(format!(
+                "{} must match the init image, which is {}x{}",
+                name, image.width, image.height
+            ));
+        }
+    }
+    if load_mask(request, image.width, image.height)?.is_some_and(|mask| mask.is_empty()) {
+        return Err("mask selects no pixels to regenerate".to_string());
+    }
+    Ok(())
+}
+
+fn validate_tile_size(tile_size: u32) -> Result<(), String> {
+    if tile_size < MIN_VAE_TILE_SIZE || !tile_size.is_multiple_of(VAE_SCALE_FACTOR) {
+        return Err(format!(
+            "vae_tile_size must be a multiple of {} of at least {}",
+            VAE_SCALE_FACTOR, MIN_VAE_TILE_SIZE
+        ));
+    }
+    Ok(())
+}
+
+fn store_task(task: GenerationTask) {
+    TASK_STORE.with(|store| {
+        store
+            .borrow_mut()
+            .insert(task.id.clone(), StorableGenerationTask::new(task));
+    });
+}
+
+fn load_task(task_id: &str) -> Option<GenerationTask> {
+    let mut task = TASK_STORE
+        .with(|store| store.borrow().get(&task_id.to_string()))?
+        .task;
+    // Unreadable records decode to a placeholder without an id
+    if task.id.is_empty() {
+        task.id = task_id.to_string();
+    }
+    Some(task)
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Image storage
+
+fn store_image(image_id: &str, bytes: &[u8], content_type: &str) -> ImageRef {
+    remove_image(image_id);
+
+    let chunks: Vec<&[u8]> = bytes.chunks(IMAGE_CHUNK_SIZE).collect();
+    IMAGE_STORE.with(|store| {
+        let mut store = store.borrow_mut();
+        for (index, chunk) in chunks.iter().enumerate() {
+            let key = ChunkKey {
+                id: image_id.to_string(),
+                index: index as u32,
+            };
+            store.insert(key, chunk.to_vec());
+        }
+    });
+
+    ImageRef {
+        image_id: image_id.to_string(),
+        size: bytes.len() as u64,
+        content_type: content_type.to_string(),
+        sha256: sha256_hex(bytes),
+        chunk_count: chunks.len() as u32,
+    }
+}
+
+fn load_image(image: &ImageRef) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(image.size as usize);
+    IMAGE_STORE.with(|store| {
+        let store = store.borrow();
+        for index in 0..image.chunk_count {
+            let key = ChunkKey {
+                id: image.image_id.clone(),
+                index,
+            };
+            match store.get(&key) {
+                Some(chunk) => bytes.extend_from_slice(&chunk),
+                None => return Err(format!("Image chunk {} is missing", index)),
+            }
+        }
+        Ok(())
+    })?;
+
+    if bytes.len() as u64 != image.size {
+        return Err("Stored image size does not match its reference".to_string());
+    }
+    Ok(bytes)
+}
+
+fn remove_image(image_id: &str) {
+    IMAGE_STORE.with(|store| {
+        let mut store = store.borrow_mut();
+        let start = ChunkKey {
+            id: image_id.to_string(),
+            index: 0,
+        };
+        let keys: Vec<ChunkKey> = store
+            .keys_range(start..)
+            .take_while(|key| key.id == image_id)
+            .collect();
+        for key in keys {
+            store.remove(&key);
+        }
+    });
+}
+
+fn load_model_file(name: &str) -> Option<Vec<u8>> {
+    MODEL_STORE.with(|store| store.borrow().load(name))
+}
+
+// Free the copies of `names` replaced since the model last loaded them, once
+// the components reading them have been swapped out
+fn release_model_files(names: &[&str]) {
+    MODEL_STORE.with(|store| {
+        let mut store = store.borrow_mut();
+        for name in names {
+            store.release(name);
+        }
+    });
+}
+
+// Build the CLIP tokenizer from the uploaded files, if both are present
+fn load_stored_tokenizer() -> Option<Result<ClipTokenizer, String>> {
+    let vocab = load_model_file(TOKENIZER_VOCAB)?;
+    let merges = load_model_file(TOKENIZER_MERGES)?;
+    Some(ClipTokenizer::from_files(&vocab, &merges))
+}
+
+// Build the CLIP text encoder from the uploaded weights, if present
+fn load_stored_text_encoder() -> Option<Result<ClipTextEncoder<Memory>, String>> {
+    let tensors = MODEL_STORE.with(|store| store.borrow().tensors(TEXT_ENCODER_WEIGHTS))?;
+    Some(tensors.and_then(ClipTextEncoder::from_tensors))
+}
+
+fn load_stored_unet() -> Option<Result<UNet2DConditionModel<Memory>, String>> {
+    let tensors = MODEL_STORE.with(|store| store.borrow().tensors(UNET_WEIGHTS))?;
+    Some(tensors.and_then(UNet2DConditionModel::from_tensors))
+}
+
+fn load_stored_taesd() -> Option<Result<TaesdDecoder<Memory>, String>> {
+    let tensors = MODEL_STORE.with(|store| store.borrow().tensors(TAESD_WEIGHTS))?;
+    Some(tensors.and_then(TaesdDecoder::from_tensors))
+}
+
+// Latest preview of a task that is still processing
+fn load_preview(task_id: &str) -> Result<TaskPreview, String> {
+    let task = load_task(task_id).ok_or_else(|| "Task not found".to_string())?;
+    if !matches!(task.status, TaskStatus::Processing) {
+        return Err("Previews are only available while a task is processing".to_string());
+    }
+    PREVIEW_STORE
+        .with(|store| store.borrow().get(&task_id.to_string()))
+        .and_then(|StoredRecord(preview)| preview)
+        .ok_or_else(|| "No preview has been rendered yet".to_string())
+}
+
+fn load_stored_vae() -> Option<Result<AutoencoderDecoder<Memory>, String>> {
+    let tensors = MODEL_STORE.with(|store| store.borrow().tensors(VAE_WEIGHTS))?;
+    Some(tensors.and_then(AutoencoderDecoder::from_tensors))
+}
+
+fn load_stored_vae_encoder() -> Option<Result<AutoencoderEncoder<Memory>, String>> {
+    let tensors = MODEL_STORE.with(|store| store.borrow().tensors(VAE_WEIGHTS))?;
+    Some(tensors.and_then(AutoencoderEncoder::from_tensors))
+}
+
+// Store uploaded init image bytes for `uploader`, first evicting their old
+// uploads beyond the caps. Each caller's uploads are kept apart: the id is
+// derived from the uploader and the bytes, and uploading the same bytes
+// again renews the stored copy.
+fn store_upload(
+    uploader: Principal,
+    now: u64,
+    image: &[u8],
+    content_type: &str,
+) -> Result<String, String> {
+    let key = [uploader.as_slice(), image].concat();
+    let upload_id = format!("upload_{}", &sha256_hex(&key)[..16]);
+    let existing = UPLOAD_STORE
+        .with(|store| store.borrow().get(&upload_id))
+        .and_then(|StoredRecord(upload)| upload);
+    let upload = match existing {
+        Some(upload) => UploadRecord {
+            uploaded_at: now,
+            ..upload
+        },
+        None => {
+            evict_uploads(uploader)?;
+            UploadRecord {
+                image: store_image(&upload_id, image, content_type),
+                uploader,
+                uploaded_at: now,
+            }
+        }
+    };
+    UPLOAD_STORE.with(|store| {
+        store
+            .borrow_mut()
+            .insert(upload_id.clone(), StoredRecord(Some(upload)))
+    });
+    Ok(upload_id)
+}
+
+// Make room for one more upload by `uploader`, removing their oldest uploads
+// that no pending or processing task refers to. Other callers' uploads are
+// never evicted, so once every caller together holds MAX_UPLOADS, only those
+// with uploads of their own to give up can upload.
+fn evict_uploads(uploader: Principal) -> Result<(), String> {
+    let in_use: BTreeSet<String> = TASK_STORE.with(|store| {
+        store
+            .borrow()
+            .values()
+            .map(|stored| stored.task)
+            .filter(|task| matches!(task.status, TaskStatus::Pending | TaskStatus::Processing))
+            .flat_map(|task| {
+                let init_image = match task.request.init_image {
+                    Some(InitImage::Upload(id)) => Some(id),
+                    _ => None,
+                };
+                let mask = match task.request.mask {
+                    Some(InpaintMask::Upload(id)) => Some(id),
+                    _ => None,
+                };
+                init_image.into_iter().chain(mask)
+            })
+            .collect()
+    });
+    let (total, mut own) = UPLOAD_STORE.with(|store| {
+        let store = store.borrow();
+        let own: Vec<(u64, String)> = store
+            .iter()
+            .filter_map(|(id, StoredRecord(upload))| {
+                let upload = upload?;
+                (upload.uploader == uploader).then_some((upload.uploaded_at, id))
+            })
+            .collect();
+        (store.len() as usize, own)
+    });
+    own.sort();
+    let own_count = own.len();
+
+    let excess = (own_count + 1)
+        .saturating_sub(MAX_UPLOADS_PER_CALLER)
+        .max((total + 1).saturating_sub(MAX_UPLOADS));
+    let evictable: Vec<String> = own
+        .into_iter()
+        .map(|(_, id)| id)
+        .filter(|id| !in_use.contains(id))
+        .take(excess)
+        .collect();
+    if evictable.len() < excess {
+        return Err(if own_count >= MAX_UPLOADS_PER_CALLER {
+            format!(
+                "All {} of your uploads are used by queued tasks",
+                MAX_UPLOADS_PER_CALLER
+            )
+        } else {
+            "Upload storage is full; try again later".to_string()
+        });
+    }
+    for id in evictable {
+        remove_image(&id);
+        UPLOAD_STORE.with(|store| store.borrow_mut().remove(&id));
+    }
+    Ok(())
+}
+
+// Decode the init image of an img2img request
+fn load_init_image(init_image: &InitImage) -> Result<RgbImage, String> {
+    let bytes = match init_image {
+        InitImage::Upload(upload_id) => {
+            let upload = UPLOAD_STORE
+                .with(|store| store.borrow().get(upload_id))
+                .and_then(|StoredRecord(upload)| upload)
+                .ok_or_else(|| format!("Upload {} not found", upload_id))?;
+            load_image(&upload.image)?
         }
+        InitImage::Task(task_id) => load_task_image(task_id)?.1,
+    };
+    image_codec::decode(&bytes)
+}
+
+// Build the inpainting mask of a request for a `width` x `height` image
+fn load_mask(request: &GenerationRequest, width: u32, height: u32) -> Result<Option<Mask>, String> {
+    let mask = match request.mask {
+        Some(InpaintMask::Upload(ref upload_id)) => {
+            let image = load_init_image(&InitImage::Upload(upload_id.clone()))?;
+            if (image.width, image.height) != (width, height) {
+                return Err(format!(
+                    "Mask is {}x{}, the init image is {}x{}",
+                    image.width, image.height, width, height
+                ));
+            }
+            Mask::from_image(&image)
+        }
+        Some(InpaintMask::Rectangles(ref rects)) => Mask::from_rectangles(width, height, rects)?,
+        None => return Ok(None),
+    };
+    Ok(Some(mask))
+}
+
+// Copy the pixels an inpainting request keeps from its init image, undoing
+// the small changes the VAE round trip makes to them
+fn preserve_unmasked(request: &GenerationRequest, image: &mut RgbImage) -> Result<(), String> {
+    let (Some(init_image), Some(mask)) = (
+        request.init_image.as_ref(),
+        load_mask(request, image.width, image.height)?,
+    ) else {
+        return Ok(());
+    };
+    mask.preserve_unmasked(image, &load_init_image(init_image)?)
+}
+
+// Look up a task's image, returning its reference alongside the bytes
+fn load_task_image(task_id: &str) -> Result<(ImageRef, Vec<u8>), String> {
+    let task = load_task(task_id).ok_or_else(|| "Task not found".to_string())?;
+    let image = task
+        .image
+        .ok_or_else(|| "Image not ready or generation failed".to_string())?;
+    let bytes = load_image(&image)?;
+    Ok((image, bytes))
+}
+
+// Rewrite task records stored in an older layout in the current one, moving
+// images still stored inline into IMAGE_STORE
+fn migrate_task_records() -> MigrationReport {
+    let mut report = MigrationReport::default();
+
+    let outdated: Vec<StorableGenerationTask> = TASK_STORE.with(|store| {
+        store
+            .borrow()
+            .values()
+            .inspect(|stored| {
+                report.scanned += 1;
+                if stored.unreadable {
+                    report.unreadable += 1;
+                }
+            })
+            .filter(StorableGenerationTask::needs_migration)
+            .collect()
+    });
+
+    for stored in outdated {
+        let mut task = stored.task;
+        if let Some(bytes) = stored.inline_image {
+            // The first release only produced BMP images
+            let content_type = OutputFormat::Bmp.content_type();
+            task.image = Some(store_image(&task.id, &bytes, content_type));
+            report.images_moved += 1;
+        }
+        store_task(task);
+        report.converted += 1;
+    }
 
-        // Decode latents to image
-        let image_bytes = self.vae_decoder.decode(&latents);
+    report
+}
+
+// Background worker
+//
+// Tasks move Pending -> Processing -> Completed/Failed across separate timer
+// ticks, so callers polling get_task_status can observe every state. While
+// Processing, each tick advances the checkpointed pipeline by as much work
+// as fits in its instruction budget (see TickBudget).
+//
+// A tick that traps is rolled back as a whole, so each tick is preceded by a
+// cheap message that claims the task and counts the attempt. A task whose
+// ticks keep trapping is failed once MAX_TICK_ATTEMPTS is reached instead of
+// blocking the tasks queued behind it, and a watchdog timer restarts the
+// worker after a trap.
+
+// Instruction budget of a worker tick. The pipeline runs in units of work (a
+// text encoder pass over one prompt chunk, one UNet forward or one VAE
+// tile), and a tick starts another unit while the instructions spent so far
+// plus the largest unit measured in the tick stay within the budget. At
+// least one unit runs per tick: a unit that does not fit in a message on its
+// own, e.g. a UNet forward on too large an image, traps every attempt and
+// its task fails after MAX_TICK_ATTEMPTS. Smaller dimensions or VAE tiles
+// bring such a request within the limit.
+struct TickBudget {
+    limit: u64,
+    counter: Box<dyn Fn() -> u64>,
+    start: u64,
+    last: u64,
+    largest_unit: u64,
+}
+
+impl TickBudget {
+    fn new(limit: u64, counter: Box<dyn Fn() -> u64>) -> Self {
+        let start = counter();
+        Self {
+            limit,
+            counter,
+            start,
+            last: start,
+            largest_unit: 0,
+        }
+    }
+
+    // Counts the instructions executed by the current message
+    fn for_message() -> Self {
+        Self::new(
+            TICK_INSTRUCTION_BUDGET,
+            Box::new(|| ic_cdk::api::performance_counter(0)),
+        )
+    }
+
+    // Record the end of a unit of work, returning whether another one as
+    // large as the largest so far still fits
+    fn another_fits(&mut self) -> bool {
+        let now = (self.counter)();
+        self.largest_unit = self.largest_unit.max(now - self.last);
+        self.last = now;
+        now - self.start + self.largest_unit < self.limit
+    }
+}
+
+fn schedule_worker() {
+    let now = get_current_time();
+    let queued = WORKER_QUEUED_AT.with(|queued_at| {
+        queued_at
+            .get()
+            .is_some_and(|at| now.saturating_sub(at) < WORKER_STALL_TIMEOUT.as_nanos() as u64)
+    });
+    if queued {
+        return;
+    }
+
+    WORKER_QUEUED_AT.with(|queued_at| queued_at.set(Some(now)));
+    ic_cdk_timers::set_timer(Duration::ZERO, claim_next_task);
+}
+
+fn start_worker_watchdog() {
+    ic_cdk_timers::set_timer_interval(WORKER_WATCHDOG_INTERVAL, schedule_worker);
+}
+
+fn find_task(status: fn(&TaskStatus) -> bool) -> Option<GenerationTask> {
+    TASK_STORE.with(|store| {
+        store
+            .borrow()
+            .values()
+            .map(|stored| stored.task)
+            .filter(|task| status(&task.status))
+            .min_by_key(|task| task.created_at)
+    })
+}
+
+// Model components can only be swapped between tasks: the checkpoint of a
+// task in progress holds embeddings, latents or tiles shaped by the
+// components that built it
+fn check_no_task_processing() -> Result<(), String> {
+    match find_task(|s| matches!(s, TaskStatus::Processing)) {
+        Some(task) => Err(format!(
+            "Task {} is in progress; load model files once it finishes",
+            task.id
+        )),
+        None => Ok(()),
+    }
+}
+
+// Pick the next task and commit the attempt, then run the tick in a message
+// of its own
+fn claim_next_task() {
+    let Some(mut task) = find_task(|s| matches!(s, TaskStatus::Processing))
+        .or_else(|| find_task(|s| matches!(s, TaskStatus::Pending)))
+    else {
+        WORKER_QUEUED_AT.with(|queued_at| queued_at.set(None));
+        return;
+    };
+
+    let attempts = task.attempts.unwrap_or(0);
+    if attempts >= MAX_TICK_ATTEMPTS {
+        fail_task(
+            &mut task,
+            format!(
+                "Worker tick trapped {} times in a row, likely exceeding the instruction or memory limit; a smaller image or vae_tile_size may fit",
+                attempts
+            ),
+        );
+        store_task(task);
+        ic_cdk_timers::set_timer(Duration::ZERO, claim_next_task);
+        return;
+    }
+
+    task.attempts = Some(attempts + 1);
+    let task_id = task.id.clone();
+    store_task(task);
+    ic_cdk_timers::set_timer(Duration::ZERO, move || process_task(&task_id));
+}
+
+fn process_task(task_id: &str) {
+    if let Some(mut task) = load_task(task_id) {
+        // Reaching the end of the tick means it did not trap
+        task.attempts = None;
+        match task.status {
+            TaskStatus::Processing => run_task(task),
+            TaskStatus::Pending => start_task(task),
+            TaskStatus::Completed | TaskStatus::Failed => {}
+        }
+    }
+
+    // Keep draining the queue on subsequent ticks
+    WORKER_QUEUED_AT.with(|queued_at| queued_at.set(None));
+    schedule_worker();
+}
+
+fn with_model<R>(f: impl FnOnce(&StableDiffusionModel) -> Result<R, String>) -> Result<R, String> {
+    MODEL.with(|model| match *model.borrow() {
+        Some(ref sd_model) => f(sd_model),
+        None => Err("Model not initialized".to_string()),
+    })
+}
+
+fn start_task(mut task: GenerationTask) {
+    // The prompts are encoded on the following ticks
+    match with_model(|model| Ok(model.tokenize(&task.request).2)) {
+        Ok(warning) => {
+            task.status = TaskStatus::Processing;
+            task.progress = None;
+            task.warning = warning;
+            let checkpoint = PipelineCheckpoint::Preparing(Box::default());
+            PIPELINE_STORE.with(|store| {
+                store
+                    .borrow_mut()
+                    .insert(task.id.clone(), StorablePipelineState(Some(checkpoint)))
+            });
+        }
+        Err(error_msg) => fail_task(&mut task, error_msg),
+    }
+
+    store_task(task);
+}
+
+// Mark a task failed and drop its transient state; the caller stores it
+fn fail_task(task: &mut GenerationTask, error_msg: String) {
+    task.status = TaskStatus::Failed;
+    task.completed_at = Some(get_current_time());
+    task.error = Some(error_msg);
+    PIPELINE_STORE.with(|store| store.borrow_mut().remove(&task.id));
+    PREVIEW_STORE.with(|store| store.borrow_mut().remove(&task.id));
+}
+
+enum TickOutcome {
+    InProgress(PipelineCheckpoint, Option<TaskPreview>),
+    Finished(RgbImage),
+}
+
+fn run_task(mut task: GenerationTask) {
+    let checkpoint = PIPELINE_STORE.with(|store| store.borrow().get(&task.id));
+
+    let mut budget = TickBudget::for_message();
+    let result = with_model(|model| {
+        let checkpoint = match checkpoint {
+            Some(StorablePipelineState(Some(checkpoint))) => checkpoint,
+            // Processing without a usable checkpoint: restart from the beginning
+            _ => PipelineCheckpoint::Preparing(Box::default()),
+        };
+        let mut state = match checkpoint {
+            PipelineCheckpoint::Running(state) => state,
+            PipelineCheckpoint::Preparing(mut preparation) => {
+                let checkpoint =
+                    match model.prepare(&task.request, &mut preparation, &mut budget)? {
+                        Some(state) => PipelineCheckpoint::Running(Box::new(state)),
+                        None => PipelineCheckpoint::Preparing(preparation),
+                    };
+                return Ok(TickOutcome::InProgress(checkpoint, None));
+            }
+        };
+
+        if state.is_finished() {
+            return match model.decode_tiles(&mut state, &mut budget)? {
+                Some(mut image) => {
+                    preserve_unmasked(&task.request, &mut image)?;
+                    Ok(TickOutcome::Finished(image))
+                }
+                None => Ok(TickOutcome::InProgress(
+                    PipelineCheckpoint::Running(state),
+                    None,
+                )),
+            };
+        }
+
+        // One preview per tick covers the latest step; earlier ones would
+        // be overwritten before anyone could fetch them
+        model.run_steps(&mut state, &mut budget)?;
+        let preview = model
+            .render_preview(&task.id, &state)
+            .inspect_err(|error| ic_cdk::println!("Preview failed: {}", error))
+            .ok();
+        Ok(TickOutcome::InProgress(
+            PipelineCheckpoint::Running(state),
+            preview,
+        ))
+    });
+
+    match result {
+        Ok(TickOutcome::InProgress(checkpoint, preview)) => {
+            task.progress = checkpoint.progress();
+            if let Some(preview) = preview {
+                PREVIEW_STORE.with(|store| {
+                    store
+                        .borrow_mut()
+                        .insert(task.id.clone(), StoredRecord(Some(preview)))
+                });
+            }
+            PIPELINE_STORE.with(|store| {
+                store
+                    .borrow_mut()
+                    .insert(task.id.clone(), StorablePipelineState(Some(checkpoint)))
+            });
+        }
+        Ok(TickOutcome::Finished(image)) => {
+            let format = task.request.output_format.unwrap_or_default();
+            let metadata = ImageMetadata {
+                task_id: task.id.clone(),
+                model_version: MODEL_VERSION.to_string(),
+                canister_id: ic_cdk::id().to_text(),
+                request: task.request.clone(),
+            };
+            let image_bytes = image_codec::encode(&image, format, &metadata.to_text_entries());
+            task.status = TaskStatus::Completed;
+            task.completed_at = Some(get_current_time());
+            task.image = Some(store_image(&task.id, &image_bytes, format.content_type()));
+            PIPELINE_STORE.with(|store| store.borrow_mut().remove(&task.id));
+            PREVIEW_STORE.with(|store| store.borrow_mut().remove(&task.id));
+        }
+        Err(error_msg) => fail_task(&mut task, error_msg),
+    }
+
+    store_task(task);
+}
+
+// API Endpoints
+
+#[update]
+async fn generate_image(mut request: GenerationRequest) -> ApiResponse<String> {
+    let current_time = get_current_time();
+
+    let validation =
+        resolve_init_image(&mut request).and_then(|()| validate_request(&request, &get_config()));
+    if let Err(error_msg) = validation {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: current_time,
+        };
+    }
+
+    // Record every effective parameter so the task can be reproduced
+    if request.seed.is_none() {
+        match random_seed().await {
+            Ok(seed) => request.seed = Some(seed),
+            Err(error_msg) => {
+                return ApiResponse {
+                    success: false,
+                    data: None,
+                    error: Some(error_msg),
+                    timestamp: current_time,
+                };
+            }
+        }
+    }
+    request.fill_defaults(&get_config());
+
+    let task_id = generate_task_id();
+
+    // Queue the task; the timer-driven worker picks it up
+    store_task(GenerationTask {
+        id: task_id.clone(),
+        status: TaskStatus::Pending,
+        created_at: current_time,
+        completed_at: None,
+        request,
+        image: None,
+        error: None,
+        progress: None,
+        warning: None,
+        attempts: None,
+    });
+    schedule_worker();
+
+    ApiResponse {
+        success: true,
+        data: Some(task_id),
+        error: None,
+        timestamp: current_time,
+    }
+}
+
+// Store an image for img2img requests to start from, returning its upload
+// id. Uploading the same bytes again returns the same id. Each caller keeps
+// at most MAX_UPLOADS_PER_CALLER uploads, and their older ones are evicted
+// unless a queued task still needs them.
+#[update]
+fn upload_init_image(image: Vec<u8>) -> ApiResponse<String> {
+    let current_time = get_current_time();
+    let config = get_config();
+
+    let checked = image_codec::detect_format(&image)
+        .ok_or_else(|| "Unsupported image format".to_string())
+        .and_then(|format| {
+            let decoded = image_codec::decode(&image)?;
+            validate_dimension("width", decoded.width, &config)?;
+            validate_dimension("height", decoded.height, &config)?;
+            Ok(format)
+        });
+    let format = match checked {
+        Ok(format) => format,
+        Err(error_msg) => {
+            return ApiResponse {
+                success: false,
+                data: None,
+                error: Some(format!("Invalid init image: {}", error_msg)),
+                timestamp: current_time,
+            };
+        }
+    };
+
+    match store_upload(
+        ic_cdk::caller(),
+        current_time,
+        &image,
+        format.content_type(),
+    ) {
+        Ok(upload_id) => ApiResponse {
+            success: true,
+            data: Some(upload_id),
+            error: None,
+            timestamp: current_time,
+        },
+        Err(error_msg) => ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: current_time,
+        },
+    }
+}
+
+#[query]
+fn get_task_status(task_id: String) -> ApiResponse<GenerationTask> {
+    if let Some(task) = load_task(&task_id) {
+        ApiResponse {
+            success: true,
+            data: Some(task),
+            error: None,
+            timestamp: get_current_time(),
+        }
+    } else {
+        ApiResponse {
+            success: false,
+            data: None,
+            error: Some("Task not found".to_string()),
+            timestamp: get_current_time(),
+        }
+    }
+}
+
+#[query]
+fn get_image(task_id: String) -> ApiResponse<Vec<u8>> {
+    match load_task_image(&task_id) {
+        Ok((_, image_data)) => ApiResponse {
+            success: true,
+            data: Some(image_data),
+            error: None,
+            timestamp: get_current_time(),
+        },
+        Err(error_msg) => ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        },
+    }
+}
+
+#[query]
+fn get_preview(task_id: String) -> ApiResponse<TaskPreview> {
+    match load_preview(&task_id) {
+        Ok(preview) => ApiResponse {
+            success: true,
+            data: Some(preview),
+            error: None,
+            timestamp: get_current_time(),
+        },
+        Err(error_msg) => ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        },
+    }
+}
+
+#[query]
+fn list_tasks() -> ApiResponse<Vec<String>> {
+    TASK_STORE.with(|store| {
+        let task_ids: Vec<String> = store.borrow().keys().collect();
+
+        ApiResponse {
+            success: true,
+            data: Some(task_ids),
+            error: None,
+            timestamp: get_current_time(),
+        }
+    })
+}
+
+// Parse the generation metadata back out of an image produced by this canister
+#[query]
+fn read_image_metadata(image: Vec<u8>) -> ApiResponse<ImageMetadata> {
+    let metadata = image_codec::read_metadata(&image)
+        .and_then(|entries| ImageMetadata::from_text_entries(&entries));
+
+    match metadata {
+        Ok(metadata) => ApiResponse {
+            success: true,
+            data: Some(metadata),
+            error: None,
+            timestamp: get_current_time(),
+        },
+        Err(error_msg) => ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        },
+    }
+}
+
+#[query(name = "get_config")]
+fn get_config_query() -> ApiResponse<CanisterConfig> {
+    ApiResponse {
+        success: true,
+        data: Some(get_config()),
+        error: None,
+        timestamp: get_current_time(),
+    }
+}
+
+#[update]
+fn set_config(config: CanisterConfig) -> ApiResponse<CanisterConfig> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some("Only controllers can change the configuration".to_string()),
+            timestamp: get_current_time(),
+        };
+    }
+    if let Some(Err(error_msg)) = config.vae_tile_size.map(validate_tile_size) {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        };
+    }
+
+    match CONFIG.with(|cell| cell.borrow_mut().set(config.clone())) {
+        Ok(_) => ApiResponse {
+            success: true,
+            data: Some(config),
+            error: None,
+            timestamp: get_current_time(),
+        },
+        Err(error) => ApiResponse {
+            success: false,
+            data: None,
+            error: Some(format!("Failed to store configuration: {:?}", error)),
+            timestamp: get_current_time(),
+        },
+    }
+}
+
+// Switch the pipeline to the CLIP tokenizer built from the uploaded files
+#[update]
+fn load_tokenizer() -> ApiResponse<TokenizerInfo> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some("Only controllers can load the tokenizer".to_string()),
+            timestamp: get_current_time(),
+        };
+    }
+    if let Err(error_msg) = check_no_task_processing() {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        };
+    }
+
+    let tokenizer = match load_stored_tokenizer() {
+        Some(Ok(tokenizer)) => tokenizer,
+        Some(Err(error_msg)) => {
+            return ApiResponse {
+                success: false,
+                data: None,
+                error: Some(error_msg),
+                timestamp: get_current_time(),
+            };
+        }
+        None => {
+            return ApiResponse {
+                success: false,
+                data: None,
+                error: Some("Upload both vocab.json and merges.txt first".to_string()),
+                timestamp: get_current_time(),
+            };
+        }
+    };
+
+    let info = TokenizerInfo {
+        name: "clip-bpe".to_string(),
+        vocab_size: tokenizer.vocab_size() as u64,
+        merges: tokenizer.merge_count() as u64,
+    };
+    MODEL.with(|model| {
+        if let Some(model) = model.borrow_mut().as_mut() {
+            model.tokenizer = Tokenizer::Clip(tokenizer);
+        }
+    });
+    release_model_files(&[TOKENIZER_VOCAB, TOKENIZER_MERGES]);
+
+    ApiResponse {
+        success: true,
+        data: Some(info),
+        error: None,
+        timestamp: get_current_time(),
+    }
+}
+
+#[update]
+fn begin_model_upload(name: String, size: u64) -> ApiResponse<ModelFileInfo> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some("Only controllers can upload model files".to_string()),
+            timestamp: get_current_time(),
+        };
+    }
+
+    match MODEL_STORE.with(|store| store.borrow_mut().begin(&name, size, get_current_time())) {
+        Ok(info) => ApiResponse {
+            success: true,
+            data: Some(info),
+            error: None,
+            timestamp: get_current_time(),
+        },
+        Err(error_msg) => ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        },
+    }
+}
+
+// Append a chunk at `offset`, returning the number of bytes received so far.
+// Chunks must fit in one ingress message.
+#[update]
+fn append_model_chunk(name: String, offset: u64, data: Vec<u8>) -> ApiResponse<u64> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some("Only controllers can upload model files".to_string()),
+            timestamp: get_current_time(),
+        };
+    }
+
+    match MODEL_STORE.with(|store| store.borrow_mut().append(&name, offset, &data)) {
+        Ok(received) => ApiResponse {
+            success: true,
+            data: Some(received),
+            error: None,
+            timestamp: get_current_time(),
+        },
+        Err(error_msg) => ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        },
+    }
+}
+
+// Commit an upload once its SHA-256 matches `sha256`
+#[update]
+fn finalize_model_upload(name: String, sha256: String) -> ApiResponse<ModelFileInfo> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some("Only controllers can upload model files".to_string()),
+            timestamp: get_current_time(),
+        };
+    }
+
+    match MODEL_STORE.with(|store| {
+        store
+            .borrow_mut()
+            .finalize(&name, &sha256, get_current_time())
+    }) {
+        Ok(info) => ApiResponse {
+            success: true,
+            data: Some(info),
+            error: None,
+            timestamp: get_current_time(),
+        },
+        Err(error_msg) => ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        },
+    }
+}
+
+#[query]
+fn model_info() -> ApiResponse<Vec<ModelFileInfo>> {
+    ApiResponse {
+        success: true,
+        data: Some(MODEL_STORE.with(|store| store.borrow().info())),
+        error: None,
+        timestamp: get_current_time(),
+    }
+}
+
+// Switch the pipeline to the CLIP text encoder built from the uploaded
+// safetensors file
+#[update]
+fn load_text_encoder() -> ApiResponse<TextEncoderInfo> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some("Only controllers can load the text encoder".to_string()),
+            timestamp: get_current_time(),
+        };
+    }
+    if let Err(error_msg) = check_no_task_processing() {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        };
+    }
+
+    let encoder = match load_stored_text_encoder() {
+        Some(Ok(encoder)) => encoder,
+        Some(Err(error_msg)) => {
+            return ApiResponse {
+                success: false,
+                data: None,
+                error: Some(error_msg),
+                timestamp: get_current_time(),
+            };
+        }
+        None => {
+            return ApiResponse {
+                success: false,
+                data: None,
+                error: Some("Upload the text encoder weights first".to_string()),
+                timestamp: get_current_time(),
+            };
+        }
+    };
+
+    let config = encoder.config();
+    let info = TextEncoderInfo {
+        name: "clip".to_string(),
+        vocab_size: config.vocab_size as u64,
+        hidden_size: config.hidden_size as u64,
+        num_layers: config.num_layers as u64,
+        max_positions: config.max_positions as u64,
+    };
+    MODEL.with(|model| {
+        if let Some(model) = model.borrow_mut().as_mut() {
+            model.text_encoder = TextEncoder::Clip(Box::new(encoder));
+        }
+    });
+    release_model_files(&[TEXT_ENCODER_WEIGHTS]);
+
+    ApiResponse {
+        success: true,
+        data: Some(info),
+        error: None,
+        timestamp: get_current_time(),
+    }
+}
 
-        Ok(image_bytes)
+// Switch the pipeline to the UNet built from the uploaded safetensors file
+#[update]
+fn load_unet() -> ApiResponse<UNetInfo> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some("Only controllers can load the UNet".to_string()),
+            timestamp: get_current_time(),
+        };
+    }
+    if let Err(error_msg) = check_no_task_processing() {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        };
     }
 
-    fn generate_random_latents(&self, size: usize, seed: u64) -> Vec<f32> {
-        // Simple pseudo-random number generation
-        let mut latents = Vec::with_capacity(size);
-        let mut rng_state = seed;
+    let unet = match load_stored_unet() {
+        Some(Ok(unet)) => unet,
+        Some(Err(error_msg)) => {
+            return ApiResponse {
+                success: false,
+                data: None,
+                error: Some(error_msg),
+                timestamp: get_current_time(),
+            };
+        }
+        None => {
+            return ApiResponse {
+                success: false,
+                data: None,
+                error: Some("Upload the UNet weights first".to_string()),
+                timestamp: get_current_time(),
+            };
+        }
+    };
 
-        for _ in 0..size {
-            rng_state = rng_state.wrapping_mul(1664525).wrapping_add(1013904223);
-            let random_val = (rng_state as f32 / u64::MAX as f32) * 2.0 - 1.0;
-            latents.push(random_val * 0.18215); // SD latent scaling factor
+    let config = unet.config();
+    let info = UNetInfo {
+        name: "unet2d-condition".to_string(),
+        in_channels: config.in_channels as u64,
+        block_out_channels: config
+            .block_out_channels
+            .iter()
+            .map(|&c| c as u64)
+            .collect(),
+        layers_per_block: config.layers_per_block as u64,
+        mid_block: config.mid_block,
+        cross_attention_dim: config.cross_attention_dim as u64,
+    };
+    MODEL.with(|model| {
+        if let Some(model) = model.borrow_mut().as_mut() {
+            model.unet = UNet::Conditional(Box::new(unet));
         }
+    });
+    release_model_files(&[UNET_WEIGHTS]);
 
-        latents
+    ApiResponse {
+        success: true,
+        data: Some(info),
+        error: None,
+        timestamp: get_current_time(),
     }
 }
 
-// Helper functions
-fn generate_task_id() -> String {
-    TASK_COUNTER.with(|counter| {
-        let mut c = counter.borrow_mut();
-        *c += 1;
-        format!("task_{}", *c)
-    })
-}
-
-fn get_current_time() -> u64 {
-    time()
-}
-
-// API Endpoints
-
+// Switch the pipeline to the VAE decoder built from the uploaded safetensors
+// file
 #[update]
-async fn generate_image(request: GenerationRequest) -> ApiResponse<String> {
-    let task_id = generate_task_id();
-    let current_time = get_current_time();
+fn load_vae() -> ApiResponse<VAEInfo> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some("Only controllers can load the VAE".to_string()),
+            timestamp: get_current_time(),
+        };
+    }
+    if let Err(error_msg) = check_no_task_processing() {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        };
+    }
 
-    // Create initial task
-    let mut task = GenerationTask {
-        id: task_id.clone(),
-        status: TaskStatus::Pending,
-        created_at: current_time,
-        completed_at: None,
-        request: request.clone(),
-        result: None,
-        error: None,
+    let decoder = match load_stored_vae() {
+        Some(Ok(decoder)) => decoder,
+        Some(Err(error_msg)) => {
+            return ApiResponse {
+                success: false,
+                data: None,
+                error: Some(error_msg),
+                timestamp: get_current_time(),
+            };
+        }
+        None => {
+            return ApiResponse {
+                success: false,
+                data: None,
+                error: Some("Upload the VAE weights first".to_string()),
+                timestamp: get_current_time(),
+            };
+        }
     };
 
-    // Process the image generation first
-    let result = MODEL.with(|model| {
-        if let Some(ref sd_model) = *model.borrow() {
-            sd_model.generate_image(&request)
-        } else {
-            Err("Model not initialized".to_string())
-        }
-    });
+    // Decoder-only weights leave the mock encoder in place
+    let encoder = load_stored_vae_encoder().and_then(Result::ok);
 
-    // Update task with result before storing
-    match result {
-        Ok(image_bytes) => {
-            task.status = TaskStatus::Completed;
-            task.completed_at = Some(get_current_time());
-            task.result = Some(image_bytes);
-        }
-        Err(error_msg) => {
-            task.status = TaskStatus::Failed;
-            task.completed_at = Some(get_current_time());
-            task.error = Some(error_msg);
+    let config = decoder.config();
+    let info = VAEInfo {
+        name: "autoencoder-kl".to_string(),
+        latent_channels: config.latent_channels as u64,
+        block_out_channels: config
+            .block_out_channels
+            .iter()
+            .map(|&c| c as u64)
+            .collect(),
+        layers_per_block: config.layers_per_block as u64,
+        scaling_factor: config.scaling_factor,
+        has_encoder: encoder.is_some(),
+    };
+    MODEL.with(|model| {
+        if let Some(model) = model.borrow_mut().as_mut() {
+            model.vae_decoder = VAEDecoder::Autoencoder(Box::new(decoder));
+            model.vae_encoder = match encoder {
+                Some(encoder) => VAEEncoder::Autoencoder(Box::new(encoder)),
+                None => VAEEncoder::Mock(MockVAEEncoder::new()),
+            };
         }
-    }
-
-    // Store the completed task in a single borrow
-    TASK_STORE.with(|store| {
-        store
-            .borrow_mut()
-            .insert(task_id.clone(), StorableGenerationTask(task));
     });
+    release_model_files(&[VAE_WEIGHTS]);
 
     ApiResponse {
         success: true,
-        data: Some(task_id),
+        data: Some(info),
         error: None,
-        timestamp: current_time,
+        timestamp: get_current_time(),
     }
 }
 
-#[query]
-fn get_task_status(task_id: String) -> ApiResponse<GenerationTask> {
-    TASK_STORE.with(|store| {
-        if let Some(StorableGenerationTask(task)) = store.borrow().get(&task_id) {
-            ApiResponse {
-                success: true,
-                data: Some(task),
-                error: None,
-                timestamp: get_current_time(),
-            }
-        } else {
-            ApiResponse {
+// Switch previews to the TAESD decoder built from the uploaded safetensors
+// file, returning the decoder name
+#[update]
+fn load_preview_decoder() -> ApiResponse<String> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some("Only controllers can load the preview decoder".to_string()),
+            timestamp: get_current_time(),
+        };
+    }
+    if let Err(error_msg) = check_no_task_processing() {
+        return ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        };
+    }
+
+    let decoder = match load_stored_taesd() {
+        Some(Ok(decoder)) => PreviewDecoder::Taesd(Box::new(decoder)),
+        Some(Err(error_msg)) => {
+            return ApiResponse {
                 success: false,
                 data: None,
-                error: Some("Task not found".to_string()),
+                error: Some(error_msg),
                 timestamp: get_current_time(),
-            }
+            };
         }
-    })
-}
-
-#[query]
-fn get_image(task_id: String) -> ApiResponse<Vec<u8>> {
-    TASK_STORE.with(|store| {
-        if let Some(StorableGenerationTask(task)) = store.borrow().get(&task_id) {
-            if let Some(ref image_data) = task.result {
-                ApiResponse {
-                    success: true,
-                    data: Some(image_data.clone()),
-                    error: None,
-                    timestamp: get_current_time(),
-                }
-            } else {
-                ApiResponse {
-                    success: false,
-                    data: None,
-                    error: Some("Image not ready or generation failed".to_string()),
-                    timestamp: get_current_time(),
-                }
-            }
-        } else {
-            ApiResponse {
+        None => {
+            return ApiResponse {
                 success: false,
                 data: None,
-                error: Some("Task not found".to_string()),
+                error: Some("Upload the TAESD weights first".to_string()),
                 timestamp: get_current_time(),
-            }
+            };
         }
-    })
+    };
+
+    let name = decoder.name().to_string();
+    MODEL.with(|model| {
+        if let Some(model) = model.borrow_mut().as_mut() {
+            model.preview_decoder = decoder;
+        }
+    });
+    release_model_files(&[TAESD_WEIGHTS]);
+
+    ApiResponse {
+        success: true,
+        data: Some(name),
+        error: None,
+        timestamp: get_current_time(),
+    }
 }
 
 #[query]
-fn list_tasks() -> ApiResponse<Vec<String>> {
-    TASK_STORE.with(|store| {
-        let task_ids: Vec<String> = store.borrow().iter().map(|(id, _)| id).collect();
+fn tokenize(text: String) -> ApiResponse<TokenizeResult> {
+    match with_model(|model| {
+        let tokenizer = &model.tokenizer;
+        let fragments = prompt::parse_prompt_attention(&text);
+        let (tokens, weights): (Vec<Token>, Vec<f32>) =
+            std::iter::once((tokenizer.start_token(), 1.0))
+                .chain(tokenizer.tokenize_weighted(&fragments))
+                .chain(std::iter::once((tokenizer.end_token(), 1.0)))
+                .unzip();
+        Ok(TokenizeResult {
+            tokenizer: tokenizer.name().to_string(),
+            tokens,
+            weights,
+        })
+    }) {
+        Ok(result) => ApiResponse {
+            success: true,
+            data: Some(result),
+            error: None,
+            timestamp: get_current_time(),
+        },
+        Err(error_msg) => ApiResponse {
+            success: false,
+            data: None,
+            error: Some(error_msg),
+            timestamp: get_current_time(),
+        },
+    }
+}
 
-        ApiResponse {
+#[query]
+fn get_migration_report() -> ApiResponse<MigrationReport> {
+    match LAST_MIGRATION.with(|last| last.borrow().clone()) {
+        Some(report) => ApiResponse {
             success: true,
-            data: Some(task_ids),
+            data: Some(report),
             error: None,
             timestamp: get_current_time(),
-        }
-    })
+        },
+        None => ApiResponse {
+            success: false,
+            data: None,
+            error: Some("No migration has run since the last upgrade".to_string()),
+            timestamp: get_current_time(),
+        },
+    }
 }
 
 // HTTP Interface for external access
@@ -564,27 +2588,73 @@ fn http_request(req: HttpRequest) -> HttpResponse {
         }
         ("GET", path) if path.starts_with("image/") => {
             let task_id = path.strip_prefix("image/").unwrap_or("");
-            let response = get_image(task_id.to_string());
 
-            if response.success && response.data.is_some() {
-                HttpResponse {
+            match load_task_image(task_id) {
+                Ok((image, body)) => HttpResponse {
                     status: Nat::from(200u16),
                     headers: vec![HttpHeader {
                         name: "Content-Type".to_string(),
-                        value: "image/bmp".to_string(),
+                        value: image.content_type,
                     }],
-                    body: response.data.unwrap(),
+                    body,
+                },
+                Err(error_msg) => {
+                    let response = ApiResponse::<Vec<u8>> {
+                        success: false,
+                        data: None,
+                        error: Some(error_msg),
+                        timestamp: get_current_time(),
+                    };
+
+                    HttpResponse {
+                        status: Nat::from(404u16),
+                        headers: vec![HttpHeader {
+                            name: "Content-Type".to_string(),
+                            value: "application/json".to_string(),
+                        }],
+                        body: serde_json::to_string(&response)
+                            .unwrap_or_default()
+                            .into_bytes(),
+                    }
                 }
-            } else {
-                HttpResponse {
-                    status: Nat::from(404u16),
-                    headers: vec![HttpHeader {
-                        name: "Content-Type".to_string(),
-                        value: "application/json".to_string(),
-                    }],
-                    body: serde_json::to_string(&response)
-                        .unwrap_or_default()
-                        .into_bytes(),
+            }
+        }
+        ("GET", path) if path.starts_with("preview/") => {
+            let task_id = path.strip_prefix("preview/").unwrap_or("");
+
+            match load_preview(task_id) {
+                Ok(preview) => HttpResponse {
+                    status: Nat::from(200u16),
+                    headers: vec![
+                        HttpHeader {
+                            name: "Content-Type".to_string(),
+                            value: preview.content_type,
+                        },
+                        HttpHeader {
+                            name: "Cache-Control".to_string(),
+                            value: "no-store".to_string(),
+                        },
+                    ],
+                    body: preview.image,
+                },
+                Err(error_msg) => {
+                    let response = ApiResponse::<Vec<u8>> {
+                        success: false,
+                        data: None,
+                        error: Some(error_msg),
+                        timestamp: get_current_time(),
+                    };
+
+                    HttpResponse {
+                        status: Nat::from(404u16),
+                        headers: vec![HttpHeader {
+                            name: "Content-Type".to_string(),
+                            value: "application/json".to_string(),
+                        }],
+                        body: serde_json::to_string(&response)
+                            .unwrap_or_default()
+                            .into_bytes(),
+                    }
                 }
             }
         }
@@ -667,6 +2737,10 @@ fn init() {
     MODEL.with(|model| {
         *model.borrow_mut() = Some(StableDiffusionModel::new());
     });
+    // The model was just built from the committed copies
+    release_model_files(model_store::MODEL_FILES);
+
+    start_worker_watchdog();
 }
 
 #[pre_upgrade]
@@ -678,4 +2752,501 @@ fn pre_upgrade() {
 fn post_upgrade() {
     // Reinitialize the model after upgrade
     init();
+
+    let mut report = migrate_task_records();
+    report.completed_at = get_current_time();
+    ic_cdk::println!(
+        "Task migration: {} scanned, {} converted, {} images moved, {} unreadable",
+        report.scanned,
+        report.converted,
+        report.images_moved,
+        report.unreadable
+    );
+    LAST_MIGRATION.with(|last| *last.borrow_mut() = Some(report));
+
+    reconcile_task_counter();
+
+    // Timers do not survive upgrades; resume any queued work
+    schedule_worker();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    fn queued_task(id: &str) -> GenerationTask {
+        GenerationTask {
+            id: id.to_string(),
+            status: TaskStatus::Pending,
+            created_at: 0,
+            completed_at: None,
+            request: GenerationRequest::default(),
+            image: None,
+            error: None,
+            progress: None,
+            warning: None,
+            attempts: None,
+        }
+    }
+
+    fn counter() -> u64 {
+        TASK_COUNTER.with(|counter| *counter.borrow().get())
+    }
+
+    #[test]
+    fn task_ids_skip_ids_already_taken() {
+        store_task(queued_task("task_1"));
+        store_task(queued_task("task_2"));
+        assert_eq!(counter(), 0);
+
+        assert_eq!(generate_task_id(), "task_3");
+        assert_eq!(counter(), 3);
+        assert_eq!(generate_task_id(), "task_4");
+    }
+
+    #[test]
+    fn reconcile_advances_the_counter_past_stored_ids() {
+        store_task(queued_task("task_7"));
+        store_task(queued_task("task_12"));
+        store_task(queued_task("imported"));
+        reconcile_task_counter();
+        assert_eq!(counter(), 12);
+
+        // Never moves the counter back
+        TASK_STORE.with(|store| store.borrow_mut().remove(&"task_12".to_string()));
+        reconcile_task_counter();
+        assert_eq!(counter(), 12);
+        assert_eq!(generate_task_id(), "task_13");
+    }
+
+    #[test]
+    fn chunked_images_round_trip_next_to_similar_ids() {
+        let image = |len: usize, seed: u8| -> Vec<u8> {
+            (0..len)
+                .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+                .collect()
+        };
+        let first = image(2 * IMAGE_CHUNK_SIZE + 17, 1);
+        let second = image(IMAGE_CHUNK_SIZE + 1, 2);
+        let third = image(5, 3);
+
+        let first_ref = store_image("task_1", &first, "image/png");
+        let second_ref = store_image("task_10", &second, "image/png");
+        let third_ref = store_image("task_2", &third, "image/bmp");
+        assert_eq!(first_ref.chunk_count, 3);
+        assert_eq!(second_ref.chunk_count, 2);
+        assert_eq!(first_ref.sha256, sha256_hex(&first));
+
+        assert_eq!(load_image(&first_ref).unwrap(), first);
+        assert_eq!(load_image(&second_ref).unwrap(), second);
+
+        // Replacing and removing one image leaves its neighbours intact
+        let shorter = image(10, 4);
+        let replaced = store_image("task_1", &shorter, "image/png");
+        assert_eq!(load_image(&replaced).unwrap(), shorter);
+        remove_image("task_1");
+        assert!(load_image(&replaced).is_err());
+        assert_eq!(load_image(&second_ref).unwrap(), second);
+        assert_eq!(load_image(&third_ref).unwrap(), third);
+        let chunks = IMAGE_STORE.with(|store| store.borrow().len());
+        assert_eq!(chunks, 3);
+    }
+
+    #[test]
+    fn requests_are_validated_with_their_effective_values() {
+        let config = CanisterConfig::default();
+        let check = |request: GenerationRequest| validate_request(&request, &config);
+        assert!(check(GenerationRequest::default()).is_ok());
+
+        // Unset dimensions default to 512, above this limit
+        let small = CanisterConfig {
+            max_image_dimension: 256,
+            ..CanisterConfig::default()
+        };
+        assert!(validate_request(&GenerationRequest::default(), &small).is_err());
+        let sized = GenerationRequest {
+            width: Some(256),
+            height: Some(128),
+            ..GenerationRequest::default()
+        };
+        assert!(validate_request(&sized, &small).is_ok());
+        for width in [0, 260, 1032] {
+            let request = GenerationRequest {
+                width: Some(width),
+                ..GenerationRequest::default()
+            };
+            assert!(check(request).is_err(), "width {}", width);
+        }
+
+        for (steps, valid) in [(0, false), (1, true), (1000, true), (1001, false)] {
+            let request = GenerationRequest {
+                num_inference_steps: Some(steps),
+                ..GenerationRequest::default()
+            };
+            assert_eq!(check(request).is_ok(), valid, "{} steps", steps);
+        }
+
+        for (guidance_scale, valid) in [
+            (0.0, true),
+            (7.5, true),
+            (-1.0, false),
+            (f32::NAN, false),
+            (f32::INFINITY, false),
+        ] {
+            let request = GenerationRequest {
+                guidance_scale: Some(guidance_scale),
+                ..GenerationRequest::default()
+            };
+            assert_eq!(check(request).is_ok(), valid, "{}", guidance_scale);
+        }
+
+        let eta = GenerationRequest {
+            eta: Some(1.5),
+            ..GenerationRequest::default()
+        };
+        assert!(check(eta).is_err());
+        let tiles = GenerationRequest {
+            vae_tile_size: Some(32),
+            ..GenerationRequest::default()
+        };
+        assert!(check(tiles).is_err());
+    }
+
+    #[test]
+    fn strength_and_masks_need_an_init_image() {
+        let config = CanisterConfig::default();
+        let check = |request: GenerationRequest| validate_request(&request, &config);
+        let img2img = |strength: Option<f32>, steps: u32| GenerationRequest {
+            init_image: Some(InitImage::Upload("upload_0".to_string())),
+            strength,
+            num_inference_steps: Some(steps),
+            ..GenerationRequest::default()
+        };
+        assert!(check(img2img(None, 20)).is_ok());
+        assert!(check(img2img(Some(1.0), 20)).is_ok());
+        assert!(check(img2img(Some(0.0), 20)).is_err());
+        assert!(check(img2img(Some(1.2), 20)).is_err());
+        // 0.4 of 2 steps rounds down to no steps at all
+        assert!(check(img2img(Some(0.4), 2)).is_err());
+
+        let strength = GenerationRequest {
+            strength: Some(0.5),
+            ..GenerationRequest::default()
+        };
+        assert!(check(strength).is_err());
+        let mask = GenerationRequest {
+            mask: Some(InpaintMask::Rectangles(Vec::new())),
+            ..GenerationRequest::default()
+        };
+        assert!(check(mask).is_err());
+    }
+
+    #[test]
+    fn model_loads_wait_for_tasks_in_progress() {
+        store_task(queued_task("task_1"));
+        assert!(check_no_task_processing().is_ok());
+
+        let mut task = queued_task("task_2");
+        task.status = TaskStatus::Processing;
+        store_task(task);
+        let error = check_no_task_processing().unwrap_err();
+        assert!(error.contains("task_2"), "{}", error);
+    }
+
+    #[test]
+    fn prompts_are_encoded_across_ticks() {
+        let model = StableDiffusionModel::new();
+        let request = GenerationRequest {
+            prompt: "a lighthouse".to_string(),
+            width: Some(64),
+            height: Some(64),
+            seed: Some(1),
+            ..GenerationRequest::default()
+        };
+        let mut preparation = Preparation::default();
+        // Nothing more fits once a unit has run
+        let budget = || TickBudget::new(0, Box::new(|| 0));
+        let prepared = model.prepare(&request, &mut preparation, &mut budget());
+        assert!(prepared.unwrap().is_none());
+        assert_eq!(preparation.chunks.len(), 1);
+
+        // The negative prompt's chunk completes the preparation
+        let prepared = model.prepare(&request, &mut preparation, &mut budget());
+        let state = prepared.unwrap().unwrap();
+        // Each chunk is framed by start and end tokens
+        let tokens = CHUNK_LENGTH + 2;
+        assert_eq!(state.text_embeddings.shape(), [1, tokens, 768]);
+        assert_eq!(state.negative_embeddings.shape(), [1, tokens, 768]);
+        assert_ne!(state.text_embeddings, state.negative_embeddings);
+        assert_eq!(state.latents.shape(), [1, 4, 8, 8]);
+    }
+
+    #[test]
+    fn configured_prediction_type_reaches_the_scheduler() {
+        let model = StableDiffusionModel::new();
+        let request = GenerationRequest {
+            prompt: "a lighthouse".to_string(),
+            width: Some(64),
+            height: Some(64),
+            num_inference_steps: Some(2),
+            seed: Some(3),
+            scheduler: Some(SchedulerKind::Ddim),
+            ..GenerationRequest::default()
+        };
+        let generate = |prediction_type| {
+            let config = CanisterConfig {
+                prediction_type,
+                ..CanisterConfig::default()
+            };
+            CONFIG.with(|cell| cell.borrow_mut().set(config).unwrap());
+            let budget = &mut TickBudget::new(u64::MAX, Box::new(|| 0));
+            let mut preparation = Preparation::default();
+            let mut state = model
+                .prepare(&request, &mut preparation, budget)
+                .unwrap()
+                .unwrap();
+            model.run_steps(&mut state, budget).unwrap();
+            state.latents
+        };
+
+        let epsilon = generate(None);
+        assert_eq!(epsilon, generate(Some(PredictionType::Epsilon)));
+        assert_ne!(epsilon, generate(Some(PredictionType::VPrediction)));
+    }
+
+    #[test]
+    fn init_images_are_encoded_across_ticks() {
+        let mut image = RgbImage::new(128, 64);
+        for (i, value) in image.pixels.iter_mut().enumerate() {
+            *value = (i * 37 % 256) as u8;
+        }
+        let bytes = image_codec::encode(&image, OutputFormat::Png, &[]);
+        let uploader = Principal::anonymous();
+        let upload_id = store_upload(uploader, 0, &bytes, "image/png").unwrap();
+        let model = StableDiffusionModel::new();
+        let request = GenerationRequest {
+            prompt: "a lighthouse".to_string(),
+            width: Some(128),
+            height: Some(64),
+            seed: Some(5),
+            vae_tile_size: Some(64),
+            init_image: Some(InitImage::Upload(upload_id.clone())),
+            ..GenerationRequest::default()
+        };
+        let mut preparation = Preparation::default();
+        let budget = &mut TickBudget::new(u64::MAX, Box::new(|| 0));
+        let whole = model.prepare(&request, &mut preparation, budget);
+        let whole = whole.unwrap().unwrap();
+
+        // Both prompt chunks, then one init image tile per tick. The image is
+        // decoded once, so it can go away while its tiles are encoded.
+        let mut preparation = Preparation::default();
+        let mut ticks = 0;
+        let tiled = loop {
+            ticks += 1;
+            let budget = &mut TickBudget::new(0, Box::new(|| 0));
+            if let Some(state) = model.prepare(&request, &mut preparation, budget).unwrap() {
+                break state;
+            }
+            if preparation.init_encode.is_some() {
+                UPLOAD_STORE.with(|store| store.borrow_mut().remove(&upload_id));
+            }
+        };
+        let total_tiles = preparation
+            .init_encode
+            .as_ref()
+            .unwrap()
+            .tiles
+            .total_tiles();
+        assert!(total_tiles > 1);
+        assert_eq!(ticks, 2 + total_tiles);
+
+        // The same latents as preparing in a single tick
+        assert_eq!(tiled.latents.shape(), [1, 4, 8, 16]);
+        for (a, b) in tiled
+            .latents
+            .values()
+            .iter()
+            .zip(whole.latents.values().iter())
+        {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn uploads_beyond_the_caps_evict_the_callers_oldest_unused() {
+        let caller = |n: u8| Principal::from_slice(&[n]);
+        let upload =
+            |by: u8, bytes: u64, now: u64| store_upload(caller(by), now, &bytes.to_le_bytes(), "");
+        let stored = |id: &String| UPLOAD_STORE.with(|store| store.borrow().contains_key(id));
+        let count = || UPLOAD_STORE.with(|store| store.borrow().len()) as usize;
+        let per_caller = MAX_UPLOADS_PER_CALLER as u64;
+
+        let first: Vec<String> = (0..per_caller).map(|i| upload(0, i, i).unwrap()).collect();
+        let mut task = queued_task("task_1");
+        task.request.init_image = Some(InitImage::Upload(first[0].clone()));
+        store_task(task);
+
+        // The oldest upload is in use, so the caller's next one replaces the
+        // second oldest
+        let next = upload(0, 100, 100).unwrap();
+        assert!(stored(&first[0]) && !stored(&first[1]) && stored(&next));
+        // Uploading the same bytes again renews them
+        assert_eq!(upload(0, 2, 101).unwrap(), first[2]);
+        upload(0, 102, 102).unwrap();
+        assert!(stored(&first[2]) && !stored(&first[3]));
+
+        // The same bytes from another caller are an upload of their own
+        let copy = upload(1, 4, 200).unwrap();
+        assert_ne!(copy, first[4]);
+        for i in 1..per_caller {
+            upload(1, 1000 + i, 200 + i).unwrap();
+        }
+        for n in 2..(MAX_UPLOADS / MAX_UPLOADS_PER_CALLER) as u8 {
+            for i in 0..per_caller {
+                upload(n, n as u64 * 1000 + i, n as u64 * 100 + i).unwrap();
+            }
+        }
+        assert_eq!(count(), MAX_UPLOADS);
+
+        // Once storage is full, callers can only replace their own uploads
+        assert!(upload(99, 99, 1000).is_err());
+        upload(1, 5000, 1001).unwrap();
+        assert!(!stored(&copy) && stored(&first[4]));
+        assert_eq!(count(), MAX_UPLOADS);
+    }
+
+    #[test]
+    fn uploads_used_by_queued_tasks_are_kept() {
+        let caller = Principal::anonymous();
+        for now in 0..MAX_UPLOADS_PER_CALLER as u64 {
+            let upload_id = store_upload(caller, now, &now.to_le_bytes(), "").unwrap();
+            let mut task = queued_task(&format!("task_{}", now));
+            task.request.mask = Some(InpaintMask::Upload(upload_id));
+            store_task(task);
+        }
+        assert!(store_upload(caller, 100, b"another", "").is_err());
+
+        // Finished tasks no longer hold on to their uploads
+        let mut task = queued_task("task_0");
+        task.status = TaskStatus::Completed;
+        store_task(task);
+        assert!(store_upload(caller, 101, b"another", "").is_ok());
+    }
+
+    #[test]
+    fn tick_budgets_leave_room_for_the_largest_unit() {
+        let spent = Rc::new(Cell::new(100));
+        let counter = spent.clone();
+        let mut budget = TickBudget::new(50, Box::new(move || counter.get()));
+        for (instructions, fits) in [(110, true), (125, true), (130, true), (136, false)] {
+            spent.set(instructions);
+            assert_eq!(budget.another_fits(), fits, "at {}", instructions);
+        }
+    }
+
+    #[test]
+    fn image_metadata_reads_back_from_every_format() {
+        let mut request = GenerationRequest {
+            prompt: "a café at night, \"lanterns\"".to_string(),
+            negative_prompt: Some("blur".to_string()),
+            seed: Some(11),
+            ..GenerationRequest::default()
+        };
+        request.fill_defaults(&CanisterConfig::default());
+        let metadata = ImageMetadata {
+            task_id: "task_5".to_string(),
+            model_version: MODEL_VERSION.to_string(),
+            canister_id: "aaaaa-aa".to_string(),
+            request,
+        };
+        let image = image_codec::RgbImage::new(8, 8);
+
+        for format in [OutputFormat::Bmp, OutputFormat::Png, OutputFormat::Qoi] {
+            let encoded = image_codec::encode(&image, format, &metadata.to_text_entries());
+            let entries = image_codec::read_metadata(&encoded).unwrap();
+            let parameters = entries.iter().find(|(key, _)| key == "parameters");
+            assert_eq!(parameters.unwrap().1, metadata.summary());
+
+            let read = ImageMetadata::from_text_entries(&entries).unwrap();
+            assert_eq!(read.task_id, "task_5");
+            assert_eq!(read.canister_id, "aaaaa-aa");
+            assert_eq!(
+                serde_json::to_string(&read.request).unwrap(),
+                serde_json::to_string(&metadata.request).unwrap(),
+                "{:?}",
+                format
+            );
+        }
+        assert!(ImageMetadata::from_text_entries(&[]).is_err());
+    }
+
+    // Task records as unversioned releases wrote them
+    #[derive(CandidType)]
+    struct InlineImageRecord {
+        id: String,
+        status: TaskStatus,
+        created_at: u64,
+        completed_at: Option<u64>,
+        request: GenerationRequest,
+        result: Option<Vec<u8>>,
+        error: Option<String>,
+    }
+
+    fn raw_task_store() -> StableBTreeMap<String, Vec<u8>, Memory> {
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))))
+    }
+
+    #[test]
+    fn migration_moves_inline_images_and_counts_records() {
+        let v0 = |id: &str, result: Option<Vec<u8>>| {
+            Encode!(&InlineImageRecord {
+                id: id.to_string(),
+                status: TaskStatus::Completed,
+                created_at: 1,
+                completed_at: Some(2),
+                request: GenerationRequest::default(),
+                result,
+                error: None,
+            })
+            .unwrap()
+        };
+        let garbage = vec![0xde, 0xad, 0xbe, 0xef];
+        let mut raw = raw_task_store();
+        raw.insert("task_1".to_string(), v0("task_1", Some(vec![7; 40])));
+        raw.insert("task_2".to_string(), v0("task_2", None));
+        raw.insert("task_3".to_string(), garbage.clone());
+        TASK_STORE.with(|store| *store.borrow_mut() = IdStore::init(raw.into_memory()));
+        store_task(queued_task("task_4"));
+
+        let report = migrate_task_records();
+        assert_eq!(
+            (
+                report.scanned,
+                report.converted,
+                report.images_moved,
+                report.unreadable
+            ),
+            (4, 2, 1, 1)
+        );
+
+        let first = load_task("task_1").unwrap();
+        let image = first.image.unwrap();
+        assert_eq!(image.content_type, "image/bmp");
+        assert_eq!(load_image(&image).unwrap(), vec![7; 40]);
+        assert!(load_task("task_2").unwrap().image.is_none());
+
+        // Converted records are rewritten in the current layout and the
+        // unreadable one is left as it was
+        let raw = raw_task_store();
+        assert!(raw.get(&"task_1".to_string()).unwrap().starts_with(b"SDTK"));
+        assert_eq!(raw.get(&"task_3".to_string()).unwrap(), garbage);
+
+        let again = migrate_task_records();
+        assert_eq!(
+            (again.scanned, again.converted, again.unreadable),
+            (4, 0, 1)
+        );
+    }
 }
@@ -0,0 +1,490 @@
+// Uploaded model files
+//
+// A controller streams each file in with begin/append/finalize calls. Beginning
+// an upload reserves a contiguous extent of a dedicated stable-memory region
+// and chunks are written straight into place, so committed files never need
+// reassembling. The SHA-256 is accumulated chunk by chunk and checked on
+// finalize; only then does the upload replace the committed copy, which stays
+// usable until that point. An interrupted upload resumes by appending at the
+// `received` offset reported by `info`.
+//
+// Loaded model components read their weights from the region on demand, so
+// the extent a replaced file occupied is retired rather than freed: it stays
+// reserved until the component is reloaded and `release` is called.
+
+use crate::tensor_store::TensorStore;
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::{Memory, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use sha2::compress256;
+use sha2::digest::generic_array::GenericArray;
+use std::borrow::Cow;
+
+pub const TOKENIZER_VOCAB: &str = "tokenizer/vocab.json";
+pub const TOKENIZER_MERGES: &str = "tokenizer/merges.txt";
+pub const TEXT_ENCODER_WEIGHTS: &str = "text_encoder/model.safetensors";
+pub const UNET_WEIGHTS: &str = "unet/diffusion_pytorch_model.safetensors";
+pub const VAE_WEIGHTS: &str = "vae/diffusion_pytorch_model.safetensors";
+pub const TAESD_WEIGHTS: &str = "taesd/diffusion_pytorch_model.safetensors";
+
+// Files the pipeline knows how to use; uploads under other names are refused
+pub const MODEL_FILES: &[&str] = &[
+    TOKENIZER_VOCAB,
+    TOKENIZER_MERGES,
+    TEXT_ENCODER_WEIGHTS,
+    UNET_WEIGHTS,
+    VAE_WEIGHTS,
+    TAESD_WEIGHTS,
+];
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+const SHA256_BLOCK_SIZE: usize = 64;
+const SHA256_INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+// Byte range of the weight region
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Extent {
+    pub offset: u64,
+    pub size: u64,
+}
+
+impl Extent {
+    fn end(&self) -> u64 {
+        self.offset + self.size
+    }
+}
+
+// SHA-256 whose state can be checkpointed between messages
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct UploadHasher {
+    state: Vec<u32>,
+    // Bytes of an incomplete block carried over to the next chunk
+    pending: Vec<u8>,
+    length: u64,
+}
+
+impl UploadHasher {
+    pub fn new() -> Self {
+        Self {
+            state: SHA256_INITIAL_STATE.to_vec(),
+            pending: Vec::new(),
+            length: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+
+        if !self.pending.is_empty() {
+            let take = (SHA256_BLOCK_SIZE - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < SHA256_BLOCK_SIZE {
+                return;
+            }
+            let block = std::mem::take(&mut self.pending);
+            self.compress(&block);
+        }
+
+        let full = data.len() - data.len() % SHA256_BLOCK_SIZE;
+        self.compress(&data[..full]);
+        self.pending = data[full..].to_vec();
+    }
+
+    // Lowercase hex digest of everything hashed so far
+    pub fn finish(&self) -> String {
+        let mut hasher = self.clone();
+        let mut tail = std::mem::take(&mut hasher.pending);
+        tail.push(0x80);
+        while tail.len() % SHA256_BLOCK_SIZE != SHA256_BLOCK_SIZE - 8 {
+            tail.push(0);
+        }
+        tail.extend_from_slice(&(self.length * 8).to_be_bytes());
+        hasher.compress(&tail);
+
+        hasher
+            .state
+            .iter()
+            .map(|word| format!("{:08x}", word))
+            .collect()
+    }
+
+    fn compress(&mut self, blocks: &[u8]) {
+        let mut state: [u32; 8] = self.state[..].try_into().unwrap();
+        for block in blocks.chunks_exact(SHA256_BLOCK_SIZE) {
+            compress256(
+                &mut state,
+                std::slice::from_ref(GenericArray::from_slice(block)),
+            );
+        }
+        self.state = state.to_vec();
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CommittedFile {
+    pub extent: Extent,
+    pub sha256: String,
+    pub committed_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct PendingUpload {
+    pub extent: Extent,
+    pub received: u64,
+    pub hasher: UploadHasher,
+    pub started_at: u64,
+}
+
+// Index entry of one model file
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct ModelFileRecord {
+    pub committed: Option<CommittedFile>,
+    pub upload: Option<PendingUpload>,
+    // Extent of the copy the loaded component was built from, kept reserved
+    // after a newer copy is committed until the component is reloaded
+    pub retired: Option<Extent>,
+}
+
+impl Storable for ModelFileRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    // An index entry that no longer decodes is treated as a missing file,
+    // which can be uploaded again, rather than trapping every message that
+    // reads the index
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), ModelFileRecord).unwrap_or_else(|error| {
+            ic_cdk::println!("Stored model file record is unreadable: {}", error);
+            Self::default()
+        })
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct CommittedFileInfo {
+    pub size: u64,
+    pub sha256: String,
+    pub committed_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct UploadProgress {
+    pub size: u64,
+    pub received: u64,
+    pub started_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ModelFileInfo {
+    pub name: String,
+    pub committed: Option<CommittedFileInfo>,
+    pub upload: Option<UploadProgress>,
+}
+
+pub struct ModelStore<M: Memory> {
+    // Raw region holding file contents
+    region: M,
+    index: StableBTreeMap<String, ModelFileRecord, M>,
+}
+
+impl<M: Memory> ModelStore<M> {
+    pub fn init(region: M, index: M) -> Self {
+        Self {
+            region,
+            index: StableBTreeMap::init(index),
+        }
+    }
+
+    // Start (or restart) the upload of `name`, reserving `size` bytes
+    pub fn begin(&mut self, name: &str, size: u64, now: u64) -> Result<ModelFileInfo, String> {
+        if !MODEL_FILES.contains(&name) {
+            return Err(format!("Unknown model file {}", name));
+        }
+        if size == 0 {
+            return Err("Model file size must be positive".to_string());
+        }
+
+        let mut record = self.index.get(&name.to_string()).unwrap_or_default();
+        record.upload = None;
+        self.index.insert(name.to_string(), record.clone());
+
+        let extent = self.allocate(size);
+        let pages = extent.end().div_ceil(WASM_PAGE_SIZE);
+        let current = self.region.size();
+        if pages > current && self.region.grow(pages - current) < 0 {
+            return Err("Not enough stable memory for this model file".to_string());
+        }
+
+        record.upload = Some(PendingUpload {
+            extent,
+            received: 0,
+            hasher: UploadHasher::new(),
+            started_at: now,
+        });
+        self.index.insert(name.to_string(), record.clone());
+        Ok(file_info(name, &record))
+    }
+
+    // Write a chunk at `offset`, which must equal the bytes received so far.
+    // Returns the new received count.
+    pub fn append(&mut self, name: &str, offset: u64, data: &[u8]) -> Result<u64, String> {
+        let mut record = self.index.get(&name.to_string()).unwrap_or_default();
+        let upload = record
+            .upload
+            .as_mut()
+            .ok_or_else(|| format!("No upload of {} in progress", name))?;
+
+        if offset != upload.received {
+            return Err(format!(
+                "Expected offset {} for {}, got {}",
+                upload.received, name, offset
+            ));
+        }
+        if upload.received + data.len() as u64 > upload.extent.size {
+            return Err(format!(
+                "Chunk exceeds the declared size of {} bytes",
+                upload.extent.size
+            ));
+        }
+
+        self.region.write(upload.extent.offset + offset, data);
+        upload.hasher.update(data);
+        upload.received += data.len() as u64;
+        let received = upload.received;
+
+        self.index.insert(name.to_string(), record);
+        Ok(received)
+    }
+
+    // Verify the upload against `sha256` and commit it. A mismatched upload
+    // is discarded.
+    pub fn finalize(
+        &mut self,
+        name: &str,
+        sha256: &str,
+        now: u64,
+    ) -> Result<ModelFileInfo, String> {
+        let mut record = self.index.get(&name.to_string()).unwrap_or_default();
+        let upload = record
+            .upload
+            .take()
+            .ok_or_else(|| format!("No upload of {} in progress", name))?;
+
+        if upload.received != upload.extent.size {
+            return Err(format!(
+                "Upload of {} is incomplete: {} of {} bytes received",
+                name, upload.received, upload.extent.size
+            ));
+        }
+
+        let digest = upload.hasher.finish();
+        if !digest.eq_ignore_ascii_case(sha256.trim()) {
+            self.index.insert(name.to_string(), record);
+            return Err(format!(
+                "SHA-256 mismatch for {}: expected {}, uploaded data hashes to {}",
+                name, sha256, digest
+            ));
+        }
+
+        // Only the first replaced copy can be in use; later ones were never
+        // loaded and are freed
+        if record.retired.is_none() {
+            record.retired = record.committed.as_ref().map(|file| file.extent);
+        }
+        record.committed = Some(CommittedFile {
+            extent: upload.extent,
+            sha256: digest,
+            committed_at: now,
+        });
+        self.index.insert(name.to_string(), record.clone());
+        Ok(file_info(name, &record))
+    }
+
+    // Contents of a committed file
+    pub fn load(&self, name: &str) -> Option<Vec<u8>> {
+        let committed = self.index.get(&name.to_string())?.committed?;
+        let mut bytes = vec![0; committed.extent.size as usize];
+        self.region.read(committed.extent.offset, &mut bytes);
+        Some(bytes)
+    }
+
+    // Tensors of a committed safetensors file, read on demand
+    pub fn tensors(&self, name: &str) -> Option<Result<TensorStore<M>, String>>
+    where
+        M: Clone,
+    {
+        let committed = self.index.get(&name.to_string())?.committed?;
+        Some(TensorStore::open(self.region.clone(), committed.extent))
+    }
+
+    // Free the retired extent of `name` once nothing reads from it
+    pub fn release(&mut self, name: &str) {
+        let Some(mut record) = self.index.get(&name.to_string()) else {
+            return;
+        };
+        if record.retired.take().is_some() {
+            self.index.insert(name.to_string(), record);
+        }
+    }
+
+    // State of every known model file
+    pub fn info(&self) -> Vec<ModelFileInfo> {
+        MODEL_FILES
+            .iter()
+            .map(|name| {
+                let record = self.index.get(&name.to_string()).unwrap_or_default();
+                file_info(name, &record)
+            })
+            .collect()
+    }
+
+    // First gap between the extents in use that fits `size` bytes
+    fn allocate(&self, size: u64) -> Extent {
+        let mut in_use: Vec<Extent> = self
+            .index
+            .iter()
+            .flat_map(|(_, record)| {
+                let committed = record.committed.map(|file| file.extent);
+                let upload = record.upload.map(|upload| upload.extent);
+                committed.into_iter().chain(upload).chain(record.retired)
+            })
+            .collect();
+        in_use.sort_by_key(|extent| extent.offset);
+
+        let mut offset = 0;
+        for extent in in_use {
+            if extent.offset >= offset + size {
+                break;
+            }
+            offset = offset.max(extent.end());
+        }
+        Extent { offset, size }
+    }
+}
+
+fn file_info(name: &str, record: &ModelFileRecord) -> ModelFileInfo {
+    ModelFileInfo {
+        name: name.to_string(),
+        committed: record.committed.as_ref().map(|file| CommittedFileInfo {
+            size: file.extent.size,
+            sha256: file.sha256.clone(),
+            committed_at: file.committed_at,
+        }),
+        upload: record.upload.as_ref().map(|upload| UploadProgress {
+            size: upload.extent.size,
+            received: upload.received,
+            started_at: upload.started_at,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+    use sha2::{Digest, Sha256};
+
+    fn store() -> ModelStore<VectorMemory> {
+        ModelStore::init(VectorMemory::default(), VectorMemory::default())
+    }
+
+    fn sha256_hex(bytes: &[u8]) -> String {
+        Sha256::digest(bytes)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn upload(store: &mut ModelStore<VectorMemory>, name: &str, data: &[u8], chunk: usize) {
+        store.begin(name, data.len() as u64, 1).unwrap();
+        for (i, part) in data.chunks(chunk).enumerate() {
+            store.append(name, (i * chunk) as u64, part).unwrap();
+        }
+        store.finalize(name, &sha256_hex(data), 2).unwrap();
+    }
+
+    #[test]
+    fn hasher_matches_sha256_across_chunk_boundaries() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 % 251) as u8).collect();
+        for chunk in [1, 63, 64, 65, 1000] {
+            let mut hasher = UploadHasher::new();
+            for part in data.chunks(chunk) {
+                hasher.update(part);
+            }
+            assert_eq!(hasher.finish(), sha256_hex(&data));
+        }
+        assert_eq!(UploadHasher::new().finish(), sha256_hex(&[]));
+    }
+
+    #[test]
+    fn commits_verified_uploads_only() {
+        let mut store = store();
+        upload(&mut store, TOKENIZER_VOCAB, b"first version", 4);
+        assert_eq!(store.load(TOKENIZER_VOCAB).unwrap(), b"first version");
+
+        // Offsets must continue where the upload left off
+        store.begin(TOKENIZER_VOCAB, 6, 3).unwrap();
+        store.append(TOKENIZER_VOCAB, 0, b"sec").unwrap();
+        assert!(store.append(TOKENIZER_VOCAB, 0, b"sec").is_err());
+        assert!(
+            store
+                .finalize(TOKENIZER_VOCAB, &sha256_hex(b"second"), 4)
+                .is_err()
+        );
+        store.append(TOKENIZER_VOCAB, 3, b"ond").unwrap();
+        assert!(store.append(TOKENIZER_VOCAB, 6, b"!").is_err());
+
+        // A bad hash leaves the committed copy in place
+        assert!(
+            store
+                .finalize(TOKENIZER_VOCAB, &sha256_hex(b"other!"), 4)
+                .is_err()
+        );
+        assert_eq!(store.load(TOKENIZER_VOCAB).unwrap(), b"first version");
+        assert!(store.info()[0].upload.is_none());
+
+        assert!(store.begin("unet/model.safetensors", 4, 5).is_err());
+    }
+
+    #[test]
+    fn replaced_files_free_their_extent_once_released() {
+        let mut store = store();
+        upload(&mut store, UNET_WEIGHTS, &[1; 100], 30);
+        upload(&mut store, TOKENIZER_MERGES, &[2; 50], 30);
+        upload(&mut store, UNET_WEIGHTS, &[3; 80], 30);
+        upload(&mut store, UNET_WEIGHTS, &[4; 90], 30);
+        assert_eq!(store.load(UNET_WEIGHTS).unwrap(), [4; 90]);
+
+        // The loaded UNet may still read the first copy, which stays
+        // reserved; the second was never loaded and its gap is free
+        let record = store.index.get(&UNET_WEIGHTS.to_string()).unwrap();
+        assert_eq!(
+            record.retired,
+            Some(Extent {
+                offset: 0,
+                size: 100
+            })
+        );
+        assert_eq!(store.allocate(80).offset, 150);
+        assert_eq!(store.allocate(81).offset, 320);
+
+        // Once the UNet is reloaded the first copy is free as well
+        store.release(UNET_WEIGHTS);
+        assert_eq!(store.allocate(100).offset, 0);
+        assert_eq!(store.load(TOKENIZER_MERGES).unwrap(), [2; 50]);
+    }
+
+    #[test]
+    fn unreadable_records_read_as_missing_files() {
+        let record = ModelFileRecord::from_bytes(Cow::Borrowed(&[1, 2, 3]));
+        assert!(record.committed.is_none() && record.upload.is_none());
+    }
+}
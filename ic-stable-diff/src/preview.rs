@@ -0,0 +1,277 @@
+// Approximate decoders for progress previews
+//
+// Running the full VAE after every tick would cost as much as the denoising
+// itself, so previews use one of two cheap stand-ins. The linear decoder
+// projects each latent pixel to a colour with a fixed 4x3 matrix fitted to
+// the SD 1.x VAE, giving an image at latent resolution for free. TAESD, a
+// 1.2M-parameter distilled decoder uploaded in the diffusers AutoencoderTiny
+// layout, renders full-resolution previews close to the real decode.
+
+use crate::tensor::Tensor;
+use crate::tensor_store::{TensorStore, Weights};
+use ic_stable_structures::Memory;
+
+// Latent channel to RGB contributions for SD 1.x latents, in [-1, 1]
+const LINEAR_RGB_FACTORS: [[f32; 3]; 4] = [
+    [0.3512, 0.2297, 0.3227],
+    [0.3250, 0.4974, 0.2350],
+    [-0.2829, 0.1762, 0.2721],
+    [-0.2120, -0.2616, -0.7177],
+];
+
+#[derive(Clone)]
+pub enum PreviewDecoder {
+    Linear,
+    Taesd(Box<TaesdDecoder<crate::Memory>>),
+}
+
+impl PreviewDecoder {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PreviewDecoder::Linear => "linear",
+            PreviewDecoder::Taesd(_) => "taesd",
+        }
+    }
+
+    // Approximate `[1, 3, h', w']` pixels in [-1, 1] for `[1, 4, h, w]`
+    // latents, where h' is h for the linear decoder and 8h for TAESD
+    pub fn forward(&self, latents: &Tensor) -> Result<Tensor, String> {
+        match self {
+            PreviewDecoder::Linear => linear_preview(latents),
+            PreviewDecoder::Taesd(decoder) => decoder.forward(latents),
+        }
+    }
+}
+
+fn linear_preview(latents: &Tensor) -> Result<Tensor, String> {
+    let [1, 4, height, width] = latents.shape()[..] else {
+        return Err(format!(
+            "Linear previews need latents of shape [1, 4, h, w], got {:?}",
+            latents.shape()
+        ));
+    };
+    // [h * w, 4] x [4, 3]
+    let factors = Tensor::new(&[4, 3], LINEAR_RGB_FACTORS.concat());
+    let rgb = latents
+        .clone()
+        .reshape(&[4, height * width])
+        .transpose(0, 1)
+        .matmul(&factors);
+    Ok(rgb.transpose(0, 1).reshape(&[1, 3, height, width]))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaesdConfig {
+    pub latent_channels: usize,
+    pub channels: usize,
+    // Blocks per stage; every stage but the last ends by doubling the size
+    pub num_blocks: Vec<usize>,
+}
+
+// One entry of the decoder's `layers` sequence
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TaesdLayer {
+    Conv {
+        input: usize,
+        output: usize,
+        bias: bool,
+    },
+    Block,
+    Relu,
+    Upsample,
+}
+
+impl TaesdConfig {
+    pub fn sd() -> Self {
+        Self {
+            latent_channels: 4,
+            channels: 64,
+            num_blocks: vec![3, 3, 3, 1],
+        }
+    }
+
+    // TAESD only varies in its latent channels and width
+    pub fn infer<M: Memory>(tensors: &TensorStore<M>) -> Result<Self, String> {
+        let conv_in = tensors.view("decoder.layers.0.weight")?.shape;
+        let [channels, latent_channels, 3, 3] = conv_in[..] else {
+            return Err(format!("TAESD input conv has shape {:?}", conv_in));
+        };
+        Ok(Self {
+            latent_channels,
+            channels,
+            ..Self::sd()
+        })
+    }
+
+    fn layers(&self) -> Vec<TaesdLayer> {
+        let c = self.channels;
+        let mut layers = vec![
+            TaesdLayer::Conv {
+                input: self.latent_channels,
+                output: c,
+                bias: true,
+            },
+            TaesdLayer::Relu,
+        ];
+        for (i, &blocks) in self.num_blocks.iter().enumerate() {
+            let last = i + 1 == self.num_blocks.len();
+            layers.extend(std::iter::repeat_n(TaesdLayer::Block, blocks));
+            if !last {
+                layers.push(TaesdLayer::Upsample);
+            }
+            layers.push(TaesdLayer::Conv {
+                input: c,
+                output: if last { 3 } else { c },
+                bias: last,
+            });
+        }
+        layers
+    }
+
+    pub fn tensor_shapes(&self) -> Vec<(String, Vec<usize>)> {
+        let c = self.channels;
+        let mut shapes = Vec::new();
+        for (i, layer) in self.layers().into_iter().enumerate() {
+            let prefix = format!("decoder.layers.{}", i);
+            match layer {
+                TaesdLayer::Conv {
+                    input,
+                    output,
+                    bias,
+                } => {
+                    shapes.push((format!("{}.weight", prefix), vec![output, input, 3, 3]));
+                    if bias {
+                        shapes.push((format!("{}.bias", prefix), vec![output]));
+                    }
+                }
+                TaesdLayer::Block => {
+                    for conv in [0, 2, 4] {
+                        shapes.push((format!("{}.conv.{}.weight", prefix, conv), vec![c, c, 3, 3]));
+                        shapes.push((format!("{}.conv.{}.bias", prefix, conv), vec![c]));
+                    }
+                }
+                TaesdLayer::Relu | TaesdLayer::Upsample => {}
+            }
+        }
+        shapes
+    }
+}
+
+#[derive(Clone)]
+pub struct TaesdDecoder<M: Memory> {
+    config: TaesdConfig,
+    tensors: TensorStore<M>,
+}
+
+impl<M: Memory> TaesdDecoder<M> {
+    pub fn from_tensors(tensors: TensorStore<M>) -> Result<Self, String> {
+        let config = TaesdConfig::infer(&tensors)?;
+        for (name, shape) in config.tensor_shapes() {
+            tensors.view_with_shape(&name, &shape)?;
+        }
+        Ok(Self { config, tensors })
+    }
+
+    pub fn config(&self) -> &TaesdConfig {
+        &self.config
+    }
+
+    pub fn forward(&self, latents: &Tensor) -> Result<Tensor, String> {
+        match latents.shape()[..] {
+            [1, channels, _, _] if channels == self.config.latent_channels => {}
+            _ => {
+                return Err(format!(
+                    "Expected latents of shape [1, {}, h, w], got {:?}",
+                    self.config.latent_channels,
+                    latents.shape()
+                ));
+            }
+        }
+
+        let mut weights = Weights::new(&self.tensors);
+        let w = &mut weights;
+        // Soft clamp to [-3, 3]
+        let mut x = latents.map(|v| (v / 3.0).tanh() * 3.0);
+        for (i, layer) in self.config.layers().into_iter().enumerate() {
+            let prefix = format!("decoder.layers.{}", i);
+            x = match layer {
+                TaesdLayer::Conv { bias: true, .. } => w.conv(&prefix, &x, 1)?,
+                TaesdLayer::Conv { bias: false, .. } => w.conv_padded(&prefix, &x, 1, 1, false)?,
+                TaesdLayer::Block => {
+                    let mut h = x.clone();
+                    for conv in [0, 2, 4] {
+                        if conv > 0 {
+                            h = relu(&h);
+                        }
+                        h = w.conv(&format!("{}.conv.{}", prefix, conv), &h, 1)?;
+                    }
+                    relu(&h.add(&x))
+                }
+                TaesdLayer::Relu => relu(&x),
+                TaesdLayer::Upsample => x.upsample_nearest2d(2),
+            };
+        }
+        // TAESD outputs [0, 1]
+        Ok(x.map(|v| v * 2.0 - 1.0))
+    }
+}
+
+fn relu(x: &Tensor) -> Tensor {
+    x.map(|v| v.max(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::TorchGenerator;
+    use crate::{safetensors, tensor_store};
+
+    #[test]
+    fn linear_preview_projects_each_latent_pixel() {
+        let mut latents = vec![0.0; 4 * 2 * 3];
+        // Second latent channel at pixel (1, 2)
+        latents[6 + 5] = 1.0;
+        let rgb = linear_preview(&Tensor::new(&[1, 4, 2, 3], latents)).unwrap();
+        assert_eq!(rgb.shape(), [1, 3, 2, 3]);
+        let values = rgb.values();
+        for (c, &factor) in LINEAR_RGB_FACTORS[1].iter().enumerate() {
+            assert!((values[c * 6 + 5] - factor).abs() < 1e-6);
+            assert_eq!(values[c * 6], 0.0);
+        }
+        assert!(linear_preview(&Tensor::zeros(&[1, 3, 2, 2])).is_err());
+    }
+
+    #[test]
+    fn taesd_layout_matches_the_reference_decoder() {
+        let config = TaesdConfig::sd();
+        let layers = config.layers();
+        assert_eq!(layers.len(), 19);
+        assert_eq!(layers[5], TaesdLayer::Upsample);
+        let parameters: usize = config
+            .tensor_shapes()
+            .iter()
+            .map(|(_, shape)| shape.iter().product::<usize>())
+            .sum();
+        assert_eq!(parameters, 1_222_531);
+
+        let small = TaesdConfig {
+            channels: 8,
+            ..TaesdConfig::sd()
+        };
+        let mut generator = TorchGenerator::new(4);
+        let tensors: Vec<_> = small
+            .tensor_shapes()
+            .into_iter()
+            .map(|(name, shape)| {
+                let values = generator.randn(shape.iter().product());
+                (name, shape, values.iter().map(|v| v * 0.1).collect())
+            })
+            .collect();
+        let store = tensor_store::from_bytes(&safetensors::serialize(&tensors));
+        let decoder = TaesdDecoder::from_tensors(store).unwrap();
+        assert_eq!(decoder.config(), &small);
+
+        let pixels = decoder.forward(&Tensor::zeros(&[1, 4, 2, 3])).unwrap();
+        assert_eq!(pixels.shape(), [1, 3, 16, 24]);
+    }
+}
@@ -0,0 +1,222 @@
+// Prompt emphasis syntax
+//
+// Follows the attention syntax of the AUTOMATIC1111 web UI:
+//   (text)        weight x 1.1
+//   [text]        weight / 1.1
+//   (text:1.5)    weight x 1.5
+//   \( \) \[ \]   literal brackets
+//   \\            literal backslash; any other backslash is kept as is
+// Brackets nest multiplicatively and unclosed brackets apply to the rest of
+// the prompt.
+
+const ROUND_BRACKET_MULTIPLIER: f32 = 1.1;
+const SQUARE_BRACKET_MULTIPLIER: f32 = 1.0 / 1.1;
+
+// Split a prompt into text fragments with their emphasis weights. Adjacent
+// fragments of equal weight are merged.
+pub fn parse_prompt_attention(text: &str) -> Vec<(String, f32)> {
+    let mut fragments: Vec<(String, f32)> = Vec::new();
+    let mut round_brackets = Vec::new();
+    let mut square_brackets = Vec::new();
+
+    let multiply_range = |fragments: &mut Vec<(String, f32)>, start: usize, multiplier: f32| {
+        for fragment in &mut fragments[start..] {
+            fragment.1 *= multiplier;
+        }
+    };
+
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let len = match c {
+            '\\' => {
+                // An escaped bracket or backslash is literal text, and so is
+                // any other backslash
+                match rest[1..].chars().next().filter(|c| "()[]\\".contains(*c)) {
+                    Some(escaped) => {
+                        fragments.push((escaped.to_string(), 1.0));
+                        2
+                    }
+                    None => {
+                        fragments.push(("\\".to_string(), 1.0));
+                        1
+                    }
+                }
+            }
+            '(' => {
+                round_brackets.push(fragments.len());
+                1
+            }
+            '[' => {
+                square_brackets.push(fragments.len());
+                1
+            }
+            ')' | ']' => {
+                let (brackets, multiplier) = if c == ')' {
+                    (&mut round_brackets, ROUND_BRACKET_MULTIPLIER)
+                } else {
+                    (&mut square_brackets, SQUARE_BRACKET_MULTIPLIER)
+                };
+                match brackets.pop() {
+                    Some(start) => multiply_range(&mut fragments, start, multiplier),
+                    None => fragments.push((c.to_string(), 1.0)),
+                }
+                1
+            }
+            ':' => match explicit_weight(rest) {
+                Some((weight, len)) if !round_brackets.is_empty() => {
+                    let start = round_brackets.pop().unwrap_or_default();
+                    multiply_range(&mut fragments, start, weight);
+                    len
+                }
+                Some((_, len)) => {
+                    fragments.push((rest[..len].to_string(), 1.0));
+                    len
+                }
+                None => {
+                    fragments.push((":".to_string(), 1.0));
+                    1
+                }
+            },
+            _ => {
+                let len = rest.find(|c| "\\()[]:".contains(c)).unwrap_or(rest.len());
+                fragments.push((rest[..len].to_string(), 1.0));
+                len
+            }
+        };
+        rest = &rest[len..];
+    }
+
+    for start in round_brackets {
+        multiply_range(&mut fragments, start, ROUND_BRACKET_MULTIPLIER);
+    }
+    for start in square_brackets {
+        multiply_range(&mut fragments, start, SQUARE_BRACKET_MULTIPLIER);
+    }
+
+    let mut merged: Vec<(String, f32)> = Vec::new();
+    for (text, weight) in fragments {
+        match merged.last_mut() {
+            Some(last) if last.1 == weight => last.0.push_str(&text),
+            _ => merged.push((text, weight)),
+        }
+    }
+    if merged.is_empty() {
+        merged.push((String::new(), 1.0));
+    }
+    merged
+}
+
+// Match `:<number>)` with optional whitespace, returning the weight and the
+// length of the match
+fn explicit_weight(text: &str) -> Option<(f32, usize)> {
+    let after_colon = text.strip_prefix(':')?;
+    let number_start = after_colon.trim_start();
+    let number_len = number_start
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '+' || c == '-'))
+        .unwrap_or(number_start.len());
+    let weight: f32 = number_start[..number_len].parse().ok()?;
+    let after_number = number_start[number_len..].trim_start();
+    after_number.strip_prefix(')')?;
+
+    let len = text.len() - after_number.len() + 1;
+    Some((weight, len))
+}
+
+// Scale each token's embedding by its weight, then rescale everything so the
+// mean of the embeddings is unchanged, as the A1111 web UI does
+pub fn apply_token_weights(embeddings: &mut [f32], weights: &[f32]) {
+    if weights.iter().all(|&weight| weight == 1.0) || weights.is_empty() {
+        return;
+    }
+    let dim = embeddings.len() / weights.len();
+
+    let original_mean = mean(embeddings);
+    for (token, &weight) in embeddings.chunks_mut(dim).zip(weights) {
+        for value in token {
+            *value *= weight;
+        }
+    }
+    let new_mean = mean(embeddings);
+
+    if new_mean != 0.0 {
+        let correction = original_mean / new_mean;
+        for value in embeddings.iter_mut() {
+            *value *= correction;
+        }
+    }
+}
+
+fn mean(values: &[f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    (values.iter().map(|&v| v as f64).sum::<f64>() / values.len() as f64) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_fragments(prompt: &str, expected: &[(&str, f32)]) {
+        let fragments = parse_prompt_attention(prompt);
+        assert_eq!(fragments.len(), expected.len(), "{:?}", fragments);
+        for ((text, weight), (expected_text, expected_weight)) in fragments.iter().zip(expected) {
+            assert_eq!(text, expected_text);
+            assert!(
+                (weight - expected_weight).abs() < 1e-5,
+                "{:?} != {:?}",
+                fragments,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn parses_emphasis_like_a1111() {
+        assert_fragments("normal text", &[("normal text", 1.0)]);
+        assert_fragments(
+            "an (important) word",
+            &[("an ", 1.0), ("important", 1.1), (" word", 1.0)],
+        );
+        assert_fragments("(unbalanced", &[("unbalanced", 1.1)]);
+        assert_fragments("\\(literal\\]", &[("(literal]", 1.0)]);
+        assert_fragments("a\\\\b", &[("a\\b", 1.0)]);
+        assert_fragments("(unnecessary)(parens)", &[("unnecessaryparens", 1.1)]);
+        assert_fragments(
+            "a (((house:1.3)) [on] a (hill:0.5), sun, (((sky))).",
+            &[
+                ("a ", 1.0),
+                ("house", 1.573),
+                (" ", 1.1),
+                ("on", 1.0),
+                (" a ", 1.1),
+                ("hill", 0.55),
+                (", sun, ", 1.1),
+                ("sky", 1.4641),
+                (".", 1.1),
+            ],
+        );
+    }
+
+    #[test]
+    fn stray_backslashes_are_kept() {
+        assert_fragments("C:\\Users (x)", &[("C:\\Users ", 1.0), ("x", 1.1)]);
+        assert_fragments("(ends with\\", &[("ends with\\", 1.1)]);
+        assert_fragments("\\n", &[("\\n", 1.0)]);
+    }
+
+    #[test]
+    fn colon_without_weight_is_text() {
+        assert_fragments("ratio 16:9", &[("ratio 16:9", 1.0)]);
+        assert_fragments("a:1.2) b", &[("a:1.2) b", 1.0)]);
+        assert_fragments("[blurry: 2 ]", &[("blurry: 2 ", 1.0 / 1.1)]);
+    }
+
+    #[test]
+    fn token_weights_preserve_mean() {
+        let mut embeddings = vec![1.0, 2.0, 3.0, 4.0];
+        apply_token_weights(&mut embeddings, &[2.0, 1.0]);
+        assert!((mean(&embeddings) - 2.5).abs() < 1e-6);
+        assert!((embeddings[0] / embeddings[2] - 2.0 / 3.0).abs() < 1e-6);
+    }
+}
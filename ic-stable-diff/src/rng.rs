@@ -0,0 +1,200 @@
+// Seeded random numbers compatible with PyTorch's CPU generator
+//
+// `torch.manual_seed(seed)` seeds an MT19937 engine and `torch.randn` turns
+// its output into normal samples with Box-Muller. Reproducing both exactly
+// means a seed shared with diffusers or other PyTorch-based tools yields the
+// same initial latents here.
+//
+// The generator state is Candid-serializable so it can be checkpointed with
+// the pipeline and keep producing the same stream across worker ticks.
+
+use candid::CandidType;
+use serde::Deserialize;
+
+const STATE_SIZE: usize = 624;
+const SHIFT_SIZE: usize = 397;
+const MATRIX_A: u32 = 0x9908_b0df;
+const UPPER_MASK: u32 = 0x8000_0000;
+const LOWER_MASK: u32 = 0x7fff_ffff;
+
+// torch.randn fills tensors of at least this many floats 16 values at a time
+const NORMAL_FILL_BLOCK: usize = 16;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TorchGenerator {
+    state: Vec<u32>,
+    index: u32,
+    // Second Box-Muller sample cached by the scalar (double) normal path
+    next_double_normal: Option<f64>,
+}
+
+impl TorchGenerator {
+    pub fn new(seed: u64) -> Self {
+        // PyTorch seeds the engine with the low 32 bits of the seed
+        let mut state = vec![0u32; STATE_SIZE];
+        state[0] = seed as u32;
+        for i in 1..STATE_SIZE {
+            let previous = state[i - 1];
+            state[i] = 1_812_433_253u32
+                .wrapping_mul(previous ^ (previous >> 30))
+                .wrapping_add(i as u32);
+        }
+
+        Self {
+            state,
+            index: STATE_SIZE as u32,
+            next_double_normal: None,
+        }
+    }
+
+    fn twist(&mut self) {
+        for i in 0..STATE_SIZE {
+            let y = (self.state[i] & UPPER_MASK) | (self.state[(i + 1) % STATE_SIZE] & LOWER_MASK);
+            let mut next = self.state[(i + SHIFT_SIZE) % STATE_SIZE] ^ (y >> 1);
+            if y & 1 != 0 {
+                next ^= MATRIX_A;
+            }
+            self.state[i] = next;
+        }
+        self.index = 0;
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        if self.index as usize >= STATE_SIZE {
+            self.twist();
+        }
+
+        let mut y = self.state[self.index as usize];
+        self.index += 1;
+
+        y ^= y >> 11;
+        y ^= (y << 7) & 0x9d2c_5680;
+        y ^= (y << 15) & 0xefc6_0000;
+        y ^ (y >> 18)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let high = self.next_u32() as u64;
+        let low = self.next_u32() as u64;
+        (high << 32) | low
+    }
+
+    // Uniform float in [0, 1) from 24 random bits, as torch.rand does
+    pub fn uniform_f32(&mut self) -> f32 {
+        (self.next_u32() & 0x00ff_ffff) as f32 / (1u32 << 24) as f32
+    }
+
+    // Uniform double in [0, 1) from 53 random bits
+    fn uniform_f64(&mut self) -> f64 {
+        (self.next_u64() & ((1u64 << 53) - 1)) as f64 / (1u64 << 53) as f64
+    }
+
+    // Standard normal sample computed in double precision, caching the
+    // second value of each Box-Muller pair
+    fn normal_f64(&mut self) -> f64 {
+        if let Some(sample) = self.next_double_normal.take() {
+            return sample;
+        }
+
+        let u1 = self.uniform_f64();
+        let u2 = self.uniform_f64();
+        let radius = (-2.0 * (-u2).ln_1p()).sqrt();
+        let theta = 2.0 * std::f64::consts::PI * u1;
+        self.next_double_normal = Some(radius * theta.sin());
+        radius * theta.cos()
+    }
+
+    // Equivalent of `torch.randn(size, generator=g)` for a float32 tensor
+    pub fn randn(&mut self, size: usize) -> Vec<f32> {
+        if size < NORMAL_FILL_BLOCK {
+            return (0..size).map(|_| self.normal_f64() as f32).collect();
+        }
+
+        let mut data: Vec<f32> = (0..size).map(|_| self.uniform_f32()).collect();
+        for block in data.chunks_exact_mut(NORMAL_FILL_BLOCK) {
+            normal_fill_16(block);
+        }
+
+        // A partial last block is redrawn over the final 16 values
+        if !size.is_multiple_of(NORMAL_FILL_BLOCK) {
+            let tail = &mut data[size - NORMAL_FILL_BLOCK..];
+            for value in tail.iter_mut() {
+                *value = self.uniform_f32();
+            }
+            normal_fill_16(tail);
+        }
+
+        data
+    }
+}
+
+// Box-Muller over 16 uniforms: the first half provides the radii and the
+// second half the angles
+fn normal_fill_16(data: &mut [f32]) {
+    let half = NORMAL_FILL_BLOCK / 2;
+    for j in 0..half {
+        let u1 = 1.0 - data[j];
+        let u2 = data[j + half];
+        let radius = (-2.0 * u1.ln()).sqrt();
+        let theta = 2.0 * std::f32::consts::PI * u2;
+        data[j] = radius * theta.cos();
+        data[j + half] = radius * theta.sin();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_all_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!(
+                (a - e).abs() < 1e-4,
+                "expected {:?}, got {:?}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn matches_mt19937_reference_output() {
+        // First outputs of the reference MT19937 seeded with 5489
+        let mut generator = TorchGenerator::new(5489);
+        assert_eq!(generator.next_u32(), 3_499_211_612);
+        assert_eq!(generator.next_u32(), 581_869_302);
+        assert_eq!(generator.next_u32(), 3_890_346_734);
+    }
+
+    #[test]
+    fn rand_matches_torch() {
+        // torch.manual_seed(0); torch.rand(2)
+        let mut generator = TorchGenerator::new(0);
+        let values = [generator.uniform_f32(), generator.uniform_f32()];
+        assert_all_close(&values, &[0.4963, 0.7682]);
+    }
+
+    #[test]
+    fn randn_small_matches_torch() {
+        // torch.manual_seed(0); torch.randn(5)
+        let mut generator = TorchGenerator::new(0);
+        assert_all_close(
+            &generator.randn(5),
+            &[1.5410, -0.2934, -2.1788, 0.5684, -1.0845],
+        );
+    }
+
+    #[test]
+    fn randn_block_matches_torch() {
+        // torch.manual_seed(0); torch.randn(4, 4)
+        let mut generator = TorchGenerator::new(0);
+        assert_all_close(
+            &generator.randn(16),
+            &[
+                -1.1258, -1.1524, -0.2506, -0.4339, 0.8487, 0.6920, -0.3160, -2.1152, 0.3223,
+                -1.2633, 0.3500, 0.3081, 0.1198, 1.2377, 1.1168, -0.2473,
+            ],
+        );
+    }
+}
@@ -0,0 +1,263 @@
+// Safetensors file format
+//
+// A file starts with the little-endian u64 length of a JSON header mapping
+// tensor names to their dtype, shape and byte range within the data section
+// that follows the header. An optional `__metadata__` entry holds free-form
+// string pairs.
+
+use std::collections::{BTreeMap, HashMap};
+
+const METADATA_KEY: &str = "__metadata__";
+
+// Headers larger than this are rejected rather than parsed
+const MAX_HEADER_SIZE: u64 = 100 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dtype {
+    F32,
+    F16,
+    BF16,
+}
+
+impl Dtype {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "F32" => Ok(Dtype::F32),
+            "F16" => Ok(Dtype::F16),
+            "BF16" => Ok(Dtype::BF16),
+            other => Err(format!("Unsupported tensor dtype {}", other)),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            Dtype::F32 => 4,
+            Dtype::F16 | Dtype::BF16 => 2,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TensorInfo {
+    pub dtype: Dtype,
+    pub shape: Vec<usize>,
+    // Byte range relative to the start of the data section
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct SafetensorsHeader {
+    pub tensors: BTreeMap<String, TensorInfo>,
+    pub metadata: HashMap<String, String>,
+    // Offset of the data section from the start of the file
+    pub data_start: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct RawTensorInfo {
+    dtype: String,
+    shape: Vec<usize>,
+    data_offsets: (u64, u64),
+}
+
+impl SafetensorsHeader {
+    // Length of the JSON header announced by the first 8 bytes of a file
+    pub fn header_len(prefix: &[u8]) -> Result<u64, String> {
+        let len_bytes: [u8; 8] = prefix
+            .get(..8)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| "Safetensors file is truncated".to_string())?;
+        let len = u64::from_le_bytes(len_bytes);
+        if len > MAX_HEADER_SIZE {
+            return Err(format!("Safetensors header of {} bytes is too large", len));
+        }
+        Ok(len)
+    }
+
+    // Parse the header from the start of a file. `prefix` must contain at
+    // least the first 8 + header_len bytes; `file_size` is used to check
+    // that every tensor lies within the file.
+    pub fn parse(prefix: &[u8], file_size: u64) -> Result<Self, String> {
+        let header_len = Self::header_len(prefix)?;
+        let json = prefix
+            .get(8..8 + header_len as usize)
+            .ok_or_else(|| "Safetensors header is truncated".to_string())?;
+        let entries: HashMap<String, serde_json::Value> = serde_json::from_slice(json)
+            .map_err(|e| format!("Invalid safetensors header: {}", e))?;
+
+        let data_start = 8 + header_len;
+        let data_size = file_size
+            .checked_sub(data_start)
+            .ok_or_else(|| "Safetensors file is truncated".to_string())?;
+
+        let mut tensors = BTreeMap::new();
+        let mut metadata = HashMap::new();
+        for (name, value) in entries {
+            if name == METADATA_KEY {
+                metadata = serde_json::from_value(value)
+                    .map_err(|e| format!("Invalid safetensors metadata: {}", e))?;
+                continue;
+            }
+
+            let raw: RawTensorInfo = serde_json::from_value(value)
+                .map_err(|e| format!("Invalid entry for tensor {}: {}", name, e))?;
+            let info = TensorInfo {
+                dtype: Dtype::parse(&raw.dtype)?,
+                shape: raw.shape,
+                start: raw.data_offsets.0,
+                end: raw.data_offsets.1,
+            };
+
+            // A crafted shape must not wrap around to a size that fits,
+            // which is easy with 32-bit usize on wasm32
+            let expected =
+                info.shape
+                    .iter()
+                    .try_fold(info.dtype.size(), |size, &dim| size.checked_mul(dim))
+                    .ok_or_else(|| format!("Tensor {} is too large", name))? as u64;
+            if info.end < info.start || info.end - info.start != expected || info.end > data_size {
+                return Err(format!("Tensor {} has an invalid byte range", name));
+            }
+            tensors.insert(name, info);
+        }
+
+        Ok(Self {
+            tensors,
+            metadata,
+            data_start,
+        })
+    }
+
+    pub fn tensor(&self, name: &str) -> Result<&TensorInfo, String> {
+        self.tensors
+            .get(name)
+            .ok_or_else(|| format!("Missing tensor {}", name))
+    }
+}
+
+// Convert raw little-endian tensor bytes to f32 values, replacing the
+// contents of `values` so its capacity can be reused
+pub fn convert_into(dtype: Dtype, bytes: &[u8], values: &mut Vec<f32>) {
+    values.clear();
+    match dtype {
+        Dtype::F32 => values.extend(
+            bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+        ),
+        Dtype::F16 => values.extend(
+            bytes
+                .chunks_exact(2)
+                .map(|b| f16_to_f32(u16::from_le_bytes([b[0], b[1]]))),
+        ),
+        Dtype::BF16 => values.extend(
+            bytes
+                .chunks_exact(2)
+                .map(|b| f32::from_bits((u16::from_le_bytes([b[0], b[1]]) as u32) << 16)),
+        ),
+    }
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = ((bits >> 15) as u32) << 31;
+    let exponent = ((bits >> 10) & 0x1f) as u32;
+    let mantissa = (bits & 0x3ff) as u32;
+
+    let bits = match (exponent, mantissa) {
+        (0, 0) => sign,
+        // Subnormal: renormalize into an f32 exponent
+        (0, _) => {
+            let shift = mantissa.leading_zeros() - 21;
+            let mantissa = (mantissa << shift) & 0x3ff;
+            sign | ((113 - shift) << 23) | (mantissa << 13)
+        }
+        (0x1f, _) => sign | 0x7f80_0000 | (mantissa << 13),
+        _ => sign | ((exponent + 112) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
+
+// Serialize f32 tensors into a safetensors file
+#[cfg(test)]
+pub fn serialize(tensors: &[(String, Vec<usize>, Vec<f32>)]) -> Vec<u8> {
+    let mut header = serde_json::Map::new();
+    let mut data = Vec::new();
+    for (name, shape, values) in tensors {
+        let start = data.len();
+        data.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+        header.insert(
+            name.clone(),
+            serde_json::json!({ "dtype": "F32", "shape": shape, "data_offsets": [start, data.len()] }),
+        );
+    }
+
+    let header = serde_json::to_vec(&header).unwrap();
+    let mut bytes = (header.len() as u64).to_le_bytes().to_vec();
+    bytes.extend(header);
+    bytes.extend(data);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_f32(dtype: Dtype, bytes: &[u8]) -> Vec<f32> {
+        let mut values = Vec::new();
+        convert_into(dtype, bytes, &mut values);
+        values
+    }
+
+    #[test]
+    fn parses_header_and_values() {
+        let bytes = serialize(&[("a".to_string(), vec![2, 2], vec![1.0, -2.0, 3.5, 0.25])]);
+        let header = SafetensorsHeader::parse(&bytes, bytes.len() as u64).unwrap();
+        let info = header.tensor("a").unwrap();
+        assert_eq!(info.shape, [2, 2]);
+
+        let start = (header.data_start + info.start) as usize;
+        let end = (header.data_start + info.end) as usize;
+        assert_eq!(
+            to_f32(info.dtype, &bytes[start..end]),
+            [1.0, -2.0, 3.5, 0.25]
+        );
+
+        // The data section must hold every tensor
+        assert!(
+            SafetensorsHeader::parse(&bytes[..bytes.len() - 1], bytes.len() as u64 - 1).is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_shapes_whose_size_overflows() {
+        let json = br#"{"a": {"dtype": "F32", "shape": [4294967296, 4294967296], "data_offsets": [0, 0]}}"#;
+        let bytes = [&(json.len() as u64).to_le_bytes()[..], json].concat();
+        let error = SafetensorsHeader::parse(&bytes, bytes.len() as u64).unwrap_err();
+        assert!(error.contains("too large"), "{}", error);
+    }
+
+    #[test]
+    fn converts_half_precision() {
+        let values = [0x3c00, 0xc000, 0x7bff, 0x0001, 0x0200, 0x7c00, 0x8000];
+        let bytes: Vec<u8> = values.iter().flat_map(|v: &u16| v.to_le_bytes()).collect();
+        assert_eq!(
+            to_f32(Dtype::F16, &bytes),
+            [
+                1.0,
+                -2.0,
+                65504.0,
+                2f32.powi(-24),
+                2f32.powi(-15),
+                f32::INFINITY,
+                -0.0
+            ]
+        );
+
+        let bytes: Vec<u8> = [0x3f80u16, 0xc040]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        assert_eq!(to_f32(Dtype::BF16, &bytes), [1.0, -3.0]);
+    }
+}
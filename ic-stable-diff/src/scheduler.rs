@@ -0,0 +1,933 @@
+// Diffusion schedulers (samplers)
+//
+// Every scheduler works on latent tensors of any shape and keeps all of its
+// per-generation state in Candid-serializable fields, so a SchedulerState can
+// be checkpointed with the pipeline between worker ticks.
+//
+// The Euler-family schedulers follow the k-diffusion formulation: samples
+// live in sigma space (x = x0 + sigma * noise) and the model input is scaled
+// by 1 / sqrt(sigma^2 + 1).
+//
+// DDIM works directly on the variance-preserving samples
+// x_t = sqrt(alpha_bar_t) * x0 + sqrt(1 - alpha_bar_t) * noise.
+
+use crate::rng::TorchGenerator;
+use crate::tensor::Tensor;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+pub trait Scheduler {
+    // Build the inference schedule for `num_inference_steps` steps
+    fn set_timesteps(&mut self, num_inference_steps: usize);
+
+    // Training timesteps the UNet is evaluated at, in sampling order
+    fn timesteps(&self) -> &[u32];
+
+    // Scale the denoising model input for the given step
+    fn scale_model_input(&self, sample: &Tensor, step_index: usize) -> Tensor;
+
+    // Compute the sample for the next step from the predicted noise
+    fn step(&mut self, model_output: &Tensor, step_index: usize, sample: &Tensor) -> Tensor;
+
+    // Standard deviation the initial noise is scaled by
+    fn init_noise_sigma(&self) -> f32;
+
+    // Noise clean latents to the noise level the given step starts from
+    fn add_noise(&self, original: &Tensor, noise: &Tensor, step_index: usize) -> Tensor;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub enum SchedulerKind {
+    #[default]
+    Ddim,
+    Euler,
+    EulerAncestral,
+    DpmPlusPlus2M,
+    Lms,
+}
+
+// What the denoising model was trained to predict
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, CandidType, Deserialize, Serialize)]
+pub enum PredictionType {
+    // The added noise (SD 1.x, SD 2.x base)
+    #[default]
+    Epsilon,
+    // The velocity sqrt(alpha_bar) * noise - sqrt(1 - alpha_bar) * x0 (SD 2.x 768)
+    VPrediction,
+}
+
+// Training noise schedule shared by all schedulers
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SchedulerConfig {
+    pub num_train_timesteps: usize,
+    pub beta_start: f32,
+    pub beta_end: f32,
+    pub prediction_type: PredictionType,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            num_train_timesteps: 1000,
+            beta_start: 0.00085,
+            beta_end: 0.012,
+            prediction_type: PredictionType::Epsilon,
+        }
+    }
+}
+
+impl SchedulerConfig {
+    // Cumulative product of (1 - beta) over the "scaled linear" beta schedule
+    // used by Stable Diffusion
+    pub fn alphas_cumprod(&self) -> Vec<f64> {
+        let n = self.num_train_timesteps;
+        let start = (self.beta_start as f64).sqrt();
+        let end = (self.beta_end as f64).sqrt();
+
+        let mut product = 1.0;
+        (0..n)
+            .map(|i| {
+                let t = if n > 1 {
+                    i as f64 / (n - 1) as f64
+                } else {
+                    0.0
+                };
+                let beta = (start + (end - start) * t).powi(2);
+                product *= 1.0 - beta;
+                product
+            })
+            .collect()
+    }
+
+    // Evenly spaced timesteps from the end of the training schedule, the
+    // "trailing" spacing in diffusers. Requests are validated against the
+    // training schedule; more steps than it has timesteps would repeat them.
+    pub fn get_timesteps(&self, num_inference_steps: usize) -> Vec<u32> {
+        assert!(
+            (1..=self.num_train_timesteps).contains(&num_inference_steps),
+            "{} inference steps do not fit a schedule of {} training timesteps",
+            num_inference_steps,
+            self.num_train_timesteps
+        );
+        let step_size = self.num_train_timesteps / num_inference_steps;
+        (0..num_inference_steps)
+            .map(|i| (self.num_train_timesteps - i * step_size - 1) as u32)
+            .collect()
+    }
+
+    // Noise levels for `timesteps` followed by a final 0
+    fn sigmas(&self, timesteps: &[u32]) -> Vec<f32> {
+        let alphas_cumprod = self.alphas_cumprod();
+        timesteps
+            .iter()
+            .map(|&t| {
+                let alpha = alphas_cumprod[t as usize];
+                ((1.0 - alpha) / alpha).sqrt() as f32
+            })
+            .chain(std::iter::once(0.0))
+            .collect()
+    }
+}
+
+// Scheduler chosen for a generation, with its checkpointable state
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum SchedulerState {
+    Ddim(DDIMScheduler),
+    Euler(EulerScheduler),
+    EulerAncestral(EulerAncestralScheduler),
+    DpmPlusPlus2M(DPMPlusPlus2MScheduler),
+    Lms(LMSScheduler),
+}
+
+impl SchedulerState {
+    pub fn new(
+        kind: SchedulerKind,
+        config: SchedulerConfig,
+        num_inference_steps: usize,
+        generator: TorchGenerator,
+        eta: f32,
+    ) -> Self {
+        let mut scheduler = match kind {
+            SchedulerKind::Ddim => SchedulerState::Ddim(DDIMScheduler::new(config, eta, generator)),
+            SchedulerKind::Euler => SchedulerState::Euler(EulerScheduler::new(config)),
+            SchedulerKind::EulerAncestral => {
+                SchedulerState::EulerAncestral(EulerAncestralScheduler::new(config, generator))
+            }
+            SchedulerKind::DpmPlusPlus2M => {
+                SchedulerState::DpmPlusPlus2M(DPMPlusPlus2MScheduler::new(config))
+            }
+            SchedulerKind::Lms => SchedulerState::Lms(LMSScheduler::new(config)),
+        };
+        scheduler.set_timesteps(num_inference_steps);
+        scheduler
+    }
+
+    fn inner(&self) -> &dyn Scheduler {
+        match self {
+            SchedulerState::Ddim(s) => s,
+            SchedulerState::Euler(s) => s,
+            SchedulerState::EulerAncestral(s) => s,
+            SchedulerState::DpmPlusPlus2M(s) => s,
+            SchedulerState::Lms(s) => s,
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut dyn Scheduler {
+        match self {
+            SchedulerState::Ddim(s) => s,
+            SchedulerState::Euler(s) => s,
+            SchedulerState::EulerAncestral(s) => s,
+            SchedulerState::DpmPlusPlus2M(s) => s,
+            SchedulerState::Lms(s) => s,
+        }
+    }
+}
+
+impl Scheduler for SchedulerState {
+    fn set_timesteps(&mut self, num_inference_steps: usize) {
+        self.inner_mut().set_timesteps(num_inference_steps)
+    }
+
+    fn timesteps(&self) -> &[u32] {
+        self.inner().timesteps()
+    }
+
+    fn scale_model_input(&self, sample: &Tensor, step_index: usize) -> Tensor {
+        self.inner().scale_model_input(sample, step_index)
+    }
+
+    fn step(&mut self, model_output: &Tensor, step_index: usize, sample: &Tensor) -> Tensor {
+        assert_eq!(
+            model_output.shape(),
+            sample.shape(),
+            "model output does not match the sample"
+        );
+        self.inner_mut().step(model_output, step_index, sample)
+    }
+
+    fn init_noise_sigma(&self) -> f32 {
+        self.inner().init_noise_sigma()
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: &Tensor, step_index: usize) -> Tensor {
+        self.inner().add_noise(original, noise, step_index)
+    }
+}
+
+// First step of an img2img schedule: the image is noised to this step's
+// level and only the remaining `strength` share of the steps are run, with
+// the same rounding as diffusers
+pub fn strength_start_step(num_inference_steps: usize, strength: f32) -> usize {
+    let init_steps =
+        ((num_inference_steps as f64 * strength as f64) as usize).min(num_inference_steps);
+    num_inference_steps - init_steps
+}
+
+// DDIM
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DDIMScheduler {
+    config: SchedulerConfig,
+    timesteps: Vec<u32>,
+    // alpha_bar at each timestep followed by the value used after the last
+    // step, precomputed when the schedule is built
+    alphas_cumprod: Vec<f64>,
+    // 0 gives deterministic DDIM, 1 matches the DDPM posterior variance
+    eta: f32,
+    generator: TorchGenerator,
+}
+
+impl DDIMScheduler {
+    pub fn new(config: SchedulerConfig, eta: f32, generator: TorchGenerator) -> Self {
+        Self {
+            config,
+            timesteps: Vec::new(),
+            alphas_cumprod: Vec::new(),
+            eta,
+            generator,
+        }
+    }
+
+    // Standard deviation of the noise added when stepping from alpha_bar_t
+    // to alpha_bar_prev (sigma_t in the DDIM paper, scaled by eta)
+    fn std_dev(&self, alpha_prod_t: f64, alpha_prod_prev: f64) -> f64 {
+        let variance =
+            (1.0 - alpha_prod_prev) / (1.0 - alpha_prod_t) * (1.0 - alpha_prod_t / alpha_prod_prev);
+        self.eta as f64 * variance.max(0.0).sqrt()
+    }
+}
+
+impl Scheduler for DDIMScheduler {
+    fn set_timesteps(&mut self, num_inference_steps: usize) {
+        self.timesteps = self.config.get_timesteps(num_inference_steps);
+
+        // Past the last timestep the sample is treated as clean data, using
+        // alpha_bar at t = 0 as Stable Diffusion does (set_alpha_to_one=False)
+        let alphas_cumprod = self.config.alphas_cumprod();
+        self.alphas_cumprod = self
+            .timesteps
+            .iter()
+            .map(|&t| alphas_cumprod[t as usize])
+            .chain(alphas_cumprod.first().copied())
+            .collect();
+    }
+
+    fn timesteps(&self) -> &[u32] {
+        &self.timesteps
+    }
+
+    fn scale_model_input(&self, sample: &Tensor, _step_index: usize) -> Tensor {
+        sample.clone()
+    }
+
+    fn step(&mut self, model_output: &Tensor, step_index: usize, sample: &Tensor) -> Tensor {
+        let alpha_prod_t = self.alphas_cumprod[step_index];
+        let alpha_prod_prev = self.alphas_cumprod[step_index + 1];
+        let beta_prod_t = 1.0 - alpha_prod_t;
+        let std_dev = self.std_dev(alpha_prod_t, alpha_prod_prev);
+        let direction_scale = (1.0 - alpha_prod_prev - std_dev * std_dev).max(0.0).sqrt();
+        let prediction_type = self.config.prediction_type;
+
+        // Variance noise is only drawn when the step is stochastic
+        let noise = if std_dev > 0.0 {
+            self.generator.randn(sample.numel())
+        } else {
+            Vec::new()
+        };
+
+        let data = sample
+            .values()
+            .iter()
+            .zip(model_output.values().iter())
+            .enumerate()
+            .map(|(i, (&x, &output))| {
+                let (x, output) = (x as f64, output as f64);
+
+                // Predicted clean sample and the noise it implies
+                let (original, epsilon) = match prediction_type {
+                    PredictionType::Epsilon => (
+                        (x - beta_prod_t.sqrt() * output) / alpha_prod_t.sqrt(),
+                        output,
+                    ),
+                    PredictionType::VPrediction => (
+                        alpha_prod_t.sqrt() * x - beta_prod_t.sqrt() * output,
+                        alpha_prod_t.sqrt() * output + beta_prod_t.sqrt() * x,
+                    ),
+                };
+
+                let mut prev = alpha_prod_prev.sqrt() * original + direction_scale * epsilon;
+                if let Some(&noise) = noise.get(i) {
+                    prev += std_dev * noise as f64;
+                }
+                prev as f32
+            })
+            .collect();
+        Tensor::new(sample.shape(), data)
+    }
+
+    fn init_noise_sigma(&self) -> f32 {
+        1.0
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: &Tensor, step_index: usize) -> Tensor {
+        let alpha_prod = self.alphas_cumprod[step_index];
+        let (a, b) = (alpha_prod.sqrt() as f32, (1.0 - alpha_prod).sqrt() as f32);
+        original.zip_map(noise, |x, n| a * x + b * n)
+    }
+}
+
+// Helpers shared by the sigma-space (k-diffusion style) schedulers
+
+fn scale_by_sigma(sample: &Tensor, sigma: f32) -> Tensor {
+    sample.scale(1.0 / (sigma * sigma + 1.0).sqrt())
+}
+
+// Denoised estimate x0 for a sample at noise level sigma
+fn predict_original(
+    sample: &Tensor,
+    model_output: &Tensor,
+    sigma: f32,
+    prediction_type: PredictionType,
+) -> Tensor {
+    match prediction_type {
+        PredictionType::Epsilon => sample.zip_map(model_output, |x, eps| x - sigma * eps),
+        PredictionType::VPrediction => {
+            let c_skip = 1.0 / (sigma * sigma + 1.0);
+            let c_out = -sigma / (sigma * sigma + 1.0).sqrt();
+            sample.zip_map(model_output, |x, v| x * c_skip + v * c_out)
+        }
+    }
+}
+
+// ODE derivative (x - x0) / sigma, which is the model output itself for an
+// epsilon-predicting model
+fn derivative(
+    sample: &Tensor,
+    model_output: &Tensor,
+    sigma: f32,
+    prediction_type: PredictionType,
+) -> Tensor {
+    match prediction_type {
+        PredictionType::Epsilon => model_output.clone(),
+        PredictionType::VPrediction => {
+            let original = predict_original(sample, model_output, sigma, prediction_type);
+            sample.zip_map(&original, |x, x0| (x - x0) / sigma)
+        }
+    }
+}
+
+// Euler
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EulerScheduler {
+    config: SchedulerConfig,
+    timesteps: Vec<u32>,
+    sigmas: Vec<f32>,
+}
+
+impl EulerScheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self {
+            config,
+            timesteps: Vec::new(),
+            sigmas: Vec::new(),
+        }
+    }
+}
+
+impl Scheduler for EulerScheduler {
+    fn set_timesteps(&mut self, num_inference_steps: usize) {
+        self.timesteps = self.config.get_timesteps(num_inference_steps);
+        self.sigmas = self.config.sigmas(&self.timesteps);
+    }
+
+    fn timesteps(&self) -> &[u32] {
+        &self.timesteps
+    }
+
+    fn scale_model_input(&self, sample: &Tensor, step_index: usize) -> Tensor {
+        scale_by_sigma(sample, self.sigmas[step_index])
+    }
+
+    fn step(&mut self, model_output: &Tensor, step_index: usize, sample: &Tensor) -> Tensor {
+        let sigma = self.sigmas[step_index];
+        let dt = self.sigmas[step_index + 1] - sigma;
+        let derivative = derivative(sample, model_output, sigma, self.config.prediction_type);
+
+        sample.zip_map(&derivative, |x, d| x + d * dt)
+    }
+
+    fn init_noise_sigma(&self) -> f32 {
+        self.sigmas.first().copied().unwrap_or(1.0)
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: &Tensor, step_index: usize) -> Tensor {
+        let sigma = self.sigmas[step_index];
+        original.zip_map(noise, |x, n| x + sigma * n)
+    }
+}
+
+// Euler Ancestral
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct EulerAncestralScheduler {
+    config: SchedulerConfig,
+    timesteps: Vec<u32>,
+    sigmas: Vec<f32>,
+    generator: TorchGenerator,
+}
+
+impl EulerAncestralScheduler {
+    pub fn new(config: SchedulerConfig, generator: TorchGenerator) -> Self {
+        Self {
+            config,
+            timesteps: Vec::new(),
+            sigmas: Vec::new(),
+            generator,
+        }
+    }
+}
+
+impl Scheduler for EulerAncestralScheduler {
+    fn set_timesteps(&mut self, num_inference_steps: usize) {
+        self.timesteps = self.config.get_timesteps(num_inference_steps);
+        self.sigmas = self.config.sigmas(&self.timesteps);
+    }
+
+    fn timesteps(&self) -> &[u32] {
+        &self.timesteps
+    }
+
+    fn scale_model_input(&self, sample: &Tensor, step_index: usize) -> Tensor {
+        scale_by_sigma(sample, self.sigmas[step_index])
+    }
+
+    fn step(&mut self, model_output: &Tensor, step_index: usize, sample: &Tensor) -> Tensor {
+        let sigma_from = self.sigmas[step_index];
+        let sigma_to = self.sigmas[step_index + 1];
+
+        // Split the step into a deterministic part down to sigma_down and
+        // fresh noise of scale sigma_up
+        let sigma_up = (sigma_to * sigma_to * (sigma_from * sigma_from - sigma_to * sigma_to)
+            / (sigma_from * sigma_from))
+            .max(0.0)
+            .sqrt();
+        let sigma_down = (sigma_to * sigma_to - sigma_up * sigma_up).max(0.0).sqrt();
+        let dt = sigma_down - sigma_from;
+        let derivative = derivative(
+            sample,
+            model_output,
+            sigma_from,
+            self.config.prediction_type,
+        );
+        let noise = Tensor::new(sample.shape(), self.generator.randn(sample.numel()));
+
+        sample
+            .zip_map(&derivative, |x, d| x + d * dt)
+            .zip_map(&noise, |x, noise| x + noise * sigma_up)
+    }
+
+    fn init_noise_sigma(&self) -> f32 {
+        self.sigmas.first().copied().unwrap_or(1.0)
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: &Tensor, step_index: usize) -> Tensor {
+        let sigma = self.sigmas[step_index];
+        original.zip_map(noise, |x, n| x + sigma * n)
+    }
+}
+
+// DPM-Solver++(2M)
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct DPMPlusPlus2MScheduler {
+    config: SchedulerConfig,
+    timesteps: Vec<u32>,
+    sigmas: Vec<f32>,
+    previous_denoised: Option<Tensor>,
+}
+
+impl DPMPlusPlus2MScheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self {
+            config,
+            timesteps: Vec::new(),
+            sigmas: Vec::new(),
+            previous_denoised: None,
+        }
+    }
+}
+
+impl Scheduler for DPMPlusPlus2MScheduler {
+    fn set_timesteps(&mut self, num_inference_steps: usize) {
+        self.timesteps = self.config.get_timesteps(num_inference_steps);
+        self.sigmas = self.config.sigmas(&self.timesteps);
+        self.previous_denoised = None;
+    }
+
+    fn timesteps(&self) -> &[u32] {
+        &self.timesteps
+    }
+
+    fn scale_model_input(&self, sample: &Tensor, step_index: usize) -> Tensor {
+        scale_by_sigma(sample, self.sigmas[step_index])
+    }
+
+    fn step(&mut self, model_output: &Tensor, step_index: usize, sample: &Tensor) -> Tensor {
+        let sigma = self.sigmas[step_index];
+        let sigma_next = self.sigmas[step_index + 1];
+        let denoised = predict_original(sample, model_output, sigma, self.config.prediction_type);
+
+        let next = if sigma_next == 0.0 {
+            denoised.clone()
+        } else {
+            // Work in log-sigma time t = -ln(sigma)
+            let t = -(sigma as f64).ln();
+            let t_next = -(sigma_next as f64).ln();
+            let h = t_next - t;
+            let ratio = (sigma_next / sigma) as f64;
+            let coeff = -(-h).exp_m1();
+
+            // Second-order correction from the previous denoised estimate
+            let (w_current, w_previous) = match self.previous_denoised {
+                Some(_) if step_index > 0 => {
+                    let h_last = t - (-(self.sigmas[step_index - 1] as f64).ln());
+                    let r = h_last / h;
+                    (1.0 + 1.0 / (2.0 * r), -1.0 / (2.0 * r))
+                }
+                _ => (1.0, 0.0),
+            };
+            let previous = self.previous_denoised.as_ref().unwrap_or(&denoised);
+
+            let blended = denoised.zip_map(previous, |d, d_prev| {
+                (w_current * d as f64 + w_previous * d_prev as f64) as f32
+            });
+            sample.zip_map(&blended, |x, d| {
+                (ratio * x as f64 + coeff * d as f64) as f32
+            })
+        };
+
+        self.previous_denoised = Some(denoised);
+        next
+    }
+
+    fn init_noise_sigma(&self) -> f32 {
+        self.sigmas.first().copied().unwrap_or(1.0)
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: &Tensor, step_index: usize) -> Tensor {
+        let sigma = self.sigmas[step_index];
+        original.zip_map(noise, |x, n| x + sigma * n)
+    }
+}
+
+// Linear multistep (LMS)
+
+const LMS_ORDER: usize = 4;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct LMSScheduler {
+    config: SchedulerConfig,
+    timesteps: Vec<u32>,
+    sigmas: Vec<f32>,
+    // Most recent derivative first, at most LMS_ORDER entries
+    derivatives: Vec<Tensor>,
+}
+
+impl LMSScheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self {
+            config,
+            timesteps: Vec::new(),
+            sigmas: Vec::new(),
+            derivatives: Vec::new(),
+        }
+    }
+
+    // Integral over [sigma_t, sigma_t+1] of the Lagrange basis polynomial for
+    // the derivative `current` steps back. The integrand has degree at most
+    // 3, so Simpson's rule is exact.
+    fn coefficient(&self, order: usize, t: usize, current: usize) -> f64 {
+        let sigmas = &self.sigmas;
+        let basis = |tau: f64| {
+            (0..order)
+                .filter(|&k| k != current)
+                .map(|k| {
+                    (tau - sigmas[t - k] as f64)
+                        / (sigmas[t - current] as f64 - sigmas[t - k] as f64)
+                })
+                .product::<f64>()
+        };
+
+        let a = sigmas[t] as f64;
+        let b = sigmas[t + 1] as f64;
+        (b - a) / 6.0 * (basis(a) + 4.0 * basis((a + b) / 2.0) + basis(b))
+    }
+}
+
+impl Scheduler for LMSScheduler {
+    fn set_timesteps(&mut self, num_inference_steps: usize) {
+        self.timesteps = self.config.get_timesteps(num_inference_steps);
+        self.sigmas = self.config.sigmas(&self.timesteps);
+        self.derivatives.clear();
+    }
+
+    fn timesteps(&self) -> &[u32] {
+        &self.timesteps
+    }
+
+    fn scale_model_input(&self, sample: &Tensor, step_index: usize) -> Tensor {
+        scale_by_sigma(sample, self.sigmas[step_index])
+    }
+
+    fn step(&mut self, model_output: &Tensor, step_index: usize, sample: &Tensor) -> Tensor {
+        let sigma = self.sigmas[step_index];
+        self.derivatives.insert(
+            0,
+            derivative(sample, model_output, sigma, self.config.prediction_type),
+        );
+        self.derivatives.truncate(LMS_ORDER);
+
+        let order = self.derivatives.len().min(step_index + 1);
+        let coefficients: Vec<f32> = (0..order)
+            .map(|current| self.coefficient(order, step_index, current) as f32)
+            .collect();
+
+        let mut next = sample.clone();
+        for (&coefficient, derivative) in coefficients.iter().zip(&self.derivatives) {
+            next = next.zip_map(derivative, |x, d| x + coefficient * d);
+        }
+        next
+    }
+
+    fn init_noise_sigma(&self) -> f32 {
+        self.sigmas.first().copied().unwrap_or(1.0)
+    }
+
+    fn add_noise(&self, original: &Tensor, noise: &Tensor, step_index: usize) -> Tensor {
+        let sigma = self.sigmas[step_index];
+        original.zip_map(noise, |x, n| x + sigma * n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scalar(value: f32) -> Tensor {
+        Tensor::new(&[1], vec![value])
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    fn ddim(
+        num_inference_steps: usize,
+        eta: f32,
+        prediction_type: PredictionType,
+    ) -> DDIMScheduler {
+        let config = SchedulerConfig {
+            prediction_type,
+            ..SchedulerConfig::default()
+        };
+        let mut scheduler = DDIMScheduler::new(config, eta, TorchGenerator::new(0));
+        scheduler.set_timesteps(num_inference_steps);
+        scheduler
+    }
+
+    #[test]
+    fn alphas_cumprod_matches_scaled_linear_schedule() {
+        let alphas_cumprod = SchedulerConfig::default().alphas_cumprod();
+        assert_eq!(alphas_cumprod.len(), 1000);
+        assert_close(alphas_cumprod[0], 0.99915, 1e-7);
+        assert_close(alphas_cumprod[499], 0.277_669_650_456_467_6, 1e-7);
+        assert_close(alphas_cumprod[999], 0.004_660_098_513_077_234, 1e-7);
+    }
+
+    #[test]
+    fn ddim_epsilon_step_matches_reference() {
+        let mut scheduler = ddim(20, 0.0, PredictionType::Epsilon);
+        assert_eq!(scheduler.timesteps()[0], 999);
+        assert_eq!(scheduler.timesteps()[19], 49);
+
+        // t = 999 -> 949
+        let next = scheduler.step(&scalar(-0.4), 0, &scalar(0.8));
+        assert_close(next.values()[0] as f64, 1.205_773_650_372_364_4, 1e-5);
+
+        // t = 49 -> final alpha_bar = alphas_cumprod[0]
+        let last = scheduler.step(&scalar(-0.4), 19, &scalar(0.8));
+        assert_close(last.values()[0] as f64, 0.896_803_820_411_720_5, 1e-5);
+    }
+
+    #[test]
+    fn ddim_recovers_exact_trajectory() {
+        // With a perfect model prediction, deterministic DDIM lands exactly on
+        // x_prev = sqrt(alpha_bar_prev) * x0 + sqrt(1 - alpha_bar_prev) * eps
+        for prediction_type in [PredictionType::Epsilon, PredictionType::VPrediction] {
+            let mut scheduler = ddim(10, 0.0, prediction_type);
+            let (x0, eps) = (0.3f64, -1.2f64);
+            let alphas_cumprod = scheduler.alphas_cumprod.clone();
+
+            for step_index in 0..10 {
+                let alpha = alphas_cumprod[step_index];
+                let sample = alpha.sqrt() * x0 + (1.0 - alpha).sqrt() * eps;
+                let output = match prediction_type {
+                    PredictionType::Epsilon => eps,
+                    PredictionType::VPrediction => alpha.sqrt() * eps - (1.0 - alpha).sqrt() * x0,
+                };
+
+                let next =
+                    scheduler.step(&scalar(output as f32), step_index, &scalar(sample as f32));
+                let alpha_prev = alphas_cumprod[step_index + 1];
+                let expected = alpha_prev.sqrt() * x0 + (1.0 - alpha_prev).sqrt() * eps;
+                assert_close(next.values()[0] as f64, expected, 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn img2img_noises_to_the_start_step() {
+        assert_eq!(strength_start_step(20, 0.8), 4);
+        assert_eq!(strength_start_step(10, 1.0), 0);
+        assert_eq!(strength_start_step(10, 0.05), 10);
+
+        let (x0, eps) = (scalar(0.3), scalar(-1.2));
+        let scheduler = ddim(10, 0.0, PredictionType::Epsilon);
+        let alpha = scheduler.alphas_cumprod[3];
+        let expected = alpha.sqrt() * 0.3 + (1.0 - alpha).sqrt() * -1.2;
+        assert_close(
+            scheduler.add_noise(&x0, &eps, 3).values()[0] as f64,
+            expected,
+            1e-6,
+        );
+
+        // A perfect prediction carries the noised sample along the same
+        // trajectory to the next step's noise level
+        let mut scheduler = SchedulerState::new(
+            SchedulerKind::Euler,
+            SchedulerConfig::default(),
+            10,
+            TorchGenerator::new(0),
+            0.0,
+        );
+        let sample = scheduler.add_noise(&x0, &eps, 3);
+        let next = scheduler.step(&eps, 3, &sample);
+        assert_close(
+            next.values()[0] as f64,
+            scheduler.add_noise(&x0, &eps, 4).values()[0] as f64,
+            1e-5,
+        );
+    }
+
+    // Reference values below come from the diffusers step formulas with
+    // timestep_spacing="trailing", evaluated in float64. Multi-step runs
+    // start from 0.7 * init_noise_sigma and use the stand-in model
+    // eps = 0.5 * scaled_input - 0.1.
+    fn run(kind: SchedulerKind, sample: f32, steps: usize) -> f32 {
+        let mut scheduler = SchedulerState::new(
+            kind,
+            SchedulerConfig::default(),
+            10,
+            TorchGenerator::new(0),
+            0.0,
+        );
+        let mut sample = scalar(sample);
+        for step_index in 0..steps {
+            let input = scheduler.scale_model_input(&sample, step_index);
+            let output = input.map(|x| 0.5 * x - 0.1);
+            sample = scheduler.step(&output, step_index, &sample);
+        }
+        sample.values()[0]
+    }
+
+    fn from_noise(kind: SchedulerKind) -> f32 {
+        let scheduler = SchedulerState::new(
+            kind,
+            SchedulerConfig::default(),
+            10,
+            TorchGenerator::new(0),
+            0.0,
+        );
+        run(kind, 0.7 * scheduler.init_noise_sigma(), 10)
+    }
+
+    #[test]
+    fn sigma_schedule_matches_reference() {
+        let mut scheduler = EulerScheduler::new(SchedulerConfig::default());
+        scheduler.set_timesteps(10);
+        assert_eq!(
+            scheduler.timesteps(),
+            [999, 899, 799, 699, 599, 499, 399, 299, 199, 99]
+        );
+        assert_eq!(scheduler.sigmas.len(), 11);
+        assert_close(scheduler.sigmas[0] as f64, 14.614_641_189_575_195, 1e-5);
+        assert_close(scheduler.sigmas[4] as f64, 2.276_463_031_768_799, 1e-6);
+        assert_close(scheduler.sigmas[9] as f64, 0.341_673_821_210_861_2, 1e-6);
+        assert_eq!(scheduler.sigmas[10], 0.0);
+        assert_eq!(scheduler.init_noise_sigma(), scheduler.sigmas[0]);
+
+        assert_eq!(SchedulerConfig::default().get_timesteps(1000)[999], 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "do not fit")]
+    fn more_steps_than_training_timesteps_are_rejected() {
+        SchedulerConfig::default().get_timesteps(1001);
+    }
+
+    #[test]
+    fn euler_matches_reference() {
+        let mut scheduler = EulerScheduler::new(SchedulerConfig::default());
+        scheduler.set_timesteps(10);
+        let next = scheduler.step(&scalar(-0.4), 0, &scalar(0.8));
+        assert_close(next.values()[0] as f64, 3.324_735_260_009_766, 1e-5);
+
+        let last = from_noise(SchedulerKind::Euler);
+        assert_close(last as f64, 2.661_686_793_519_751_2, 1e-4);
+    }
+
+    #[test]
+    fn euler_ancestral_matches_reference() {
+        // sigma_up = 6.832782506427153 and sigma_down = 4.716950448482237
+        // for the first step, with the first torch.randn sample of seed 0
+        let mut scheduler =
+            EulerAncestralScheduler::new(SchedulerConfig::default(), TorchGenerator::new(0));
+        scheduler.set_timesteps(10);
+        let next = scheduler.step(&scalar(-0.4), 0, &scalar(0.8));
+        assert_close(next.values()[0] as f64, 15.288_367_547_319_407, 1e-4);
+
+        let last = from_noise(SchedulerKind::EulerAncestral);
+        assert_close(last as f64, 0.491_818_628_306_435_8, 1e-4);
+    }
+
+    #[test]
+    fn dpm_plus_plus_2m_matches_reference() {
+        // The first step is first order, later ones use the previous
+        // denoised estimate and the last lands on the denoised sample
+        let first = run(SchedulerKind::DpmPlusPlus2M, 0.8, 1);
+        assert_close(first as f64, 1.258_832_978_590_129_3, 1e-5);
+        let second = run(SchedulerKind::DpmPlusPlus2M, 0.8, 2);
+        assert_close(second as f64, 1.271_585_700_475_778_2, 1e-5);
+
+        let last = from_noise(SchedulerKind::DpmPlusPlus2M);
+        assert_close(last as f64, 2.386_443_400_404_998_4, 1e-4);
+    }
+
+    #[test]
+    fn lms_matches_reference() {
+        let mut scheduler = LMSScheduler::new(SchedulerConfig::default());
+        scheduler.set_timesteps(10);
+        // Integrals of the fourth-order Lagrange basis over the fourth step
+        let expected = [
+            -1.583_258_772_850_988,
+            0.687_071_770_503_514,
+            -0.159_718_369_023_813_26,
+            0.011_285_334_292_429_526,
+        ];
+        for (current, expected) in expected.into_iter().enumerate() {
+            assert_close(scheduler.coefficient(4, 3, current), expected, 1e-5);
+        }
+
+        // With a single derivative the first step is an Euler step
+        let next = scheduler.step(&scalar(-0.4), 0, &scalar(0.8));
+        assert_close(next.values()[0] as f64, 3.324_735_260_009_766, 1e-5);
+
+        let last = from_noise(SchedulerKind::Lms);
+        assert_close(last as f64, 2.506_922_972_608_837_3, 1e-4);
+    }
+
+    #[test]
+    fn ddim_eta_scales_posterior_variance() {
+        let deterministic = ddim(20, 0.0, PredictionType::Epsilon);
+        let stochastic = ddim(20, 1.0, PredictionType::Epsilon);
+        let alphas_cumprod = SchedulerConfig::default().alphas_cumprod();
+        let (alpha_t, alpha_prev) = (alphas_cumprod[999], alphas_cumprod[949]);
+
+        assert_eq!(deterministic.std_dev(alpha_t, alpha_prev), 0.0);
+        assert_close(
+            stochastic.std_dev(alpha_t, alpha_prev),
+            0.439_611_853_238_466_4f64.sqrt(),
+            1e-7,
+        );
+    }
+
+    #[test]
+    fn v_prediction_recovers_original_in_sigma_space() {
+        let (x0, eps, sigma) = (0.7f32, 0.5f32, 3.0f32);
+        let scale = (sigma * sigma + 1.0).sqrt();
+        let v = eps / scale - sigma * x0 / scale;
+
+        let sample = scalar(x0 + sigma * eps);
+        let original = predict_original(&sample, &scalar(v), sigma, PredictionType::VPrediction);
+        assert!((original.values()[0] - x0).abs() < 1e-5);
+
+        let d = derivative(&sample, &scalar(v), sigma, PredictionType::VPrediction);
+        assert!((d.values()[0] - eps).abs() < 1e-5);
+    }
+}
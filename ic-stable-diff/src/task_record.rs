@@ -0,0 +1,264 @@
+// Versioned stable-memory encoding for task records
+//
+// Records are written as TASK_RECORD_MAGIC, a layout version byte and the
+// Candid encoding of that layout. Records without the prefix predate
+// versioning and are read as layout v0.
+//
+// Adding an `opt` field to GenerationTask or GenerationRequest is backward
+// compatible in Candid and needs no new version. Any other change (renames,
+// type changes, required fields) must freeze the current layout as a
+// `TaskRecordVn` struct below, bump TASK_RECORD_VERSION and add a
+// `migrate_vn` function converting it to the next layout. A frozen layout
+// must not embed the live types, which keep changing; it carries frozen
+// copies of every record it nests.
+
+use crate::{GenerationRequest, GenerationTask, TaskStatus};
+use candid::{CandidType, Decode, Encode};
+use ic_stable_structures::Storable;
+use serde::Deserialize;
+use std::borrow::Cow;
+
+const TASK_RECORD_MAGIC: &[u8; 4] = b"SDTK";
+pub const TASK_RECORD_VERSION: u8 = 1;
+
+// Layout v0: the unversioned records of the first release, which kept the
+// image inline in `result`
+#[derive(CandidType, Deserialize)]
+struct TaskRecordV0 {
+    id: String,
+    status: TaskStatusV0,
+    created_at: u64,
+    completed_at: Option<u64>,
+    request: GenerationRequestV0,
+    result: Option<Vec<u8>>,
+    error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum TaskStatusV0 {
+    Pending,
+    Processing,
+    Completed,
+    Failed,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GenerationRequestV0 {
+    prompt: String,
+    negative_prompt: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    num_inference_steps: Option<u32>,
+    guidance_scale: Option<f32>,
+    seed: Option<u64>,
+}
+
+fn migrate_v0(record: TaskRecordV0) -> (GenerationTask, Option<Vec<u8>>) {
+    let request = record.request;
+    let task = GenerationTask {
+        id: record.id,
+        status: match record.status {
+            TaskStatusV0::Pending => TaskStatus::Pending,
+            TaskStatusV0::Processing => TaskStatus::Processing,
+            TaskStatusV0::Completed => TaskStatus::Completed,
+            TaskStatusV0::Failed => TaskStatus::Failed,
+        },
+        created_at: record.created_at,
+        completed_at: record.completed_at,
+        request: GenerationRequest {
+            prompt: request.prompt,
+            negative_prompt: request.negative_prompt,
+            width: request.width,
+            height: request.height,
+            num_inference_steps: request.num_inference_steps,
+            guidance_scale: request.guidance_scale,
+            seed: request.seed,
+            ..GenerationRequest::default()
+        },
+        image: None,
+        error: record.error,
+        progress: None,
+        warning: None,
+        attempts: None,
+    };
+    (task, record.result)
+}
+
+// Storable wrapper for GenerationTask
+#[derive(Clone, Debug)]
+pub struct StorableGenerationTask {
+    pub task: GenerationTask,
+    // Image bytes carried inline by v0 records; moved to IMAGE_STORE by the
+    // post-upgrade migration
+    pub inline_image: Option<Vec<u8>>,
+    // Layout version the record was read from
+    pub version: u8,
+    // Set when the stored bytes matched no known layout. `task` is then a
+    // Failed placeholder with an empty id, and the record is left untouched.
+    pub unreadable: bool,
+}
+
+impl StorableGenerationTask {
+    pub fn new(task: GenerationTask) -> Self {
+        Self {
+            task,
+            inline_image: None,
+            version: TASK_RECORD_VERSION,
+            unreadable: false,
+        }
+    }
+
+    pub fn needs_migration(&self) -> bool {
+        !self.unreadable && self.version < TASK_RECORD_VERSION
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let Some(rest) = bytes.strip_prefix(TASK_RECORD_MAGIC) else {
+            let record = Decode!(bytes, TaskRecordV0).map_err(|e| e.to_string())?;
+            let (task, inline_image) = migrate_v0(record);
+            return Ok(Self {
+                task,
+                inline_image,
+                version: 0,
+                unreadable: false,
+            });
+        };
+
+        match rest.split_first() {
+            Some((&1, payload)) => {
+                let task = Decode!(payload, GenerationTask).map_err(|e| e.to_string())?;
+                Ok(Self {
+                    task,
+                    inline_image: None,
+                    version: 1,
+                    unreadable: false,
+                })
+            }
+            Some((version, _)) => Err(format!("unknown task record version {}", version)),
+            None => Err("truncated task record".to_string()),
+        }
+    }
+
+    fn unreadable(error: String) -> Self {
+        Self {
+            task: GenerationTask {
+                id: String::new(),
+                status: TaskStatus::Failed,
+                created_at: 0,
+                completed_at: None,
+                request: GenerationRequest::default(),
+                image: None,
+                error: Some(format!("Stored task record is unreadable: {}", error)),
+                progress: None,
+                warning: None,
+                attempts: None,
+            },
+            inline_image: None,
+            version: 0,
+            unreadable: true,
+        }
+    }
+}
+
+impl Storable for StorableGenerationTask {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = TASK_RECORD_MAGIC.to_vec();
+        bytes.push(TASK_RECORD_VERSION);
+        bytes.extend_from_slice(&Encode!(&self.task).unwrap());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self::decode(bytes.as_ref()).unwrap_or_else(Self::unreadable)
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound =
+        ic_stable_structures::storable::Bound::Unbounded;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The first release's Storable wrapper, which encoded the record as is
+    #[derive(CandidType)]
+    struct FirstReleaseStorable(TaskRecordV0);
+
+    fn first_release_record(id: &str, result: Option<Vec<u8>>) -> Vec<u8> {
+        Encode!(&FirstReleaseStorable(TaskRecordV0 {
+            id: id.to_string(),
+            status: TaskStatusV0::Completed,
+            created_at: 5,
+            completed_at: Some(9),
+            request: GenerationRequestV0 {
+                prompt: "a lighthouse".to_string(),
+                negative_prompt: None,
+                width: Some(256),
+                height: None,
+                num_inference_steps: Some(12),
+                guidance_scale: None,
+                seed: Some(7),
+            },
+            result,
+            error: None,
+        }))
+        .unwrap()
+    }
+
+    fn read(bytes: Vec<u8>) -> StorableGenerationTask {
+        StorableGenerationTask::from_bytes(Cow::Owned(bytes))
+    }
+
+    #[test]
+    fn first_release_records_keep_their_inline_image() {
+        let stored = read(first_release_record("task_1", Some(vec![1, 2, 3])));
+        assert_eq!(stored.version, 0);
+        assert!(stored.needs_migration());
+        assert_eq!(stored.inline_image, Some(vec![1, 2, 3]));
+        let task = stored.task;
+        assert_eq!(task.id, "task_1");
+        assert!(matches!(task.status, TaskStatus::Completed));
+        assert_eq!(task.completed_at, Some(9));
+        assert_eq!(task.request.prompt, "a lighthouse");
+        assert_eq!(task.request.width, Some(256));
+        assert_eq!(task.request.num_inference_steps, Some(12));
+        assert_eq!(task.request.seed, Some(7));
+        assert!(task.request.scheduler.is_none());
+        assert!(task.image.is_none());
+        assert!(task.progress.is_none());
+    }
+
+    #[test]
+    fn current_records_round_trip() {
+        let mut task = read(first_release_record("task_3", None)).task;
+        task.warning = Some("chunked".to_string());
+        task.attempts = Some(2);
+
+        let bytes = StorableGenerationTask::new(task).to_bytes().into_owned();
+        assert!(bytes.starts_with(TASK_RECORD_MAGIC));
+        let stored = read(bytes);
+        assert_eq!(stored.version, TASK_RECORD_VERSION);
+        assert!(!stored.needs_migration());
+        assert!(stored.inline_image.is_none());
+        assert_eq!(stored.task.id, "task_3");
+        assert_eq!(stored.task.warning.as_deref(), Some("chunked"));
+        assert_eq!(stored.task.attempts, Some(2));
+    }
+
+    #[test]
+    fn garbage_reads_as_an_unreadable_placeholder() {
+        let unknown_version = [TASK_RECORD_MAGIC.as_slice(), &[9, 0, 0]].concat();
+        for bytes in [
+            vec![0xde, 0xad, 0xbe, 0xef],
+            unknown_version,
+            TASK_RECORD_MAGIC.to_vec(),
+        ] {
+            let stored = read(bytes);
+            assert!(stored.unreadable);
+            assert!(!stored.needs_migration());
+            assert!(stored.task.id.is_empty());
+            assert!(matches!(stored.task.status, TaskStatus::Failed));
+            assert!(stored.task.error.unwrap().contains("unreadable"));
+        }
+    }
+}
@@ -0,0 +1,847 @@
+// N-dimensional tensors for the diffusion pipeline
+//
+// Values are f32 in row-major storage with explicit strides, so transposes
+// and permutes only rewrite the strides; ops that need packed rows read
+// through `values()`, which copies only when the layout is not contiguous.
+// Images and latents are NCHW. Shape mismatches between operands are
+// programming errors and panic with both shapes, like out-of-bounds slice
+// indexing; use `check_shape` where a shape comes from outside the pipeline.
+
+use candid::CandidType;
+use serde::Deserialize;
+use std::borrow::Cow;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum DType {
+    F32,
+}
+
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+pub struct Tensor {
+    shape: Vec<usize>,
+    strides: Vec<usize>,
+    dtype: DType,
+    data: Vec<f32>,
+}
+
+fn contiguous_strides(shape: &[usize]) -> Vec<usize> {
+    let mut strides = vec![1; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1];
+    }
+    strides
+}
+
+impl Tensor {
+    pub fn new(shape: &[usize], data: Vec<f32>) -> Self {
+        let numel: usize = shape.iter().product();
+        assert_eq!(
+            data.len(),
+            numel,
+            "{} values cannot fill shape {:?}",
+            data.len(),
+            shape
+        );
+        Self {
+            shape: shape.to_vec(),
+            strides: contiguous_strides(shape),
+            dtype: DType::F32,
+            data,
+        }
+    }
+
+    pub fn zeros(shape: &[usize]) -> Self {
+        Self::full(shape, 0.0)
+    }
+
+    pub fn full(shape: &[usize], value: f32) -> Self {
+        Self::new(shape, vec![value; shape.iter().product()])
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.shape
+    }
+
+    pub fn strides(&self) -> &[usize] {
+        &self.strides
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    pub fn dim(&self, index: usize) -> usize {
+        self.shape[index]
+    }
+
+    pub fn numel(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn check_shape(&self, expected: &[usize]) -> Result<(), String> {
+        if self.shape != expected {
+            return Err(format!(
+                "Expected a tensor of shape {:?}, got {:?}",
+                expected, self.shape
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn is_contiguous(&self) -> bool {
+        self.strides == contiguous_strides(&self.shape)
+    }
+
+    // Values in row-major order, borrowed when already laid out that way
+    pub fn values(&self) -> Cow<'_, [f32]> {
+        if self.is_contiguous() {
+            return Cow::Borrowed(&self.data);
+        }
+
+        let mut values = Vec::with_capacity(self.numel());
+        let mut index = vec![0; self.shape.len()];
+        for _ in 0..self.numel() {
+            let offset: usize = index.iter().zip(&self.strides).map(|(i, s)| i * s).sum();
+            values.push(self.data[offset]);
+            for axis in (0..index.len()).rev() {
+                index[axis] += 1;
+                if index[axis] < self.shape[axis] {
+                    break;
+                }
+                index[axis] = 0;
+            }
+        }
+        Cow::Owned(values)
+    }
+
+    pub fn contiguous(self) -> Self {
+        if self.is_contiguous() {
+            return self;
+        }
+        let values = self.values().into_owned();
+        Self::new(&self.shape, values)
+    }
+
+    pub fn into_values(self) -> Vec<f32> {
+        self.contiguous().data
+    }
+
+    pub fn reshape(self, shape: &[usize]) -> Self {
+        assert_eq!(
+            shape.iter().product::<usize>(),
+            self.numel(),
+            "cannot reshape {:?} to {:?}",
+            self.shape,
+            shape
+        );
+        Self::new(shape, self.into_values())
+    }
+
+    pub fn transpose(self, a: usize, b: usize) -> Self {
+        let mut dims: Vec<usize> = (0..self.shape.len()).collect();
+        dims.swap(a, b);
+        self.permute(&dims)
+    }
+
+    // Reorder dimensions so dimension `dims[i]` becomes dimension i
+    pub fn permute(mut self, dims: &[usize]) -> Self {
+        let mut seen = vec![false; self.shape.len()];
+        for &dim in dims {
+            assert!(
+                dim < seen.len() && !std::mem::replace(&mut seen[dim], true),
+                "{:?} is not a permutation of the dimensions of {:?}",
+                dims,
+                self.shape
+            );
+        }
+        assert_eq!(dims.len(), self.shape.len());
+
+        self.shape = dims.iter().map(|&d| self.shape[d]).collect();
+        self.strides = dims.iter().map(|&d| self.strides[d]).collect();
+        self
+    }
+
+    // `len` entries of dimension `dim` starting at `start`
+    pub fn narrow(&self, dim: usize, start: usize, len: usize) -> Self {
+        assert!(
+            start + len <= self.shape[dim],
+            "cannot take {}..{} of dimension {} of {:?}",
+            start,
+            start + len,
+            dim,
+            self.shape
+        );
+        let values = self.values();
+        let outer: usize = self.shape[..dim].iter().product();
+        let inner: usize = self.shape[dim + 1..].iter().product();
+
+        let mut data = Vec::with_capacity(outer * len * inner);
+        for o in 0..outer {
+            let base = (o * self.shape[dim] + start) * inner;
+            data.extend_from_slice(&values[base..base + len * inner]);
+        }
+
+        let mut shape = self.shape.clone();
+        shape[dim] = len;
+        Self::new(&shape, data)
+    }
+
+    // Join tensors along `dim`; all other dimensions must match
+    pub fn concat(tensors: &[&Tensor], dim: usize) -> Self {
+        let first = tensors.first().expect("concat needs at least one tensor");
+        for tensor in tensors {
+            let mismatch = tensor.shape.len() != first.shape.len()
+                || (0..first.shape.len()).any(|d| d != dim && tensor.shape[d] != first.shape[d]);
+            assert!(
+                !mismatch,
+                "cannot concatenate {:?} and {:?} along dimension {}",
+                first.shape, tensor.shape, dim
+            );
+        }
+
+        let outer: usize = first.shape[..dim].iter().product();
+        let values: Vec<Cow<[f32]>> = tensors.iter().map(|t| t.values()).collect();
+        let mut data = Vec::with_capacity(tensors.iter().map(|t| t.numel()).sum());
+        for o in 0..outer {
+            for (tensor, values) in tensors.iter().zip(&values) {
+                let block = tensor.numel() / outer.max(1);
+                data.extend_from_slice(&values[o * block..(o + 1) * block]);
+            }
+        }
+
+        let mut shape = first.shape.clone();
+        shape[dim] = tensors.iter().map(|t| t.shape[dim]).sum();
+        Self::new(&shape, data)
+    }
+
+    // Elementwise ops
+
+    pub fn map(&self, f: impl Fn(f32) -> f32) -> Self {
+        Self::new(&self.shape, self.values().iter().map(|&x| f(x)).collect())
+    }
+
+    // Combine with `other` elementwise. `other` is broadcast to this shape:
+    // its dimensions, aligned from the right, must match or be 1.
+    pub fn zip_map(&self, other: &Tensor, f: impl Fn(f32, f32) -> f32) -> Self {
+        let lhs = self.values();
+        if self.shape == other.shape {
+            let rhs = other.values();
+            let data = lhs.iter().zip(rhs.iter()).map(|(&a, &b)| f(a, b)).collect();
+            return Self::new(&self.shape, data);
+        }
+
+        let offset = self.shape.len().checked_sub(other.shape.len());
+        let broadcastable = offset.is_some_and(|offset| {
+            other
+                .shape
+                .iter()
+                .enumerate()
+                .all(|(i, &d)| d == 1 || d == self.shape[offset + i])
+        });
+        assert!(
+            broadcastable,
+            "cannot broadcast {:?} to {:?}",
+            other.shape, self.shape
+        );
+        let offset = offset.unwrap_or_default();
+
+        // Strides of `other` over this shape, zero along broadcast dimensions
+        let mut strides = vec![0; self.shape.len()];
+        for (i, (&d, &s)) in other.shape.iter().zip(&other.strides).enumerate() {
+            if d != 1 {
+                strides[offset + i] = s;
+            }
+        }
+
+        let mut data = Vec::with_capacity(lhs.len());
+        let mut index = vec![0; self.shape.len()];
+        for &a in lhs.iter() {
+            let rhs_offset: usize = index.iter().zip(&strides).map(|(i, s)| i * s).sum();
+            data.push(f(a, other.data[rhs_offset]));
+            for axis in (0..index.len()).rev() {
+                index[axis] += 1;
+                if index[axis] < self.shape[axis] {
+                    break;
+                }
+                index[axis] = 0;
+            }
+        }
+        Self::new(&self.shape, data)
+    }
+
+    pub fn add(&self, other: &Tensor) -> Self {
+        self.zip_map(other, |a, b| a + b)
+    }
+
+    pub fn sub(&self, other: &Tensor) -> Self {
+        self.zip_map(other, |a, b| a - b)
+    }
+
+    pub fn mul(&self, other: &Tensor) -> Self {
+        self.zip_map(other, |a, b| a * b)
+    }
+
+    pub fn scale(&self, factor: f32) -> Self {
+        self.map(|x| x * factor)
+    }
+
+    pub fn silu(&self) -> Self {
+        self.map(|x| x / (1.0 + (-x).exp()))
+    }
+
+    pub fn gelu(&self) -> Self {
+        self.map(gelu)
+    }
+
+    pub fn mean(&self) -> f32 {
+        if self.numel() == 0 {
+            return 0.0;
+        }
+        (self.values().iter().map(|&x| x as f64).sum::<f64>() / self.numel() as f64) as f32
+    }
+
+    // Softmax over the last dimension
+    pub fn softmax(&self) -> Self {
+        let dim = *self.shape.last().expect("softmax of a scalar");
+        let mut data = self.values().into_owned();
+        for row in data.chunks_exact_mut(dim.max(1)) {
+            let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let mut sum = 0.0;
+            for x in row.iter_mut() {
+                *x = (*x - max).exp();
+                sum += *x;
+            }
+            for x in row.iter_mut() {
+                *x /= sum;
+            }
+        }
+        Self::new(&self.shape, data)
+    }
+
+    // Normalize over the last dimension, then apply the affine transform
+    pub fn layer_norm(&self, weight: &Tensor, bias: &Tensor, eps: f32) -> Self {
+        let dim = *self.shape.last().expect("layer_norm of a scalar");
+        assert_eq!(
+            weight.shape,
+            [dim],
+            "weight does not match {:?}",
+            self.shape
+        );
+        assert_eq!(bias.shape, [dim], "bias does not match {:?}", self.shape);
+        let (weight, bias) = (weight.values(), bias.values());
+
+        let mut data = self.values().into_owned();
+        for row in data.chunks_exact_mut(dim.max(1)) {
+            let mean = row.iter().sum::<f32>() / dim as f32;
+            let variance = row.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / dim as f32;
+            let inv_std = 1.0 / (variance + eps).sqrt();
+            for ((x, w), b) in row.iter_mut().zip(weight.iter()).zip(bias.iter()) {
+                *x = (*x - mean) * inv_std * w + b;
+            }
+        }
+        Self::new(&self.shape, data)
+    }
+
+    // Linear algebra
+
+    // Matrix product over the last two dimensions: [..., m, k] x [k, n], or
+    // [..., m, k] x [..., k, n] with matching leading dimensions
+    pub fn matmul(&self, rhs: &Tensor) -> Self {
+        let rank = self.shape.len();
+        assert!(
+            rank >= 2 && (rhs.shape.len() == 2 || rhs.shape.len() == rank),
+            "cannot multiply {:?} by {:?}",
+            self.shape,
+            rhs.shape
+        );
+        let (m, k) = (self.shape[rank - 2], self.shape[rank - 1]);
+        let (rhs_k, n) = (
+            rhs.shape[rhs.shape.len() - 2],
+            rhs.shape[rhs.shape.len() - 1],
+        );
+        let batch_shape = &self.shape[..rank - 2];
+        assert!(
+            k == rhs_k && (rhs.shape.len() == 2 || rhs.shape[..rank - 2] == *batch_shape),
+            "cannot multiply {:?} by {:?}",
+            self.shape,
+            rhs.shape
+        );
+
+        let lhs = self.values();
+        let rhs_values = rhs.values();
+        let batch: usize = batch_shape.iter().product();
+        let rhs_batch_size = if rhs.shape.len() == 2 { 0 } else { k * n };
+
+        let mut data = vec![0.0; batch * m * n];
+        for b in 0..batch {
+            matmul_into(
+                &lhs[b * m * k..(b + 1) * m * k],
+                &rhs_values[b * rhs_batch_size..b * rhs_batch_size + k * n],
+                &mut data[b * m * n..(b + 1) * m * n],
+                k,
+                n,
+            );
+        }
+
+        let mut shape = batch_shape.to_vec();
+        shape.extend([m, n]);
+        Self::new(&shape, data)
+    }
+
+    // `x W^T + b` over the last dimension, with `weight` laid out
+    // [out_features, in_features] as in PyTorch
+    pub fn linear(&self, weight: &Tensor, bias: Option<&Tensor>) -> Self {
+        let in_features = *self.shape.last().expect("linear of a scalar");
+        assert!(
+            weight.shape.len() == 2 && weight.shape[1] == in_features,
+            "cannot apply a {:?} weight to {:?}",
+            weight.shape,
+            self.shape
+        );
+        let out_features = weight.shape[0];
+        let weight_values = weight.values();
+        let bias_values = bias.map(|bias| {
+            assert_eq!(bias.shape, [out_features], "bias does not match weight");
+            bias.values()
+        });
+
+        let input = self.values();
+        let mut data = Vec::with_capacity(input.len() / in_features.max(1) * out_features);
+        for row in input.chunks_exact(in_features.max(1)) {
+            for (o, weights) in weight_values.chunks_exact(in_features.max(1)).enumerate() {
+                let dot: f32 = row.iter().zip(weights).map(|(x, w)| x * w).sum();
+                data.push(dot + bias_values.as_ref().map_or(0.0, |b| b[o]));
+            }
+        }
+
+        let mut shape = self.shape.clone();
+        *shape.last_mut().unwrap() = out_features;
+        Self::new(&shape, data)
+    }
+
+    // Image ops on NCHW tensors
+
+    // 2-D convolution with a [out_channels, in_channels, kh, kw] weight
+    pub fn conv2d(
+        &self,
+        weight: &Tensor,
+        bias: Option<&Tensor>,
+        stride: usize,
+        padding: usize,
+    ) -> Self {
+        let [n, channels, height, width] = self.shape[..] else {
+            panic!("conv2d expects an NCHW input, got {:?}", self.shape);
+        };
+        let [out_channels, in_channels, kh, kw] = weight.shape[..] else {
+            panic!("conv2d expects a 4-D weight, got {:?}", weight.shape);
+        };
+        assert_eq!(
+            in_channels, channels,
+            "cannot convolve {:?} with a {:?} weight",
+            self.shape, weight.shape
+        );
+        if let Some(bias) = bias {
+            assert_eq!(bias.shape, [out_channels], "bias does not match weight");
+        }
+
+        let out_height = (height + 2 * padding - kh) / stride + 1;
+        let out_width = (width + 2 * padding - kw) / stride + 1;
+        let input = self.values();
+        let weight_values = weight.values();
+        let bias_values = bias.map(|bias| bias.values());
+        let plane = out_height * out_width;
+
+        let mut data = vec![0.0; n * out_channels * plane];
+        for b in 0..n {
+            let image = &input[b * channels * height * width..(b + 1) * channels * height * width];
+            for oc in 0..out_channels {
+                let out =
+                    &mut data[(b * out_channels + oc) * plane..(b * out_channels + oc + 1) * plane];
+                if let Some(bias) = &bias_values {
+                    out.fill(bias[oc]);
+                }
+
+                // Accumulate one kernel tap of one input channel at a time
+                for c in 0..channels {
+                    let input_plane = &image[c * height * width..(c + 1) * height * width];
+                    for ky in 0..kh {
+                        for kx in 0..kw {
+                            let w = weight_values[((oc * channels + c) * kh + ky) * kw + kx];
+                            accumulate_tap(
+                                input_plane,
+                                out,
+                                w,
+                                (height, width),
+                                (out_height, out_width),
+                                (ky, kx),
+                                stride,
+                                padding,
+                            );
+                        }
+                    }
+                }
+            }
+        }
+
+        Self::new(&[n, out_channels, out_height, out_width], data)
+    }
+
+    // Normalize each of `groups` channel groups to zero mean and unit
+    // variance, then apply the per-channel affine transform
+    pub fn group_norm(&self, groups: usize, weight: &Tensor, bias: &Tensor, eps: f32) -> Self {
+        assert!(self.shape.len() >= 2, "group_norm of {:?}", self.shape);
+        let (n, channels) = (self.shape[0], self.shape[1]);
+        assert!(
+            channels.is_multiple_of(groups),
+            "{} channels do not split into {} groups",
+            channels,
+            groups
+        );
+        assert_eq!(weight.shape, [channels], "weight does not match channels");
+        assert_eq!(bias.shape, [channels], "bias does not match channels");
+
+        let spatial: usize = self.shape[2..].iter().product();
+        let group_size = channels / groups * spatial;
+        let weight = weight.values();
+        let bias = bias.values();
+
+        let mut data = self.values().into_owned();
+        for (g, group) in data.chunks_exact_mut(group_size).enumerate() {
+            let mean = group.iter().map(|&x| x as f64).sum::<f64>() / group_size as f64;
+            let variance = group
+                .iter()
+                .map(|&x| (x as f64 - mean).powi(2))
+                .sum::<f64>()
+                / group_size as f64;
+            let inv_std = 1.0 / (variance + eps as f64).sqrt();
+
+            for (i, channel) in group.chunks_exact_mut(spatial).enumerate() {
+                let c = (g % groups) * (channels / groups) + i;
+                for x in channel.iter_mut() {
+                    *x = ((*x as f64 - mean) * inv_std) as f32 * weight[c] + bias[c];
+                }
+            }
+        }
+        debug_assert_eq!(data.len(), n * channels * spatial);
+        Self::new(&self.shape, data)
+    }
+
+    // Nearest-neighbour upsampling of the last two dimensions
+    pub fn upsample_nearest2d(&self, factor: usize) -> Self {
+        let rank = self.shape.len();
+        assert!(rank >= 2, "cannot upsample {:?}", self.shape);
+        let (height, width) = (self.shape[rank - 2], self.shape[rank - 1]);
+        let input = self.values();
+
+        let mut data = Vec::with_capacity(input.len() * factor * factor);
+        for plane in input.chunks_exact((height * width).max(1)) {
+            for row in plane.chunks_exact(width.max(1)) {
+                let start = data.len();
+                for &x in row {
+                    data.extend(std::iter::repeat_n(x, factor));
+                }
+                for _ in 1..factor {
+                    data.extend_from_within(start..start + width * factor);
+                }
+            }
+        }
+
+        let mut shape = self.shape.clone();
+        shape[rank - 2] *= factor;
+        shape[rank - 1] *= factor;
+        Self::new(&shape, data)
+    }
+
+    // Zero-pad the last two dimensions by `bottom` rows and `right` columns
+    pub fn pad_bottom_right(&self, bottom: usize, right: usize) -> Self {
+        let rank = self.shape.len();
+        assert!(rank >= 2, "cannot pad {:?}", self.shape);
+        let (height, width) = (self.shape[rank - 2], self.shape[rank - 1]);
+        let input = self.values();
+        let padded_width = width + right;
+
+        let planes = input.len() / (height * width).max(1);
+        let mut data = Vec::with_capacity(planes * (height + bottom) * padded_width);
+        for plane in input.chunks_exact((height * width).max(1)) {
+            for row in plane.chunks_exact(width.max(1)) {
+                data.extend_from_slice(row);
+                data.resize(data.len() + right, 0.0);
+            }
+            data.resize(data.len() + bottom * padded_width, 0.0);
+        }
+
+        let mut shape = self.shape.clone();
+        shape[rank - 2] += bottom;
+        shape[rank - 1] += right;
+        Self::new(&shape, data)
+    }
+}
+
+// Exact (erf-based) GELU
+pub fn gelu(x: f32) -> f32 {
+    0.5 * x * (1.0 + erf(x / std::f32::consts::SQRT_2))
+}
+
+// Abramowitz and Stegun 7.1.26, accurate to about 1.5e-7
+fn erf(x: f32) -> f32 {
+    let sign = x.signum();
+    let x = x.abs() as f64;
+    let t = 1.0 / (1.0 + 0.327_591_1 * x);
+    let poly = t
+        * (0.254_829_592
+            + t * (-0.284_496_736
+                + t * (1.421_413_741 + t * (-1.453_152_027 + t * 1.061_405_429))));
+    sign * (1.0 - poly * (-x * x).exp()) as f32
+}
+
+// out[i] += a[i] x b for each row of a [m, k] by [k, n] product
+// Scaled dot-product attention of [batch, tokens, channels] queries over
+// [batch, context_tokens, channels] keys and values, split into `heads`
+// heads and processed `chunk` query rows at a time
+pub fn multi_head_attention(
+    query: &Tensor,
+    key: &Tensor,
+    value: &Tensor,
+    heads: usize,
+    chunk: usize,
+) -> Tensor {
+    let [batch, tokens, channels] = query.shape()[..] else {
+        panic!("attention query has shape {:?}", query.shape());
+    };
+    let context_tokens = key.dim(1);
+    let head_dim = channels / heads;
+    let scale = 1.0 / (head_dim as f32).sqrt();
+
+    // [batch * heads, tokens, head_dim]
+    let split = |x: &Tensor, tokens: usize| {
+        x.clone()
+            .reshape(&[batch, tokens, heads, head_dim])
+            .permute(&[0, 2, 1, 3])
+            .reshape(&[batch * heads, tokens, head_dim])
+    };
+    let (query, key, value) = (
+        split(query, tokens),
+        split(key, context_tokens),
+        split(value, context_tokens),
+    );
+
+    let mut output = Vec::with_capacity(batch * tokens * channels);
+    for bh in 0..batch * heads {
+        let query = query.narrow(0, bh, 1).reshape(&[tokens, head_dim]);
+        let key_t = key
+            .narrow(0, bh, 1)
+            .reshape(&[context_tokens, head_dim])
+            .transpose(0, 1)
+            .contiguous();
+        let value = value.narrow(0, bh, 1).reshape(&[context_tokens, head_dim]);
+
+        for start in (0..tokens).step_by(chunk.max(1)) {
+            let rows = chunk.min(tokens - start);
+            let scores = query
+                .narrow(0, start, rows)
+                .matmul(&key_t)
+                .scale(scale)
+                .softmax();
+            output.extend(scores.matmul(&value).into_values());
+        }
+    }
+
+    Tensor::new(&[batch, heads, tokens, head_dim], output)
+        .permute(&[0, 2, 1, 3])
+        .reshape(&[batch, tokens, channels])
+}
+
+fn matmul_into(a: &[f32], b: &[f32], out: &mut [f32], k: usize, n: usize) {
+    for (a_row, out_row) in a.chunks_exact(k.max(1)).zip(out.chunks_exact_mut(n.max(1))) {
+        for (&a, b_row) in a_row.iter().zip(b.chunks_exact(n.max(1))) {
+            for (o, &b) in out_row.iter_mut().zip(b_row) {
+                *o += a * b;
+            }
+        }
+    }
+}
+
+// Add `weight` times the input shifted by kernel tap (ky, kx) to `out`
+#[allow(clippy::too_many_arguments)]
+fn accumulate_tap(
+    input: &[f32],
+    out: &mut [f32],
+    weight: f32,
+    (height, width): (usize, usize),
+    (out_height, out_width): (usize, usize),
+    (ky, kx): (usize, usize),
+    stride: usize,
+    padding: usize,
+) {
+    for oy in 0..out_height {
+        let Some(iy) = (oy * stride + ky)
+            .checked_sub(padding)
+            .filter(|&y| y < height)
+        else {
+            continue;
+        };
+        let input_row = &input[iy * width..(iy + 1) * width];
+        let out_row = &mut out[oy * out_width..(oy + 1) * out_width];
+
+        // Output columns whose input column lies inside the image
+        let first = padding.saturating_sub(kx).div_ceil(stride);
+        let last = ((width + padding).saturating_sub(kx))
+            .div_ceil(stride)
+            .min(out_width);
+        for ox in first..last {
+            out_row[ox] += weight * input_row[ox * stride + kx - padding];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::TorchGenerator;
+
+    fn arange(shape: &[usize]) -> Tensor {
+        Tensor::new(
+            shape,
+            (0..shape.iter().product()).map(|v| v as f32).collect(),
+        )
+    }
+
+    #[test]
+    fn transposes_without_copying_until_read() {
+        let t = arange(&[2, 3]).transpose(0, 1);
+        assert_eq!(t.shape(), [3, 2]);
+        assert_eq!(t.strides(), [1, 3]);
+        assert_eq!(*t.values(), [0.0, 3.0, 1.0, 4.0, 2.0, 5.0]);
+        assert_eq!(t.narrow(0, 1, 2).into_values(), [1.0, 4.0, 2.0, 5.0]);
+    }
+
+    #[test]
+    fn matmul_and_linear_agree() {
+        let x = arange(&[2, 2, 3]);
+        let w = arange(&[4, 3]).scale(0.5);
+        let product = x.matmul(&w.clone().transpose(0, 1));
+        assert_eq!(product.shape(), [2, 2, 4]);
+        assert_eq!(product.values()[..4], [2.5, 7.0, 11.5, 16.0]);
+
+        let bias = Tensor::full(&[4], 1.0);
+        let expected = product.add(&bias);
+        assert_eq!(x.linear(&w, Some(&bias)), expected);
+    }
+
+    #[test]
+    fn conv2d_matches_direct_sum() {
+        let input = arange(&[1, 2, 4, 5]).scale(0.1);
+        let weight = arange(&[3, 2, 3, 3]).map(|v| (v * 0.7).sin());
+        let bias = Tensor::new(&[3], vec![0.5, -1.0, 2.0]);
+
+        for (stride, padding) in [(1, 1), (2, 1), (1, 0), (2, 2)] {
+            let out = input.conv2d(&weight, Some(&bias), stride, padding);
+            let [_, _, oh, ow] = out.shape()[..] else {
+                unreachable!()
+            };
+
+            let (x, w) = (input.values(), weight.values());
+            for oc in 0..3 {
+                for oy in 0..oh {
+                    for ox in 0..ow {
+                        let mut expected = bias.values()[oc];
+                        for c in 0..2 {
+                            for ky in 0..3 {
+                                for kx in 0..3 {
+                                    let iy = (oy * stride + ky) as isize - padding as isize;
+                                    let ix = (ox * stride + kx) as isize - padding as isize;
+                                    if (0..4).contains(&iy) && (0..5).contains(&ix) {
+                                        expected += x[(c * 4 + iy as usize) * 5 + ix as usize]
+                                            * w[((oc * 2 + c) * 3 + ky) * 3 + kx];
+                                    }
+                                }
+                            }
+                        }
+                        let actual = out.values()[(oc * oh + oy) * ow + ox];
+                        assert!(
+                            (actual - expected).abs() < 1e-4,
+                            "{} != {}",
+                            actual,
+                            expected
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn group_norm_normalizes_each_group() {
+        let x = arange(&[2, 4, 3]);
+        let out = x.group_norm(2, &Tensor::full(&[4], 2.0), &Tensor::full(&[4], 1.0), 0.0);
+        for group in out.values().chunks(6) {
+            let mean = group.iter().sum::<f32>() / 6.0;
+            let variance = group.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / 6.0;
+            assert!((mean - 1.0).abs() < 1e-5);
+            assert!((variance - 4.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn pads_bottom_and_right_with_zeros() {
+        let padded = arange(&[1, 2, 2]).pad_bottom_right(1, 1);
+        assert_eq!(padded.shape(), [1, 3, 3]);
+        assert_eq!(
+            padded.values()[..],
+            [0.0, 1.0, 0.0, 2.0, 3.0, 0.0, 0.0, 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn shape_ops() {
+        let x = arange(&[1, 2, 2]);
+        assert_eq!(
+            x.upsample_nearest2d(2).into_values()[..8],
+            [0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 1.0]
+        );
+
+        let joined = Tensor::concat(&[&x, &x.scale(-1.0)], 1);
+        assert_eq!(joined.shape(), [1, 4, 2]);
+        assert_eq!(joined.narrow(1, 2, 1).into_values(), [-0.0, -1.0]);
+
+        let softmax = Tensor::new(&[2, 2], vec![0.0, 0.0, 1.0, 1.0 + 2f32.ln()]).softmax();
+        assert_eq!(*softmax.values(), [0.5, 0.5, 1.0 / 3.0, 2.0 / 3.0]);
+
+        let bias = Tensor::new(&[2, 1, 1], vec![10.0, 20.0]);
+        assert_eq!(
+            arange(&[1, 2, 1, 2]).add(&bias).into_values(),
+            [10.0, 11.0, 22.0, 23.0]
+        );
+    }
+
+    #[test]
+    fn chunked_attention_matches_naive_softmax() {
+        let mut generator = TorchGenerator::new(1);
+        let query = Tensor::new(&[1, 5, 4], generator.randn(20));
+        let key = Tensor::new(&[1, 3, 4], generator.randn(12));
+        let value = Tensor::new(&[1, 3, 4], generator.randn(12));
+
+        let output = multi_head_attention(&query, &key, &value, 2, 2);
+        let (q, k, v) = (query.values(), key.values(), value.values());
+        for token in 0..5 {
+            for head in 0..2 {
+                let columns = head * 2..head * 2 + 2;
+                let scores: Vec<f32> = (0..3)
+                    .map(|j| {
+                        columns
+                            .clone()
+                            .map(|c| q[token * 4 + c] * k[j * 4 + c])
+                            .sum::<f32>()
+                            / 2f32.sqrt()
+                    })
+                    .collect();
+                let total: f32 = scores.iter().map(|s| s.exp()).sum();
+                for c in columns.clone() {
+                    let expected: f32 =
+                        (0..3).map(|j| scores[j].exp() / total * v[j * 4 + c]).sum();
+                    assert!((output.values()[token * 4 + c] - expected).abs() < 1e-5);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,421 @@
+// Tensors of a safetensors file read on demand from stable memory
+//
+// Model weights do not fit in the wasm heap, so only the header is parsed up
+// front. A `TensorView` locates a tensor without reading it; reads then page
+// the requested elements or rows into a caller-owned `PageBuffer`, which is
+// reused from one read to the next so a forward pass holds one slice of a
+// layer at a time rather than the whole model.
+//
+// `Weights` applies linear and convolution layers a block of output rows at
+// a time, so no read pages in more than PAGE_ELEMENTS values of a weight
+// however large the layer is.
+
+use crate::model_store::Extent;
+use crate::safetensors::{self, Dtype, SafetensorsHeader};
+use crate::tensor::Tensor;
+use ic_stable_structures::Memory;
+use std::collections::HashMap;
+use std::ops::Range;
+
+// Most weight values paged onto the heap by one read, 4 MiB as f32
+const PAGE_ELEMENTS: usize = 1 << 20;
+
+#[derive(Clone, Debug)]
+pub struct TensorView {
+    pub dtype: Dtype,
+    pub shape: Vec<usize>,
+    // Absolute offset of the tensor data in the backing memory
+    offset: u64,
+}
+
+impl TensorView {
+    pub fn element_count(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    // Elements per row of the first dimension
+    pub fn row_len(&self) -> usize {
+        self.shape.iter().skip(1).product()
+    }
+}
+
+// Scratch space for paged reads
+#[derive(Default)]
+pub struct PageBuffer {
+    bytes: Vec<u8>,
+    values: Vec<f32>,
+}
+
+#[derive(Clone)]
+pub struct TensorStore<M: Memory> {
+    memory: M,
+    base: u64,
+    header: SafetensorsHeader,
+}
+
+impl<M: Memory> TensorStore<M> {
+    // Parse the header of the safetensors file stored at `extent`
+    pub fn open(memory: M, extent: Extent) -> Result<Self, String> {
+        let mut len_bytes = [0u8; 8];
+        if extent.size < len_bytes.len() as u64 {
+            return Err("Safetensors file is truncated".to_string());
+        }
+        memory.read(extent.offset, &mut len_bytes);
+
+        let header_len = SafetensorsHeader::header_len(&len_bytes)?;
+        if 8 + header_len > extent.size {
+            return Err("Safetensors header is truncated".to_string());
+        }
+        let mut prefix = vec![0; 8 + header_len as usize];
+        memory.read(extent.offset, &mut prefix);
+        let header = SafetensorsHeader::parse(&prefix, extent.size)?;
+
+        Ok(Self {
+            memory,
+            base: extent.offset,
+            header,
+        })
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, String> {
+        &self.header.metadata
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.header.tensors.contains_key(name)
+    }
+
+    pub fn view(&self, name: &str) -> Result<TensorView, String> {
+        let info = self.header.tensor(name)?;
+        Ok(TensorView {
+            dtype: info.dtype,
+            shape: info.shape.clone(),
+            offset: self.base + self.header.data_start + info.start,
+        })
+    }
+
+    // Size of dimension `index` of `name`, for reading an architecture off
+    // the weights
+    pub fn dim(&self, name: &str, index: usize) -> Result<usize, String> {
+        let shape = self.view(name)?.shape;
+        shape.get(index).copied().ok_or_else(|| {
+            format!(
+                "Tensor {} has shape {:?}, without dimension {}",
+                name, shape, index
+            )
+        })
+    }
+
+    // View of `name`, checking it has the expected shape
+    pub fn view_with_shape(&self, name: &str, shape: &[usize]) -> Result<TensorView, String> {
+        let view = self.view(name)?;
+        if view.shape != shape {
+            return Err(format!(
+                "Tensor {} has shape {:?}, expected {:?}",
+                name, view.shape, shape
+            ));
+        }
+        Ok(view)
+    }
+
+    // Elements `range` of a tensor, in row-major order
+    pub fn read_range<'a>(
+        &self,
+        view: &TensorView,
+        range: Range<usize>,
+        buffer: &'a mut PageBuffer,
+    ) -> Result<&'a [f32], String> {
+        if range.start > range.end || range.end > view.element_count() {
+            return Err(format!(
+                "Elements {:?} are out of bounds for shape {:?}",
+                range, view.shape
+            ));
+        }
+
+        let size = view.dtype.size();
+        buffer.bytes.resize(range.len() * size, 0);
+        self.memory
+            .read(view.offset + (range.start * size) as u64, &mut buffer.bytes);
+        safetensors::convert_into(view.dtype, &buffer.bytes, &mut buffer.values);
+        Ok(&buffer.values)
+    }
+
+    // Rows `rows` of the first dimension of a tensor
+    pub fn read_rows<'a>(
+        &self,
+        view: &TensorView,
+        rows: Range<usize>,
+        buffer: &'a mut PageBuffer,
+    ) -> Result<&'a [f32], String> {
+        let row_len = view.row_len();
+        self.read_range(view, rows.start * row_len..rows.end * row_len, buffer)
+    }
+
+    // A whole tensor copied onto the heap, for weights small enough to keep
+    pub fn load(&self, name: &str, shape: &[usize]) -> Result<Vec<f32>, String> {
+        let view = self.view_with_shape(name, shape)?;
+        let mut buffer = PageBuffer::default();
+        self.read_range(&view, 0..view.element_count(), &mut buffer)?;
+        Ok(buffer.values)
+    }
+}
+
+// Layer weights read for one forward pass through a shared page buffer
+pub struct Weights<'a, M: Memory> {
+    tensors: &'a TensorStore<M>,
+    buffer: PageBuffer,
+    page_elements: usize,
+}
+
+impl<'a, M: Memory> Weights<'a, M> {
+    pub fn new(tensors: &'a TensorStore<M>) -> Self {
+        Self {
+            tensors,
+            buffer: PageBuffer::default(),
+            page_elements: PAGE_ELEMENTS,
+        }
+    }
+
+    // A whole tensor that fits in one page, such as a bias or norm weight
+    pub fn get(&mut self, name: &str) -> Result<Tensor, String> {
+        let view = self.tensors.view(name)?;
+        if view.element_count() > self.page_elements {
+            return Err(format!(
+                "Tensor {} has {} elements, more than one page of {}",
+                name,
+                view.element_count(),
+                self.page_elements
+            ));
+        }
+        let values = self
+            .tensors
+            .read_range(&view, 0..view.element_count(), &mut self.buffer)?;
+        Ok(Tensor::new(&view.shape, values.to_vec()))
+    }
+
+    pub fn linear(&mut self, name: &str, input: &Tensor) -> Result<Tensor, String> {
+        let bias = self.get(&format!("{}.bias", name))?;
+        self.paged(&format!("{}.weight", name), Some(&bias), |weight, bias| {
+            input.linear(weight, bias)
+        })
+    }
+
+    // A linear layer without bias, such as an attention projection
+    pub fn project(&mut self, name: &str, input: &Tensor) -> Result<Tensor, String> {
+        self.paged(&format!("{}.weight", name), None, |weight, _| {
+            input.linear(weight, None)
+        })
+    }
+
+    // Convolution with "same" padding for odd kernels
+    pub fn conv(&mut self, name: &str, input: &Tensor, stride: usize) -> Result<Tensor, String> {
+        let kernel = self.tensors.dim(&format!("{}.weight", name), 2)?;
+        self.conv_padded(name, input, stride, kernel / 2, true)
+    }
+
+    pub fn conv_padded(
+        &mut self,
+        name: &str,
+        input: &Tensor,
+        stride: usize,
+        padding: usize,
+        bias: bool,
+    ) -> Result<Tensor, String> {
+        let bias = if bias {
+            Some(self.get(&format!("{}.bias", name))?)
+        } else {
+            None
+        };
+        self.paged(
+            &format!("{}.weight", name),
+            bias.as_ref(),
+            |weight, bias| input.conv2d(weight, bias, stride, padding),
+        )
+    }
+
+    // Apply `layer` to blocks of the output rows of `weight_name`, whose
+    // first dimension is the output features or channels, and join the
+    // outputs along that dimension (the last for linear layers, the second
+    // for NCHW convolutions)
+    fn paged(
+        &mut self,
+        weight_name: &str,
+        bias: Option<&Tensor>,
+        layer: impl Fn(&Tensor, Option<&Tensor>) -> Tensor,
+    ) -> Result<Tensor, String> {
+        let view = self.tensors.view(weight_name)?;
+        let rows = *view
+            .shape
+            .first()
+            .ok_or_else(|| format!("Tensor {} has no rows", weight_name))?;
+        let rows_per_page = (self.page_elements / view.row_len().max(1)).clamp(1, rows.max(1));
+        let bias_values = bias.map(|bias| bias.values());
+        if bias.is_some_and(|bias| bias.shape() != [rows]) {
+            return Err(format!("Bias of {} does not match its rows", weight_name));
+        }
+
+        let mut output: Option<(Vec<usize>, Vec<f32>)> = None;
+        for start in (0..rows).step_by(rows_per_page) {
+            let end = (start + rows_per_page).min(rows);
+            let values = self
+                .tensors
+                .read_rows(&view, start..end, &mut self.buffer)?;
+            let mut shape = view.shape.clone();
+            shape[0] = end - start;
+            let weight = Tensor::new(&shape, values.to_vec());
+            let bias = bias_values
+                .as_ref()
+                .map(|bias| Tensor::new(&[end - start], bias[start..end].to_vec()));
+            let block = layer(&weight, bias.as_ref());
+            if end - start == rows {
+                return Ok(block);
+            }
+
+            // Output rows sit on the last axis of a linear layer's output and
+            // on axis 1 of a convolution's
+            let axis = if view.shape.len() == 2 {
+                block.shape().len() - 1
+            } else {
+                1
+            };
+            let (shape, data) = output.get_or_insert_with(|| {
+                let mut shape = block.shape().to_vec();
+                shape[axis] = rows;
+                let len = shape.iter().product();
+                (shape, vec![0.0; len])
+            });
+            let inner: usize = shape[axis + 1..].iter().product();
+            let block_values = block.values();
+            let segment = (end - start) * inner;
+            for (i, chunk) in block_values.chunks_exact(segment).enumerate() {
+                let offset = i * rows * inner + start * inner;
+                data[offset..offset + segment].copy_from_slice(chunk);
+            }
+        }
+        let (shape, data) = output.ok_or_else(|| format!("Tensor {} is empty", weight_name))?;
+        Ok(Tensor::new(&shape, data))
+    }
+
+    pub fn group_norm(
+        &mut self,
+        name: &str,
+        input: &Tensor,
+        groups: usize,
+        eps: f32,
+    ) -> Result<Tensor, String> {
+        let weight = self.get(&format!("{}.weight", name))?;
+        let bias = self.get(&format!("{}.bias", name))?;
+        Ok(input.group_norm(groups, &weight, &bias, eps))
+    }
+
+    pub fn layer_norm(&mut self, name: &str, input: &Tensor, eps: f32) -> Result<Tensor, String> {
+        let weight = self.get(&format!("{}.weight", name))?;
+        let bias = self.get(&format!("{}.bias", name))?;
+        Ok(input.layer_norm(&weight, &bias, eps))
+    }
+}
+
+// Store over an in-memory safetensors file
+#[cfg(test)]
+pub fn from_bytes(bytes: &[u8]) -> TensorStore<ic_stable_structures::VectorMemory> {
+    let memory = ic_stable_structures::VectorMemory::default();
+    memory.grow((bytes.len() as u64).div_ceil(65536) + 1);
+    // Place the file past the start to exercise the base offset
+    memory.write(100, bytes);
+    let extent = Extent {
+        offset: 100,
+        size: bytes.len() as u64,
+    };
+    TensorStore::open(memory, extent).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_rows_through_a_reused_buffer() {
+        let values: Vec<f32> = (0..12).map(|v| v as f32).collect();
+        let bytes = safetensors::serialize(&[
+            ("bias".to_string(), vec![2], vec![-1.0, -2.0]),
+            ("weight".to_string(), vec![4, 3], values),
+        ]);
+        let store = from_bytes(&bytes);
+        let view = store.view("weight").unwrap();
+
+        let mut buffer = PageBuffer::default();
+        assert_eq!(
+            store.read_rows(&view, 1..3, &mut buffer).unwrap(),
+            [3.0, 4.0, 5.0, 6.0, 7.0, 8.0]
+        );
+        assert_eq!(
+            store.read_range(&view, 11..12, &mut buffer).unwrap(),
+            [11.0]
+        );
+        assert!(store.read_rows(&view, 3..5, &mut buffer).is_err());
+
+        assert_eq!(store.load("bias", &[2]).unwrap(), [-1.0, -2.0]);
+        assert!(store.load("bias", &[1, 2]).is_err());
+        assert!(store.view("missing").is_err());
+
+        assert_eq!(store.dim("weight", 1).unwrap(), 3);
+        assert!(store.dim("weight", 2).is_err());
+    }
+
+    #[test]
+    fn paged_layers_match_whole_weights() {
+        let weight: Vec<f32> = (0..5 * 6).map(|i| ((i * 7) % 13) as f32 - 6.0).collect();
+        let bias = vec![0.5, -1.0, 2.0, 0.0, 1.5];
+        let bytes = safetensors::serialize(&[
+            ("fc.weight".to_string(), vec![5, 6], weight.clone()),
+            ("fc.bias".to_string(), vec![5], bias.clone()),
+        ]);
+        let store = from_bytes(&bytes);
+        // Two rows per page, so the five rows take three reads
+        let mut weights = Weights {
+            tensors: &store,
+            buffer: PageBuffer::default(),
+            page_elements: 12,
+        };
+        assert!(weights.get("fc.weight").is_err());
+
+        let input = Tensor::new(&[2, 6], (0..12).map(|i| (i % 5) as f32 * 0.25).collect());
+        let weight = Tensor::new(&[5, 6], weight);
+        let expected = input.linear(&weight, Some(&Tensor::new(&[5], bias)));
+        assert_eq!(weights.linear("fc", &input).unwrap(), expected);
+        assert_eq!(
+            weights.project("fc", &input).unwrap(),
+            input.linear(&weight, None)
+        );
+        assert!(weights.buffer.values.len() <= 12);
+    }
+
+    #[test]
+    fn paged_convolutions_join_channels() {
+        let weight: Vec<f32> = (0..3 * 2 * 9).map(|i| (i % 7) as f32 - 3.0).collect();
+        let bias = vec![1.0, 0.0, -1.0];
+        let bytes = safetensors::serialize(&[
+            ("conv.weight".to_string(), vec![3, 2, 3, 3], weight.clone()),
+            ("conv.bias".to_string(), vec![3], bias.clone()),
+        ]);
+        let store = from_bytes(&bytes);
+        // One output channel per page
+        let mut weights = Weights {
+            tensors: &store,
+            buffer: PageBuffer::default(),
+            page_elements: 18,
+        };
+
+        let input = Tensor::new(&[2, 2, 5, 5], (0..100).map(|i| i as f32 * 0.1).collect());
+        let weight = Tensor::new(&[3, 2, 3, 3], weight);
+        let bias = Tensor::new(&[3], bias);
+        assert_eq!(
+            weights.conv("conv", &input, 1).unwrap(),
+            input.conv2d(&weight, Some(&bias), 1, 1)
+        );
+        assert_eq!(
+            weights.conv_padded("conv", &input, 2, 0, false).unwrap(),
+            input.conv2d(&weight, None, 2, 0)
+        );
+    }
+}
@@ -0,0 +1,429 @@
+// Prompt text encoders
+//
+// ClipTextEncoder is the CLIP text transformer (CLIPTextModel) Stable
+// Diffusion conditions on, loaded from a safetensors file in the
+// transformers/diffusers layout (`text_model.*` tensor names). Until one is
+// uploaded the pipeline falls back to MockTextEncoder's deterministic
+// embeddings.
+
+use crate::tensor::{self, Tensor};
+use crate::tensor_store::{PageBuffer, TensorStore, Weights};
+use ic_stable_structures::Memory;
+
+const LAYER_NORM_EPS: f32 = 1e-5;
+
+// CLIP text models use 64-dimensional attention heads
+const HEAD_DIM: usize = 64;
+
+#[derive(Clone)]
+pub enum TextEncoder {
+    Mock(MockTextEncoder),
+    Clip(Box<ClipTextEncoder<crate::Memory>>),
+}
+
+impl TextEncoder {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TextEncoder::Mock(_) => "mock",
+            TextEncoder::Clip(_) => "clip",
+        }
+    }
+
+    // Embeddings `[tokens.len() x embedding_dim]` for a padded token sequence
+    pub fn encode(&self, tokens: &[u32]) -> Result<Vec<f32>, String> {
+        match self {
+            TextEncoder::Mock(encoder) => Ok(encoder.encode(tokens)),
+            TextEncoder::Clip(encoder) => encoder.encode(tokens),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MockTextEncoder {
+    embedding_dim: usize,
+}
+
+impl MockTextEncoder {
+    pub fn new() -> Self {
+        Self { embedding_dim: 768 }
+    }
+
+    fn encode(&self, tokens: &[u32]) -> Vec<f32> {
+        // Simplified text encoding - returns mock embeddings
+        let mut embeddings = Vec::with_capacity(tokens.len() * self.embedding_dim);
+
+        for &token in tokens {
+            for i in 0..self.embedding_dim {
+                let value = (token as f32 + i as f32) / 1000.0;
+                embeddings.push(value.sin()); // Simple deterministic "embedding"
+            }
+        }
+
+        embeddings
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Activation {
+    // x * sigmoid(1.702 x), used by OpenAI CLIP (SD 1.x)
+    QuickGelu,
+    // Exact GELU, used by OpenCLIP (SD 2.x)
+    Gelu,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClipTextConfig {
+    pub vocab_size: usize,
+    pub hidden_size: usize,
+    pub intermediate_size: usize,
+    pub num_layers: usize,
+    pub num_heads: usize,
+    pub max_positions: usize,
+    pub activation: Activation,
+}
+
+impl ClipTextConfig {
+    // Every tensor of the model with its shape, without the `text_model.`
+    // prefix
+    pub fn shapes(&self) -> Vec<(String, Vec<usize>)> {
+        let (hidden, intermediate) = (self.hidden_size, self.intermediate_size);
+        let mut shapes = vec![
+            (
+                "embeddings.token_embedding.weight".to_string(),
+                vec![self.vocab_size, hidden],
+            ),
+            (
+                "embeddings.position_embedding.weight".to_string(),
+                vec![self.max_positions, hidden],
+            ),
+        ];
+        let mut add = |name: String, out_features: usize, in_features: Option<usize>| {
+            if let Some(in_features) = in_features {
+                shapes.push((format!("{}.weight", name), vec![out_features, in_features]));
+            } else {
+                shapes.push((format!("{}.weight", name), vec![out_features]));
+            }
+            shapes.push((format!("{}.bias", name), vec![out_features]));
+        };
+        for i in 0..self.num_layers {
+            let prefix = format!("encoder.layers.{}", i);
+            add(format!("{}.layer_norm1", prefix), hidden, None);
+            for proj in ["q_proj", "k_proj", "v_proj", "out_proj"] {
+                add(
+                    format!("{}.self_attn.{}", prefix, proj),
+                    hidden,
+                    Some(hidden),
+                );
+            }
+            add(format!("{}.layer_norm2", prefix), hidden, None);
+            add(format!("{}.mlp.fc1", prefix), intermediate, Some(hidden));
+            add(format!("{}.mlp.fc2", prefix), hidden, Some(intermediate));
+        }
+        add("final_layer_norm".to_string(), hidden, None);
+        shapes
+    }
+}
+
+#[derive(Clone)]
+pub struct ClipTextEncoder<M: Memory> {
+    config: ClipTextConfig,
+    tensors: TensorStore<M>,
+}
+
+impl<M: Memory> ClipTextEncoder<M> {
+    // Check an uploaded safetensors file against the architecture its
+    // embeddings imply. CLIP ViT-L/14's text model alone is 123M parameters,
+    // so like the UNet the weights stay in stable memory and are paged in
+    // during each forward pass.
+    pub fn from_tensors(tensors: TensorStore<M>) -> Result<Self, String> {
+        let token_shape = tensors
+            .view("text_model.embeddings.token_embedding.weight")?
+            .shape;
+        let position_shape = tensors
+            .view("text_model.embeddings.position_embedding.weight")?
+            .shape;
+        let (vocab_size, hidden_size) = match token_shape[..] {
+            [vocab_size, hidden_size] => (vocab_size, hidden_size),
+            _ => return Err("Token embedding must be two-dimensional".to_string()),
+        };
+        let max_positions = position_shape.first().copied().unwrap_or(0);
+        let intermediate_size = tensors
+            .view("text_model.encoder.layers.0.mlp.fc1.weight")?
+            .shape
+            .first()
+            .copied()
+            .unwrap_or(0);
+        let num_layers = (0..)
+            .take_while(|i| {
+                tensors.contains(&format!(
+                    "text_model.encoder.layers.{}.layer_norm1.weight",
+                    i
+                ))
+            })
+            .count();
+
+        // OpenCLIP models are the wider ones; metadata can say otherwise
+        let activation = match tensors.metadata().get("hidden_act").map(String::as_str) {
+            Some("gelu") => Activation::Gelu,
+            Some("quick_gelu") => Activation::QuickGelu,
+            _ if hidden_size == 768 => Activation::QuickGelu,
+            _ => Activation::Gelu,
+        };
+
+        if hidden_size % HEAD_DIM != 0 {
+            return Err(format!(
+                "Hidden size {} is not a multiple of {}",
+                hidden_size, HEAD_DIM
+            ));
+        }
+        let config = ClipTextConfig {
+            vocab_size,
+            hidden_size,
+            intermediate_size,
+            num_layers,
+            num_heads: hidden_size / HEAD_DIM,
+            max_positions,
+            activation,
+        };
+        for (name, shape) in config.shapes() {
+            tensors.view_with_shape(&format!("text_model.{}", name), &shape)?;
+        }
+
+        Ok(Self { config, tensors })
+    }
+
+    pub fn config(&self) -> &ClipTextConfig {
+        &self.config
+    }
+
+    // Last hidden state `[tokens.len() x hidden_size]` after the final layer
+    // norm, as Stable Diffusion conditions on
+    fn encode(&self, tokens: &[u32]) -> Result<Vec<f32>, String> {
+        let dim = self.config.hidden_size;
+        if tokens.len() > self.config.max_positions {
+            return Err(format!(
+                "Text encoder accepts at most {} tokens",
+                self.config.max_positions
+            ));
+        }
+
+        // Only the embedding rows of the prompt's tokens are read
+        let token_embedding = self
+            .tensors
+            .view("text_model.embeddings.token_embedding.weight")?;
+        let position_embedding = self
+            .tensors
+            .view("text_model.embeddings.position_embedding.weight")?;
+        let mut buffer = PageBuffer::default();
+        let mut hidden = self
+            .tensors
+            .read_rows(&position_embedding, 0..tokens.len(), &mut buffer)?
+            .to_vec();
+        for (position, &token) in tokens.iter().enumerate() {
+            let token = token as usize;
+            if token >= self.config.vocab_size {
+                return Err(format!("Token id {} is outside the vocabulary", token));
+            }
+            let row = self
+                .tensors
+                .read_rows(&token_embedding, token..token + 1, &mut buffer)?;
+            add_in_place(&mut hidden[position * dim..(position + 1) * dim], row);
+        }
+        let mut hidden = Tensor::new(&[tokens.len(), dim], hidden);
+
+        let mut weights = Weights::new(&self.tensors);
+        let w = &mut weights;
+        for i in 0..self.config.num_layers {
+            let prefix = format!("text_model.encoder.layers.{}", i);
+            let normed =
+                w.layer_norm(&format!("{}.layer_norm1", prefix), &hidden, LAYER_NORM_EPS)?;
+            let attention = self.self_attention(w, &prefix, &normed)?;
+            hidden = hidden.add(&attention);
+
+            let normed =
+                w.layer_norm(&format!("{}.layer_norm2", prefix), &hidden, LAYER_NORM_EPS)?;
+            let mlp = w
+                .linear(&format!("{}.mlp.fc1", prefix), &normed)?
+                .map(|x| self.activate(x));
+            hidden = hidden.add(&w.linear(&format!("{}.mlp.fc2", prefix), &mlp)?);
+        }
+
+        let output = w.layer_norm("text_model.final_layer_norm", &hidden, LAYER_NORM_EPS)?;
+        Ok(output.into_values())
+    }
+
+    // Multi-head self-attention with a causal mask: each token attends only
+    // to itself and the tokens before it
+    fn self_attention(
+        &self,
+        w: &mut Weights<M>,
+        prefix: &str,
+        input: &Tensor,
+    ) -> Result<Tensor, String> {
+        let dim = self.config.hidden_size;
+        let seq_len = input.dim(0);
+        let scale = 1.0 / (HEAD_DIM as f32).sqrt();
+
+        let query = w.linear(&format!("{}.self_attn.q_proj", prefix), input)?;
+        let key = w.linear(&format!("{}.self_attn.k_proj", prefix), input)?;
+        let value = w.linear(&format!("{}.self_attn.v_proj", prefix), input)?;
+        let (query, key, value) = (query.values(), key.values(), value.values());
+
+        let mut context = vec![0.0; seq_len * dim];
+        let mut scores = vec![0.0; seq_len];
+        for head in 0..self.config.num_heads {
+            let columns = head * HEAD_DIM..(head + 1) * HEAD_DIM;
+            for i in 0..seq_len {
+                let q = &query[i * dim..][columns.clone()];
+                for (j, score) in scores.iter_mut().enumerate().take(i + 1) {
+                    let k = &key[j * dim..][columns.clone()];
+                    *score = q.iter().zip(k).map(|(a, b)| a * b).sum::<f32>() * scale;
+                }
+                softmax_in_place(&mut scores[..=i]);
+
+                let out = &mut context[i * dim..][columns.clone()];
+                for (j, &p) in scores[..=i].iter().enumerate() {
+                    let v = &value[j * dim..][columns.clone()];
+                    for (o, v) in out.iter_mut().zip(v) {
+                        *o += p * v;
+                    }
+                }
+            }
+        }
+
+        let context = Tensor::new(&[seq_len, dim], context);
+        w.linear(&format!("{}.self_attn.out_proj", prefix), &context)
+    }
+
+    fn activate(&self, x: f32) -> f32 {
+        match self.config.activation {
+            Activation::QuickGelu => x / (1.0 + (-1.702 * x).exp()),
+            Activation::Gelu => tensor::gelu(x),
+        }
+    }
+}
+
+fn add_in_place(target: &mut [f32], other: &[f32]) {
+    for (t, o) in target.iter_mut().zip(other) {
+        *t += o;
+    }
+}
+
+fn softmax_in_place(values: &mut [f32]) {
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let mut sum = 0.0;
+    for v in values.iter_mut() {
+        *v = (*v - max).exp();
+        sum += *v;
+    }
+    for v in values.iter_mut() {
+        *v /= sum;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::TorchGenerator;
+    use crate::{safetensors, tensor_store};
+
+    // A randomly initialized two-layer model with one attention head
+    fn tiny_model() -> Vec<u8> {
+        let (vocab, hidden, intermediate, positions) = (10, HEAD_DIM, 2 * HEAD_DIM, 8);
+        let mut generator = TorchGenerator::new(7);
+        let mut tensors = Vec::new();
+        let mut add = |name: String, shape: Vec<usize>| {
+            let size = shape.iter().product();
+            let values = generator.randn(size).iter().map(|v| v * 0.1).collect();
+            tensors.push((format!("text_model.{}", name), shape, values));
+        };
+
+        add(
+            "embeddings.token_embedding.weight".into(),
+            vec![vocab, hidden],
+        );
+        add(
+            "embeddings.position_embedding.weight".into(),
+            vec![positions, hidden],
+        );
+        for i in 0..2 {
+            let prefix = format!("encoder.layers.{}", i);
+            for norm in ["layer_norm1", "layer_norm2"] {
+                add(format!("{}.{}.weight", prefix, norm), vec![hidden]);
+                add(format!("{}.{}.bias", prefix, norm), vec![hidden]);
+            }
+            for proj in ["q_proj", "k_proj", "v_proj", "out_proj"] {
+                add(
+                    format!("{}.self_attn.{}.weight", prefix, proj),
+                    vec![hidden, hidden],
+                );
+                add(format!("{}.self_attn.{}.bias", prefix, proj), vec![hidden]);
+            }
+            add(
+                format!("{}.mlp.fc1.weight", prefix),
+                vec![intermediate, hidden],
+            );
+            add(format!("{}.mlp.fc1.bias", prefix), vec![intermediate]);
+            add(
+                format!("{}.mlp.fc2.weight", prefix),
+                vec![hidden, intermediate],
+            );
+            add(format!("{}.mlp.fc2.bias", prefix), vec![hidden]);
+        }
+        add("final_layer_norm.weight".into(), vec![hidden]);
+        add("final_layer_norm.bias".into(), vec![hidden]);
+        safetensors::serialize(&tensors)
+    }
+
+    #[test]
+    fn infers_config_from_weights() {
+        let encoder =
+            ClipTextEncoder::from_tensors(tensor_store::from_bytes(&tiny_model())).unwrap();
+        let config = encoder.config();
+        assert_eq!(config.vocab_size, 10);
+        assert_eq!(config.num_layers, 2);
+        assert_eq!(config.num_heads, 1);
+        assert_eq!(config.max_positions, 8);
+        assert_eq!(config.activation, Activation::Gelu);
+    }
+
+    #[test]
+    fn attention_is_causal() {
+        let encoder =
+            ClipTextEncoder::from_tensors(tensor_store::from_bytes(&tiny_model())).unwrap();
+        let a = encoder.encode(&[1, 2, 3]).unwrap();
+        let b = encoder.encode(&[1, 2, 5]).unwrap();
+        assert_eq!(a.len(), 3 * HEAD_DIM);
+
+        // Earlier tokens cannot see a later change
+        assert_eq!(a[..2 * HEAD_DIM], b[..2 * HEAD_DIM]);
+        assert_ne!(a[2 * HEAD_DIM..], b[2 * HEAD_DIM..]);
+
+        assert!(encoder.encode(&[10]).is_err());
+        assert!(encoder.encode(&[0; 9]).is_err());
+    }
+
+    // Expected values from an independent float64 implementation of the
+    // transformers CLIPTextModel modules, run on the same weights: the first
+    // values of each token's hidden state
+    #[test]
+    fn matches_reference_values() {
+        let encoder =
+            ClipTextEncoder::from_tensors(tensor_store::from_bytes(&tiny_model())).unwrap();
+        let output = encoder.encode(&[1, 2, 3]).unwrap();
+
+        let expected = [
+            [0.033993, 0.036473, 0.145289, -0.303295, 0.093968, -0.168257],
+            [
+                0.003748, 0.080458, -0.025644, -0.265303, 0.077430, -0.196295,
+            ],
+            [0.104913, 0.059846, 0.137756, -0.238645, 0.063369, -0.126848],
+        ];
+        for (position, row) in expected.iter().enumerate() {
+            let hidden = &output[position * HEAD_DIM..];
+            for (i, (a, b)) in hidden.iter().zip(row).enumerate() {
+                assert!((a - b).abs() < 1e-5, "{}, {}: {} != {}", position, i, a, b);
+            }
+        }
+    }
+}
@@ -0,0 +1,483 @@
+// Prompt tokenizers
+//
+// ClipTokenizer is the byte-level BPE tokenizer of CLIP, built from the
+// `vocab.json` and `merges.txt` files a controller uploads. Until both are
+// present the pipeline falls back to SimpleTokenizer, which hashes words into
+// the vocabulary range.
+
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// CLIP sequence length including the start and end tokens
+pub const MAX_LENGTH: usize = 77;
+
+// Prompt tokens per encoded chunk, leaving room for the start and end tokens
+pub const CHUNK_LENGTH: usize = MAX_LENGTH - 2;
+
+// Longest prompt encoded, in chunks, which bounds text encoder work per task
+pub const MAX_CHUNKS: usize = 4;
+
+const START_OF_TEXT: &str = "<|startoftext|>";
+const END_OF_TEXT: &str = "<|endoftext|>";
+const END_OF_WORD: &str = "</w>";
+
+// CLIP only uses the first 49152 - 256 - 2 merges of merges.txt
+const MAX_MERGES: usize = 49152 - 256 - 2;
+
+// A prompt split into CHUNK_LENGTH-token chunks, each wrapped with start/end
+// tokens and padded to MAX_LENGTH, with one weight per token id
+pub struct EncodedPrompt {
+    pub chunks: Vec<(Vec<u32>, Vec<f32>)>,
+    // Prompt tokens before chunking, excluding start/end tokens
+    pub token_count: usize,
+}
+
+impl EncodedPrompt {
+    // Whether tokens beyond MAX_CHUNKS chunks were dropped
+    pub fn truncated(&self) -> bool {
+        self.token_count > MAX_CHUNKS * CHUNK_LENGTH
+    }
+}
+
+// A token id together with its vocabulary string, for debugging prompts
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct Token {
+    pub id: u32,
+    pub text: String,
+}
+
+#[derive(Clone)]
+pub enum Tokenizer {
+    Simple(SimpleTokenizer),
+    Clip(ClipTokenizer),
+}
+
+impl Tokenizer {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tokenizer::Simple(_) => "simple",
+            Tokenizer::Clip(_) => "clip-bpe",
+        }
+    }
+
+    // Tokens of `text` without start, end or padding tokens
+    pub fn tokenize(&self, text: &str) -> Vec<Token> {
+        match self {
+            Tokenizer::Simple(tokenizer) => tokenizer.tokenize(text),
+            Tokenizer::Clip(tokenizer) => tokenizer.tokenize(text),
+        }
+    }
+
+    fn special_tokens(&self) -> SpecialTokens {
+        match self {
+            Tokenizer::Simple(_) => SpecialTokens::SIMPLE,
+            Tokenizer::Clip(tokenizer) => tokenizer.special,
+        }
+    }
+
+    pub fn start_token(&self) -> Token {
+        Token {
+            id: self.special_tokens().start,
+            text: START_OF_TEXT.to_string(),
+        }
+    }
+
+    pub fn end_token(&self) -> Token {
+        Token {
+            id: self.special_tokens().end,
+            text: END_OF_TEXT.to_string(),
+        }
+    }
+
+    // Tokens of weighted prompt fragments, each carrying its fragment's weight
+    pub fn tokenize_weighted(&self, fragments: &[(String, f32)]) -> Vec<(Token, f32)> {
+        fragments
+            .iter()
+            .flat_map(|(text, weight)| {
+                self.tokenize(text)
+                    .into_iter()
+                    .map(move |token| (token, *weight))
+            })
+            .collect()
+    }
+
+    // Encode weighted prompt fragments into at least `min_chunks` chunks.
+    // An empty prompt still yields one chunk.
+    pub fn encode_chunks(&self, fragments: &[(String, f32)], min_chunks: usize) -> EncodedPrompt {
+        let tokens = self.tokenize_weighted(fragments);
+        let mut chunks: Vec<_> = tokens
+            .chunks(CHUNK_LENGTH)
+            .take(MAX_CHUNKS)
+            .map(|chunk| self.wrap_chunk(chunk))
+            .collect();
+        while chunks.len() < min_chunks.clamp(1, MAX_CHUNKS) {
+            chunks.push(self.wrap_chunk(&[]));
+        }
+
+        EncodedPrompt {
+            chunks,
+            token_count: tokens.len(),
+        }
+    }
+
+    // Wrap up to CHUNK_LENGTH tokens with start/end tokens and pad to
+    // MAX_LENGTH. Start, end and padding tokens have weight 1.
+    fn wrap_chunk(&self, tokens: &[(Token, f32)]) -> (Vec<u32>, Vec<f32>) {
+        let special = self.special_tokens();
+        let mut ids = vec![special.start];
+        let mut weights = vec![1.0];
+        for (token, weight) in tokens {
+            ids.push(token.id);
+            weights.push(*weight);
+        }
+        ids.push(special.end);
+        ids.resize(MAX_LENGTH, special.pad);
+        weights.resize(MAX_LENGTH, 1.0);
+        (ids, weights)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SpecialTokens {
+    start: u32,
+    end: u32,
+    pad: u32,
+}
+
+impl SpecialTokens {
+    const SIMPLE: SpecialTokens = SpecialTokens {
+        start: 49406,
+        end: 49407,
+        pad: 0,
+    };
+}
+
+#[derive(Clone)]
+pub struct SimpleTokenizer {
+    vocab_size: usize,
+}
+
+impl SimpleTokenizer {
+    pub fn new() -> Self {
+        Self {
+            vocab_size: 49408, // CLIP tokenizer vocab size
+        }
+    }
+
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        // Simple word splitting and hashing for demo
+        text.split_whitespace()
+            .map(|word| Token {
+                id: (word.chars().map(|c| c as u32).sum::<u32>() % (self.vocab_size as u32 - 2))
+                    + 1,
+                text: word.to_string(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct ClipTokenizer {
+    encoder: HashMap<String, u32>,
+    bpe_ranks: HashMap<(String, String), usize>,
+    byte_encoder: Vec<char>,
+    special: SpecialTokens,
+}
+
+impl ClipTokenizer {
+    pub fn from_files(vocab_json: &[u8], merges_txt: &[u8]) -> Result<Self, String> {
+        let encoder: HashMap<String, u32> =
+            serde_json::from_slice(vocab_json).map_err(|e| format!("Invalid vocab.json: {}", e))?;
+        let merges =
+            std::str::from_utf8(merges_txt).map_err(|e| format!("Invalid merges.txt: {}", e))?;
+
+        let mut bpe_ranks = HashMap::new();
+        for (rank, line) in merges
+            .trim()
+            .lines()
+            .filter(|line| !line.starts_with("#version"))
+            .take(MAX_MERGES)
+            .enumerate()
+        {
+            let (first, second) = line
+                .split_once(' ')
+                .ok_or_else(|| format!("Invalid merge on line {}: {:?}", rank + 1, line))?;
+            bpe_ranks.insert((first.to_string(), second.to_string()), rank);
+        }
+
+        // BPE yields single byte symbols, alone or ending a word, and merge
+        // results; a vocabulary missing any of them does not belong to these
+        // merges
+        let byte_encoder = bytes_to_unicode();
+        let symbols = byte_encoder
+            .iter()
+            .flat_map(|c| [c.to_string(), format!("{}{}", c, END_OF_WORD)]);
+        let merged = bpe_ranks
+            .keys()
+            .map(|(first, second)| format!("{}{}", first, second));
+        if let Some(missing) = symbols.chain(merged).find(|s| !encoder.contains_key(s)) {
+            return Err(format!(
+                "vocab.json does not match merges.txt: it has no {:?} token",
+                missing
+            ));
+        }
+
+        let lookup = |token: &str| {
+            encoder
+                .get(token)
+                .copied()
+                .ok_or_else(|| format!("vocab.json has no {} token", token))
+        };
+        // SD 1.x pads with the end-of-text token
+        let end = lookup(END_OF_TEXT)?;
+        let special = SpecialTokens {
+            start: lookup(START_OF_TEXT)?,
+            end,
+            pad: end,
+        };
+
+        Ok(Self {
+            encoder,
+            bpe_ranks,
+            byte_encoder,
+            special,
+        })
+    }
+
+    pub fn vocab_size(&self) -> usize {
+        self.encoder.len()
+    }
+
+    pub fn merge_count(&self) -> usize {
+        self.bpe_ranks.len()
+    }
+
+    fn tokenize(&self, text: &str) -> Vec<Token> {
+        let cleaned = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        let mut tokens = Vec::new();
+
+        for piece in pre_tokenize(&cleaned.to_lowercase()) {
+            if piece == START_OF_TEXT || piece == END_OF_TEXT {
+                let id = if piece == START_OF_TEXT {
+                    self.special.start
+                } else {
+                    self.special.end
+                };
+                tokens.push(Token {
+                    id,
+                    text: piece.to_string(),
+                });
+                continue;
+            }
+
+            let encoded: String = piece
+                .bytes()
+                .map(|b| self.byte_encoder[b as usize])
+                .collect();
+            for symbol in self.bpe(&encoded) {
+                // from_files checked that every symbol BPE can yield is in
+                // the vocabulary
+                let id = self.encoder[&symbol];
+                tokens.push(Token { id, text: symbol });
+            }
+        }
+        tokens
+    }
+
+    // Apply the ranked merges to one byte-encoded word, lowest rank first
+    fn bpe(&self, word: &str) -> Vec<String> {
+        let mut symbols: Vec<String> = word.chars().map(String::from).collect();
+        if let Some(last) = symbols.last_mut() {
+            last.push_str(END_OF_WORD);
+        }
+
+        while symbols.len() > 1 {
+            let best = symbols
+                .windows(2)
+                .filter_map(|pair| {
+                    self.bpe_ranks
+                        .get(&(pair[0].clone(), pair[1].clone()))
+                        .map(|&rank| (rank, pair[0].clone(), pair[1].clone()))
+                })
+                .min();
+            let Some((_, first, second)) = best else {
+                break;
+            };
+
+            let mut merged = Vec::with_capacity(symbols.len());
+            let mut i = 0;
+            while i < symbols.len() {
+                if i + 1 < symbols.len() && symbols[i] == first && symbols[i + 1] == second {
+                    merged.push(format!("{}{}", first, second));
+                    i += 2;
+                } else {
+                    merged.push(symbols[i].clone());
+                    i += 1;
+                }
+            }
+            symbols = merged;
+        }
+        symbols
+    }
+}
+
+// Split lowercased text the way CLIP's pattern does:
+// <|startoftext|>|<|endoftext|>|'s|'t|'re|'ve|'m|'ll|'d|[\p{L}]+|[\p{N}]|[^\s\p{L}\p{N}]+
+fn pre_tokenize(text: &str) -> Vec<&str> {
+    const CONTRACTIONS: [&str; 7] = ["'re", "'ve", "'ll", "'s", "'t", "'m", "'d"];
+
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+
+        let len = if let Some(special) = [START_OF_TEXT, END_OF_TEXT]
+            .into_iter()
+            .chain(CONTRACTIONS)
+            .find(|prefix| rest.starts_with(prefix))
+        {
+            special.len()
+        } else if c.is_alphabetic() {
+            prefix_len(rest, |c| c.is_alphabetic())
+        } else if c.is_numeric() {
+            c.len_utf8()
+        } else {
+            prefix_len(rest, |c| {
+                !c.is_whitespace() && !c.is_alphabetic() && !c.is_numeric()
+            })
+        };
+
+        pieces.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+    pieces
+}
+
+fn prefix_len(text: &str, matches: impl Fn(char) -> bool) -> usize {
+    text.char_indices()
+        .find(|&(_, c)| !matches(c))
+        .map_or(text.len(), |(i, _)| i)
+}
+
+// GPT-2 style reversible mapping of bytes to printable characters
+fn bytes_to_unicode() -> Vec<char> {
+    let printable = |b: u32| {
+        (u32::from('!')..=u32::from('~')).contains(&b)
+            || (u32::from('¡')..=u32::from('¬')).contains(&b)
+            || (u32::from('®')..=u32::from('ÿ')).contains(&b)
+    };
+
+    let mut shifted = 0;
+    (0..256u32)
+        .map(|b| {
+            let code = if printable(b) {
+                b
+            } else {
+                shifted += 1;
+                255 + shifted
+            };
+            char::from_u32(code).unwrap()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pre_tokenize_follows_clip_pattern() {
+        assert_eq!(
+            pre_tokenize("a (red:1.4) car's 4k_hd!! <|endoftext|>"),
+            [
+                "a",
+                "(",
+                "red",
+                ":",
+                "1",
+                ".",
+                "4",
+                ")",
+                "car",
+                "'s",
+                "4",
+                "k",
+                "_",
+                "hd",
+                "!!",
+                "<|endoftext|>"
+            ]
+        );
+    }
+
+    // vocab.json with every byte symbol, alone and ending a word, followed
+    // by `tokens`
+    fn vocab_json(tokens: &[&str]) -> Vec<u8> {
+        let symbols = bytes_to_unicode()
+            .into_iter()
+            .flat_map(|c| [c.to_string(), format!("{}{}", c, END_OF_WORD)]);
+        let vocab: HashMap<String, usize> = symbols
+            .chain(tokens.iter().map(|token| token.to_string()))
+            .enumerate()
+            .map(|(id, token)| (token, id))
+            .collect();
+        serde_json::to_vec(&vocab).unwrap()
+    }
+
+    #[test]
+    fn bpe_merges_by_rank() {
+        let vocab = vocab_json(&["at</w>", "cat</w>", START_OF_TEXT, END_OF_TEXT]);
+        let merges = "#version: 0.2\na t</w>\nc at</w>\n";
+        let tokenizer = ClipTokenizer::from_files(&vocab, merges.as_bytes()).unwrap();
+        let (cat, start, end) = (513, 514, 515);
+
+        let tokens = tokenizer.tokenize("Cat CAT ta");
+        let ids: Vec<u32> = tokens.iter().map(|token| token.id).collect();
+        // "ta" has no merges
+        let (t, a) = (tokenizer.encoder["t"], tokenizer.encoder["a</w>"]);
+        assert_eq!(ids, [cat, cat, t, a]);
+        assert_eq!(tokens[0].text, "cat</w>");
+        let encoded = Tokenizer::Clip(tokenizer).encode_chunks(&[("cat".to_string(), 1.5)], 1);
+        let (ids, weights) = &encoded.chunks[0];
+        assert_eq!(ids[..4], [start, cat, end, end]);
+        assert_eq!(weights[..4], [1.0, 1.5, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn mismatched_vocab_and_merges_are_rejected() {
+        let merges = "#version: 0.2\na t</w>\nc at</w>\n".as_bytes();
+        let vocab = vocab_json(&["at</w>", START_OF_TEXT, END_OF_TEXT]);
+        let error = ClipTokenizer::from_files(&vocab, merges).err().unwrap();
+        assert!(error.contains("\"cat</w>\""), "{}", error);
+
+        let without_bytes =
+            br#"{"at</w>": 0, "cat</w>": 1, "<|startoftext|>": 2, "<|endoftext|>": 3}"#;
+        assert!(ClipTokenizer::from_files(without_bytes, merges).is_err());
+    }
+
+    #[test]
+    fn long_prompts_are_chunked() {
+        let tokenizer = Tokenizer::Simple(SimpleTokenizer::new());
+        let prompt = vec!["word"; 80].join(" ");
+
+        let encoded = tokenizer.encode_chunks(&[(prompt, 1.0)], 1);
+        assert_eq!(encoded.token_count, 80);
+        assert!(!encoded.truncated());
+        assert_eq!(encoded.chunks.len(), 2);
+        for (ids, weights) in &encoded.chunks {
+            assert_eq!(ids.len(), MAX_LENGTH);
+            assert_eq!(weights.len(), MAX_LENGTH);
+            assert_eq!(ids[0], 49406);
+        }
+        // 5 tokens spill into the second chunk, followed by the end token
+        assert_eq!(encoded.chunks[1].0[6], 49407);
+
+        let negative = tokenizer.encode_chunks(&[(String::new(), 1.0)], encoded.chunks.len());
+        assert_eq!(negative.chunks.len(), 2);
+        assert_eq!(negative.token_count, 0);
+    }
+}
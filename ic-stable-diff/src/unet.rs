@@ -0,0 +1,786 @@
+// Denoising UNets
+//
+// UNet2DConditionModel is the Stable Diffusion noise predictor in the
+// diffusers layout: a sinusoidal timestep embedding, ResNet blocks, spatial
+// transformers cross-attending to the text embeddings, and down, mid and up
+// blocks joined by skip connections. The architecture is read off the
+// uploaded weights, which covers SD 1.x and 2.x as well as distilled variants
+// that drop the mid block or use fewer layers per block (BK-SDM). Weights
+// stay in stable memory and are paged in one layer at a time during each
+// forward pass. Until weights are uploaded the pipeline falls back to
+// MockUNet.
+
+use crate::tensor::{self, Tensor};
+use crate::tensor_store::{TensorStore, Weights};
+use ic_stable_structures::Memory;
+
+const RESNET_EPS: f32 = 1e-5;
+const TRANSFORMER_NORM_EPS: f32 = 1e-6;
+const LAYER_NORM_EPS: f32 = 1e-5;
+const DEFAULT_NORM_GROUPS: usize = 32;
+
+// Query rows per attention score block, bounding the score matrix to
+// ATTENTION_CHUNK x key_tokens values
+const ATTENTION_CHUNK: usize = 1024;
+
+#[derive(Clone)]
+pub enum UNet {
+    Mock(MockUNet),
+    Conditional(Box<UNet2DConditionModel<crate::Memory>>),
+}
+
+impl UNet {
+    pub fn name(&self) -> &'static str {
+        match self {
+            UNet::Mock(_) => "mock",
+            UNet::Conditional(_) => "unet2d-condition",
+        }
+    }
+
+    pub fn in_channels(&self) -> usize {
+        match self {
+            UNet::Mock(unet) => unet.in_channels,
+            UNet::Conditional(unet) => unet.config.in_channels,
+        }
+    }
+
+    // Predict the noise in `[batch, in_channels, h, w]` latents conditioned on
+    // `[batch, tokens, dim]` text embeddings
+    pub fn forward(
+        &self,
+        latents: &Tensor,
+        timestep: u32,
+        text_embeddings: &Tensor,
+    ) -> Result<Tensor, String> {
+        match self {
+            UNet::Mock(unet) => Ok(unet.forward(latents, timestep, text_embeddings)),
+            UNet::Conditional(unet) => unet.forward(latents, timestep, text_embeddings),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MockUNet {
+    in_channels: usize,
+}
+
+impl MockUNet {
+    pub fn new() -> Self {
+        Self { in_channels: 4 }
+    }
+
+    fn forward(&self, latents: &Tensor, timestep: u32, text_embeddings: &Tensor) -> Tensor {
+        assert!(
+            latents.shape().len() == 4 && latents.dim(1) == self.in_channels,
+            "UNet input has shape {:?}",
+            latents.shape()
+        );
+        assert!(
+            text_embeddings.shape().len() == 3 && text_embeddings.dim(0) == latents.dim(0),
+            "UNet conditioning has shape {:?}",
+            text_embeddings.shape()
+        );
+
+        // Simplified UNet forward pass
+        // In real implementation, this would be the actual diffusion model
+        let conditioning_strength = text_embeddings.mean() * 0.1;
+        let time_factor = (timestep as f32 / 1000.0).cos();
+
+        let noise_pred = latents
+            .values()
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| x + conditioning_strength * time_factor * ((i as f32).sin() * 0.1))
+            .collect();
+        Tensor::new(latents.shape(), noise_pred)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttentionHeads {
+    // The same number of heads in every block (SD 1.x)
+    Count(usize),
+    // Heads of a fixed width, so wider blocks get more of them (SD 2.x)
+    Dim(usize),
+}
+
+impl AttentionHeads {
+    fn heads(&self, channels: usize) -> usize {
+        match *self {
+            AttentionHeads::Count(heads) => heads,
+            AttentionHeads::Dim(dim) => channels / dim,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UNetConfig {
+    pub in_channels: usize,
+    pub out_channels: usize,
+    pub block_out_channels: Vec<usize>,
+    // Whether each down block has cross-attention transformers
+    pub down_block_attention: Vec<bool>,
+    // Likewise for the up blocks, deepest first
+    pub up_block_attention: Vec<bool>,
+    // Resnets per down block; up blocks have one more to consume the extra
+    // skip connection from each downsampler
+    pub layers_per_block: usize,
+    pub mid_block: bool,
+    pub cross_attention_dim: usize,
+    pub attention_heads: AttentionHeads,
+    // Transformer projections are linear layers rather than 1x1 convolutions
+    pub linear_projection: bool,
+    pub norm_num_groups: usize,
+}
+
+impl UNetConfig {
+    // Stable Diffusion 1.x
+    pub fn sd15() -> Self {
+        Self {
+            in_channels: 4,
+            out_channels: 4,
+            block_out_channels: vec![320, 640, 1280, 1280],
+            down_block_attention: vec![true, true, true, false],
+            up_block_attention: vec![false, true, true, true],
+            layers_per_block: 2,
+            mid_block: true,
+            cross_attention_dim: 768,
+            attention_heads: AttentionHeads::Count(8),
+            linear_projection: false,
+            norm_num_groups: DEFAULT_NORM_GROUPS,
+        }
+    }
+
+    // BK-SDM-Tiny: SD 1.x distilled to one layer per down block, without the
+    // mid block or the innermost stage
+    pub fn tiny() -> Self {
+        Self {
+            block_out_channels: vec![320, 640, 1280],
+            down_block_attention: vec![true, true, true],
+            up_block_attention: vec![true, true, true],
+            layers_per_block: 1,
+            mid_block: false,
+            ..Self::sd15()
+        }
+    }
+
+    // Read the architecture off the weight names and shapes
+    pub fn infer<M: Memory>(tensors: &TensorStore<M>) -> Result<Self, String> {
+        let count = |name: &dyn Fn(usize) -> String| {
+            (0..).take_while(|&i| tensors.contains(&name(i))).count()
+        };
+        let has_attention = |blocks: &str, i: usize| {
+            tensors.contains(&format!("{}.{}.attentions.0.norm.weight", blocks, i))
+        };
+
+        let in_channels = tensors.dim("conv_in.weight", 1)?;
+        let out_channels = tensors.dim("conv_out.weight", 0)?;
+
+        let blocks = count(&|i| format!("down_blocks.{}.resnets.0.conv1.weight", i));
+        if blocks == 0 {
+            return Err("UNet weights contain no down blocks".to_string());
+        }
+        let block_out_channels = (0..blocks)
+            .map(|i| {
+                let name = format!("down_blocks.{}.resnets.0.conv1.weight", i);
+                tensors.dim(&name, 0)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let down_block_attention: Vec<bool> = (0..blocks)
+            .map(|i| has_attention("down_blocks", i))
+            .collect();
+        let up_block_attention = (0..blocks).map(|i| has_attention("up_blocks", i)).collect();
+        let layers_per_block = count(&|j| format!("down_blocks.0.resnets.{}.conv1.weight", j));
+        let mid_block = tensors.contains("mid_block.resnets.0.conv1.weight");
+
+        let transformer = down_block_attention
+            .iter()
+            .position(|&attention| attention)
+            .map(|i| format!("down_blocks.{}.attentions.0", i))
+            .or_else(|| mid_block.then(|| "mid_block.attentions.0".to_string()))
+            .ok_or("UNet weights contain no cross-attention layers")?;
+        let cross_attention_dim = tensors.dim(
+            &format!("{}.transformer_blocks.0.attn2.to_k.weight", transformer),
+            1,
+        )?;
+        let linear_projection = tensors
+            .view(&format!("{}.proj_in.weight", transformer))?
+            .shape
+            .len()
+            == 2;
+
+        // SD 2.x is the variant with linear projections and 64-wide heads;
+        // metadata can say otherwise
+        let metadata = |key: &str| tensors.metadata().get(key).and_then(|v| v.parse().ok());
+        let attention_heads = match metadata("num_attention_heads") {
+            Some(heads) => AttentionHeads::Count(heads),
+            None if linear_projection => AttentionHeads::Dim(64),
+            None => AttentionHeads::Count(8),
+        };
+
+        Ok(Self {
+            in_channels,
+            out_channels,
+            block_out_channels,
+            down_block_attention,
+            up_block_attention,
+            layers_per_block,
+            mid_block,
+            cross_attention_dim,
+            attention_heads,
+            linear_projection,
+            norm_num_groups: metadata("norm_num_groups").unwrap_or(DEFAULT_NORM_GROUPS),
+        })
+    }
+
+    // Name and shape of every weight, in the diffusers naming scheme
+    pub fn tensor_shapes(&self) -> Vec<(String, Vec<usize>)> {
+        let mut shapes = Vec::new();
+        let blocks = self.block_out_channels.len();
+        let c0 = self.block_out_channels[0];
+        let time_dim = 4 * c0;
+
+        linear_shapes(&mut shapes, "time_embedding.linear_1", c0, time_dim);
+        linear_shapes(&mut shapes, "time_embedding.linear_2", time_dim, time_dim);
+        conv_shapes(&mut shapes, "conv_in", self.in_channels, c0, 3);
+
+        let mut channels = c0;
+        for (i, (&out, &attention)) in self
+            .block_out_channels
+            .iter()
+            .zip(&self.down_block_attention)
+            .enumerate()
+        {
+            for j in 0..self.layers_per_block {
+                let resnet = format!("down_blocks.{}.resnets.{}", i, j);
+                resnet_shapes(&mut shapes, &resnet, channels, out, time_dim);
+                channels = out;
+                if attention {
+                    let transformer = format!("down_blocks.{}.attentions.{}", i, j);
+                    self.transformer_shapes(&mut shapes, &transformer, out);
+                }
+            }
+            if i + 1 < blocks {
+                let downsampler = format!("down_blocks.{}.downsamplers.0.conv", i);
+                conv_shapes(&mut shapes, &downsampler, out, out, 3);
+            }
+        }
+
+        if self.mid_block {
+            resnet_shapes(
+                &mut shapes,
+                "mid_block.resnets.0",
+                channels,
+                channels,
+                time_dim,
+            );
+            self.transformer_shapes(&mut shapes, "mid_block.attentions.0", channels);
+            resnet_shapes(
+                &mut shapes,
+                "mid_block.resnets.1",
+                channels,
+                channels,
+                time_dim,
+            );
+        }
+
+        let reversed: Vec<usize> = self.block_out_channels.iter().rev().copied().collect();
+        for (i, (&out, &attention)) in reversed.iter().zip(&self.up_block_attention).enumerate() {
+            // The last resnet takes the skip from the previous block's
+            // downsampler, which has that block's (narrower) width
+            let skip_in = reversed[(i + 1).min(blocks - 1)];
+            for j in 0..=self.layers_per_block {
+                let skip = if j == self.layers_per_block {
+                    skip_in
+                } else {
+                    out
+                };
+                let resnet = format!("up_blocks.{}.resnets.{}", i, j);
+                resnet_shapes(&mut shapes, &resnet, channels + skip, out, time_dim);
+                channels = out;
+                if attention {
+                    let transformer = format!("up_blocks.{}.attentions.{}", i, j);
+                    self.transformer_shapes(&mut shapes, &transformer, out);
+                }
+            }
+            if i + 1 < blocks {
+                let upsampler = format!("up_blocks.{}.upsamplers.0.conv", i);
+                conv_shapes(&mut shapes, &upsampler, out, out, 3);
+            }
+        }
+
+        norm_shapes(&mut shapes, "conv_norm_out", c0);
+        conv_shapes(&mut shapes, "conv_out", c0, self.out_channels, 3);
+        shapes
+    }
+
+    fn transformer_shapes(&self, shapes: &mut Vec<(String, Vec<usize>)>, prefix: &str, c: usize) {
+        let projection = |shapes: &mut Vec<_>, name: &str| {
+            if self.linear_projection {
+                linear_shapes(shapes, &format!("{}.{}", prefix, name), c, c);
+            } else {
+                conv_shapes(shapes, &format!("{}.{}", prefix, name), c, c, 1);
+            }
+        };
+
+        norm_shapes(shapes, &format!("{}.norm", prefix), c);
+        projection(shapes, "proj_in");
+        let block = format!("{}.transformer_blocks.0", prefix);
+        for (attention, context_dim) in [("attn1", c), ("attn2", self.cross_attention_dim)] {
+            let attention = format!("{}.{}", block, attention);
+            shapes.push((format!("{}.to_q.weight", attention), vec![c, c]));
+            shapes.push((format!("{}.to_k.weight", attention), vec![c, context_dim]));
+            shapes.push((format!("{}.to_v.weight", attention), vec![c, context_dim]));
+            linear_shapes(shapes, &format!("{}.to_out.0", attention), c, c);
+        }
+        for norm in ["norm1", "norm2", "norm3"] {
+            norm_shapes(shapes, &format!("{}.{}", block, norm), c);
+        }
+        // GEGLU projects to twice the 4x inner width: values and gates
+        linear_shapes(shapes, &format!("{}.ff.net.0.proj", block), c, 8 * c);
+        linear_shapes(shapes, &format!("{}.ff.net.2", block), 4 * c, c);
+        projection(shapes, "proj_out");
+    }
+}
+
+fn linear_shapes(shapes: &mut Vec<(String, Vec<usize>)>, name: &str, input: usize, output: usize) {
+    shapes.push((format!("{}.weight", name), vec![output, input]));
+    shapes.push((format!("{}.bias", name), vec![output]));
+}
+
+fn conv_shapes(
+    shapes: &mut Vec<(String, Vec<usize>)>,
+    name: &str,
+    input: usize,
+    output: usize,
+    kernel: usize,
+) {
+    shapes.push((
+        format!("{}.weight", name),
+        vec![output, input, kernel, kernel],
+    ));
+    shapes.push((format!("{}.bias", name), vec![output]));
+}
+
+fn norm_shapes(shapes: &mut Vec<(String, Vec<usize>)>, name: &str, channels: usize) {
+    shapes.push((format!("{}.weight", name), vec![channels]));
+    shapes.push((format!("{}.bias", name), vec![channels]));
+}
+
+fn resnet_shapes(
+    shapes: &mut Vec<(String, Vec<usize>)>,
+    prefix: &str,
+    input: usize,
+    output: usize,
+    time_dim: usize,
+) {
+    norm_shapes(shapes, &format!("{}.norm1", prefix), input);
+    conv_shapes(shapes, &format!("{}.conv1", prefix), input, output, 3);
+    linear_shapes(
+        shapes,
+        &format!("{}.time_emb_proj", prefix),
+        time_dim,
+        output,
+    );
+    norm_shapes(shapes, &format!("{}.norm2", prefix), output);
+    conv_shapes(shapes, &format!("{}.conv2", prefix), output, output, 3);
+    if input != output {
+        conv_shapes(
+            shapes,
+            &format!("{}.conv_shortcut", prefix),
+            input,
+            output,
+            1,
+        );
+    }
+}
+
+#[derive(Clone)]
+pub struct UNet2DConditionModel<M: Memory> {
+    config: UNetConfig,
+    tensors: TensorStore<M>,
+}
+
+impl<M: Memory> UNet2DConditionModel<M> {
+    // Check the uploaded weights against the architecture they imply
+    pub fn from_tensors(tensors: TensorStore<M>) -> Result<Self, String> {
+        let config = UNetConfig::infer(&tensors)?;
+        for &channels in &config.block_out_channels {
+            let heads = config.attention_heads.heads(channels);
+            if channels % config.norm_num_groups != 0 || heads == 0 || channels % heads != 0 {
+                return Err(format!(
+                    "{} channels do not split into {} groups and {} attention heads",
+                    channels, config.norm_num_groups, heads
+                ));
+            }
+        }
+        for (name, shape) in config.tensor_shapes() {
+            tensors.view_with_shape(&name, &shape)?;
+        }
+        Ok(Self { config, tensors })
+    }
+
+    pub fn config(&self) -> &UNetConfig {
+        &self.config
+    }
+
+    pub fn forward(
+        &self,
+        latents: &Tensor,
+        timestep: u32,
+        text_embeddings: &Tensor,
+    ) -> Result<Tensor, String> {
+        let config = &self.config;
+        let blocks = config.block_out_channels.len();
+        let (batch, height, width) = match latents.shape()[..] {
+            [batch, channels, height, width] if channels == config.in_channels => {
+                (batch, height, width)
+            }
+            _ => {
+                return Err(format!(
+                    "Expected latents of shape [batch, {}, h, w], got {:?}",
+                    config.in_channels,
+                    latents.shape()
+                ));
+            }
+        };
+        if text_embeddings.shape().len() != 3
+            || text_embeddings.dim(0) != batch
+            || text_embeddings.dim(2) != config.cross_attention_dim
+        {
+            return Err(format!(
+                "Expected text embeddings of shape [{}, tokens, {}], got {:?}",
+                batch,
+                config.cross_attention_dim,
+                text_embeddings.shape()
+            ));
+        }
+        let multiple = 1 << (blocks - 1);
+        if height % multiple != 0 || width % multiple != 0 {
+            return Err(format!(
+                "Latent size {}x{} must be a multiple of {}",
+                width, height, multiple
+            ));
+        }
+
+        let mut weights = Weights::new(&self.tensors);
+        let w = &mut weights;
+
+        let time = timestep_embedding(timestep, config.block_out_channels[0]);
+        let time = w.linear("time_embedding.linear_1", &time)?.silu();
+        let time = w.linear("time_embedding.linear_2", &time)?;
+        // Every resnet projects the activated embedding
+        let time = time.silu();
+
+        let mut sample = w.conv("conv_in", latents, 1)?;
+        let mut skips = vec![sample.clone()];
+
+        for (i, &attention) in config.down_block_attention.iter().enumerate() {
+            for j in 0..config.layers_per_block {
+                sample = self.resnet(
+                    w,
+                    &format!("down_blocks.{}.resnets.{}", i, j),
+                    &sample,
+                    &time,
+                )?;
+                if attention {
+                    let name = format!("down_blocks.{}.attentions.{}", i, j);
+                    sample = self.transformer(w, &name, &sample, text_embeddings)?;
+                }
+                skips.push(sample.clone());
+            }
+            if i + 1 < blocks {
+                sample = w.conv(
+                    &format!("down_blocks.{}.downsamplers.0.conv", i),
+                    &sample,
+                    2,
+                )?;
+                skips.push(sample.clone());
+            }
+        }
+
+        if config.mid_block {
+            sample = self.resnet(w, "mid_block.resnets.0", &sample, &time)?;
+            sample = self.transformer(w, "mid_block.attentions.0", &sample, text_embeddings)?;
+            sample = self.resnet(w, "mid_block.resnets.1", &sample, &time)?;
+        }
+
+        for (i, &attention) in config.up_block_attention.iter().enumerate() {
+            for j in 0..=config.layers_per_block {
+                let skip = skips.pop().ok_or("UNet ran out of skip connections")?;
+                sample = Tensor::concat(&[&sample, &skip], 1);
+                sample =
+                    self.resnet(w, &format!("up_blocks.{}.resnets.{}", i, j), &sample, &time)?;
+                if attention {
+                    let name = format!("up_blocks.{}.attentions.{}", i, j);
+                    sample = self.transformer(w, &name, &sample, text_embeddings)?;
+                }
+            }
+            if i + 1 < blocks {
+                sample = sample.upsample_nearest2d(2);
+                sample = w.conv(&format!("up_blocks.{}.upsamplers.0.conv", i), &sample, 1)?;
+            }
+        }
+
+        let sample = w
+            .group_norm("conv_norm_out", &sample, config.norm_num_groups, RESNET_EPS)?
+            .silu();
+        w.conv("conv_out", &sample, 1)
+    }
+
+    fn resnet(
+        &self,
+        w: &mut Weights<M>,
+        prefix: &str,
+        input: &Tensor,
+        time: &Tensor,
+    ) -> Result<Tensor, String> {
+        let groups = self.config.norm_num_groups;
+        let hidden = w
+            .group_norm(&format!("{}.norm1", prefix), input, groups, RESNET_EPS)?
+            .silu();
+        let hidden = w.conv(&format!("{}.conv1", prefix), &hidden, 1)?;
+
+        // [1, channels] broadcast over the batch and both spatial dimensions
+        let time = w.linear(&format!("{}.time_emb_proj", prefix), time)?;
+        let channels = time.dim(1);
+        let hidden = hidden.add(&time.reshape(&[channels, 1, 1]));
+
+        let hidden = w
+            .group_norm(&format!("{}.norm2", prefix), &hidden, groups, RESNET_EPS)?
+            .silu();
+        let hidden = w.conv(&format!("{}.conv2", prefix), &hidden, 1)?;
+
+        let shortcut = format!("{}.conv_shortcut", prefix);
+        if self.tensors.contains(&format!("{}.weight", shortcut)) {
+            Ok(w.conv(&shortcut, input, 1)?.add(&hidden))
+        } else {
+            Ok(input.add(&hidden))
+        }
+    }
+
+    fn transformer(
+        &self,
+        w: &mut Weights<M>,
+        prefix: &str,
+        input: &Tensor,
+        context: &Tensor,
+    ) -> Result<Tensor, String> {
+        let [batch, channels, height, width] = input.shape()[..] else {
+            unreachable!("transformer input is NCHW");
+        };
+        let tokens = height * width;
+        let to_tokens = |x: Tensor| {
+            x.reshape(&[batch, channels, tokens])
+                .transpose(1, 2)
+                .contiguous()
+        };
+        let to_image = |x: Tensor| x.transpose(1, 2).reshape(&[batch, channels, height, width]);
+
+        let groups = self.config.norm_num_groups;
+        let hidden = w.group_norm(
+            &format!("{}.norm", prefix),
+            input,
+            groups,
+            TRANSFORMER_NORM_EPS,
+        )?;
+        let mut hidden = if self.config.linear_projection {
+            w.linear(&format!("{}.proj_in", prefix), &to_tokens(hidden))?
+        } else {
+            to_tokens(w.conv(&format!("{}.proj_in", prefix), &hidden, 1)?)
+        };
+
+        let block = format!("{}.transformer_blocks.0", prefix);
+        let heads = self.config.attention_heads.heads(channels);
+
+        let normed = w.layer_norm(&format!("{}.norm1", block), &hidden, LAYER_NORM_EPS)?;
+        let attended = self.attention(w, &format!("{}.attn1", block), &normed, &normed, heads)?;
+        hidden = hidden.add(&attended);
+
+        let normed = w.layer_norm(&format!("{}.norm2", block), &hidden, LAYER_NORM_EPS)?;
+        let attended = self.attention(w, &format!("{}.attn2", block), &normed, context, heads)?;
+        hidden = hidden.add(&attended);
+
+        let normed = w.layer_norm(&format!("{}.norm3", block), &hidden, LAYER_NORM_EPS)?;
+        let projected = w.linear(&format!("{}.ff.net.0.proj", block), &normed)?;
+        let inner = projected.dim(2) / 2;
+        let gated = projected
+            .narrow(2, 0, inner)
+            .mul(&projected.narrow(2, inner, inner).gelu());
+        hidden = hidden.add(&w.linear(&format!("{}.ff.net.2", block), &gated)?);
+
+        let output = if self.config.linear_projection {
+            to_image(w.linear(&format!("{}.proj_out", prefix), &hidden)?)
+        } else {
+            w.conv(&format!("{}.proj_out", prefix), &to_image(hidden), 1)?
+        };
+        Ok(output.add(input))
+    }
+
+    fn attention(
+        &self,
+        w: &mut Weights<M>,
+        prefix: &str,
+        input: &Tensor,
+        context: &Tensor,
+        heads: usize,
+    ) -> Result<Tensor, String> {
+        let query = w.project(&format!("{}.to_q", prefix), input)?;
+        let key = w.project(&format!("{}.to_k", prefix), context)?;
+        let value = w.project(&format!("{}.to_v", prefix), context)?;
+        let output = tensor::multi_head_attention(&query, &key, &value, heads, ATTENTION_CHUNK);
+        w.linear(&format!("{}.to_out.0", prefix), &output)
+    }
+}
+
+// Sinusoidal embedding of a timestep as [cos | sin], computed in f32 like
+// diffusers' Timesteps(flip_sin_to_cos=True, downscale_freq_shift=0)
+fn timestep_embedding(timestep: u32, dim: usize) -> Tensor {
+    let half = dim / 2;
+    let mut data = vec![0.0; dim];
+    for i in 0..half {
+        let frequency = (-(10000f32.ln()) * i as f32 / half as f32).exp();
+        let angle = timestep as f32 * frequency;
+        data[i] = angle.cos();
+        data[half + i] = angle.sin();
+    }
+    Tensor::new(&[1, dim], data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::TorchGenerator;
+    use crate::{safetensors, tensor_store};
+
+    fn micro_config() -> UNetConfig {
+        UNetConfig {
+            in_channels: 4,
+            out_channels: 4,
+            block_out_channels: vec![32, 64],
+            down_block_attention: vec![true, false],
+            up_block_attention: vec![false, true],
+            layers_per_block: 1,
+            mid_block: true,
+            cross_attention_dim: 12,
+            attention_heads: AttentionHeads::Count(8),
+            linear_projection: false,
+            norm_num_groups: 32,
+        }
+    }
+
+    fn random_weights(config: &UNetConfig) -> Vec<u8> {
+        let mut generator = TorchGenerator::new(3);
+        let tensors: Vec<_> = config
+            .tensor_shapes()
+            .into_iter()
+            .map(|(name, shape)| {
+                let size = shape.iter().product();
+                let values = generator.randn(size).iter().map(|v| v * 0.1).collect();
+                (name, shape, values)
+            })
+            .collect();
+        safetensors::serialize(&tensors)
+    }
+
+    #[test]
+    fn sd15_layout_has_the_reference_parameter_count() {
+        let parameters: usize = UNetConfig::sd15()
+            .tensor_shapes()
+            .iter()
+            .map(|(_, shape)| shape.iter().product::<usize>())
+            .sum();
+        assert_eq!(parameters, 859_520_964);
+    }
+
+    #[test]
+    fn infers_architecture_from_weights() {
+        for config in [
+            micro_config(),
+            UNetConfig {
+                mid_block: false,
+                layers_per_block: 2,
+                down_block_attention: vec![true, true],
+                ..micro_config()
+            },
+        ] {
+            let store = tensor_store::from_bytes(&random_weights(&config));
+            let unet = UNet2DConditionModel::from_tensors(store).unwrap();
+            assert_eq!(unet.config(), &config);
+        }
+    }
+
+    #[test]
+    fn malformed_weights_are_rejected() {
+        let mut tensors: Vec<_> = micro_config()
+            .tensor_shapes()
+            .into_iter()
+            .map(|(name, shape)| {
+                let size = shape.iter().product();
+                (name, shape, vec![0.0; size])
+            })
+            .collect();
+        let conv_out = tensors
+            .iter_mut()
+            .find(|(name, _, _)| name == "conv_out.weight")
+            .unwrap();
+        *conv_out = (conv_out.0.clone(), Vec::new(), vec![0.0]);
+
+        let store = tensor_store::from_bytes(&safetensors::serialize(&tensors));
+        let error = UNet2DConditionModel::from_tensors(store).err().unwrap();
+        assert!(error.contains("conv_out.weight"), "{}", error);
+    }
+
+    #[test]
+    fn predicts_noise_of_the_latent_shape() {
+        let config = micro_config();
+        let store = tensor_store::from_bytes(&random_weights(&config));
+        let unet = UNet2DConditionModel::from_tensors(store).unwrap();
+
+        let mut generator = TorchGenerator::new(0);
+        let latents = Tensor::new(&[2, 4, 4, 6], generator.randn(2 * 4 * 4 * 6));
+        let context = Tensor::new(&[2, 5, 12], generator.randn(2 * 5 * 12));
+        let output = unet.forward(&latents, 500, &context).unwrap();
+        assert_eq!(output.shape(), [2, 4, 4, 6]);
+        assert!(output.values().iter().all(|v| v.is_finite()));
+
+        // Each batch entry only sees its own conditioning
+        let single = unet
+            .forward(&latents.narrow(0, 1, 1), 500, &context.narrow(0, 1, 1))
+            .unwrap();
+        for (a, b) in single.values().iter().zip(&output.values()[96..]) {
+            assert!((a - b).abs() < 1e-5);
+        }
+
+        let odd = Tensor::zeros(&[1, 4, 3, 4]);
+        assert!(unet.forward(&odd, 500, &context.narrow(0, 0, 1)).is_err());
+        let wide = Tensor::zeros(&[1, 5, 16]);
+        assert!(unet.forward(&latents.narrow(0, 0, 1), 500, &wide).is_err());
+    }
+
+    // Expected values from an independent float64 implementation of the
+    // diffusers UNet2DConditionModel modules, run on the same weights
+    #[test]
+    fn matches_reference_values() {
+        let store = tensor_store::from_bytes(&random_weights(&micro_config()));
+        let unet = UNet2DConditionModel::from_tensors(store).unwrap();
+        let mut generator = TorchGenerator::new(0);
+        let latents = Tensor::new(&[1, 4, 4, 4], generator.randn(64));
+        let context = Tensor::new(&[1, 3, 12], generator.randn(36));
+        let output = unet.forward(&latents, 500, &context).unwrap();
+
+        let expected = [
+            0.159648, 0.031511, -0.052525, -0.015716, 0.178011, 0.017520, -0.097968, -0.050481,
+            0.107077, 0.041422, -0.078656, -0.095068, 0.097247, 0.089197, 0.208275, 0.065344,
+            0.056810, 0.055286, 0.036925, -0.093355, 0.144694, 0.138381, 0.126158, -0.115238,
+            0.123432, 0.057241, 0.059099, -0.042568, 0.041044, -0.098792, -0.011072, -0.029897,
+            0.157270, 0.186482, 0.168774, 0.166145, 0.151060, 0.134034, 0.179325, 0.113432,
+            0.103572, 0.171678, 0.199910, 0.260592, 0.114869, 0.153105, 0.199936, 0.246856,
+            -0.235171, -0.390536, -0.280241, -0.314891, -0.254366, -0.474064, -0.399966, -0.357701,
+            -0.269832, -0.245660, -0.285361, -0.336888, -0.045424, -0.138139, -0.288487, -0.120648,
+        ];
+        for (i, (a, b)) in output.values().iter().zip(expected).enumerate() {
+            assert!((a - b).abs() < 1e-4, "{}: {} != {}", i, a, b);
+        }
+    }
+}
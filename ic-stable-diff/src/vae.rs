@@ -0,0 +1,1099 @@
+// VAE encoder and decoders
+//
+// AutoencoderDecoder is the decoder half of the Stable Diffusion
+// AutoencoderKL in the diffusers layout: latents are divided by the scaling
+// factor, passed through post_quant_conv, a mid block with single-head
+// self-attention, and up blocks of ResNets that double the resolution at each
+// stage, and come out as RGB in [-1, 1]. As with the UNet, the architecture is
+// read off the uploaded weights and the weights are paged in from stable
+// memory during decoding. Until weights are uploaded the pipeline falls back
+// to MockVAEDecoder, which paints a pattern derived from the latents.
+//
+// Decoding a whole image at once needs several full-resolution activations
+// of 128 or more channels, so large images are decoded as overlapping tiles
+// by TiledPass, a few per message, and blended across the seams.
+//
+// AutoencoderEncoder is the matching encoder half, read from the same
+// weights file, which turns an init image into latents for img2img: down
+// blocks of ResNets halve the resolution at each stage, and the latent
+// distribution predicted by quant_conv is sampled and scaled. Init images
+// are encoded in tiles too, blending the predicted distributions before the
+// latents are sampled from them.
+
+use crate::VAE_SCALE_FACTOR;
+use crate::image_codec::RgbImage;
+use crate::rng::TorchGenerator;
+use crate::tensor::{self, Tensor};
+use crate::tensor_store::{TensorStore, Weights};
+use candid::CandidType;
+use ic_stable_structures::Memory;
+use serde::Deserialize;
+
+const NORM_EPS: f32 = 1e-6;
+const DEFAULT_NORM_GROUPS: usize = 32;
+// SD 1.x and 2.x; SDXL's VAE declares its own in the file metadata
+const DEFAULT_SCALING_FACTOR: f32 = 0.18215;
+const ATTENTION_CHUNK: usize = 1024;
+
+#[derive(Clone)]
+pub enum VAEDecoder {
+    Mock(MockVAEDecoder),
+    Autoencoder(Box<AutoencoderDecoder<crate::Memory>>),
+}
+
+impl VAEDecoder {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VAEDecoder::Mock(_) => "mock",
+            VAEDecoder::Autoencoder(_) => "autoencoder-kl",
+        }
+    }
+
+    // The mock pattern spans the whole image, so it is decoded in one piece
+    pub fn supports_tiling(&self) -> bool {
+        matches!(self, VAEDecoder::Autoencoder(_))
+    }
+
+    // Decode `[1, latent_channels, h, w]` latents to `[1, 3, h * 8, w * 8]`
+    // pixels in [-1, 1]
+    pub fn forward(&self, latents: &Tensor) -> Result<Tensor, String> {
+        match self {
+            VAEDecoder::Mock(decoder) => decoder.forward(latents),
+            VAEDecoder::Autoencoder(decoder) => decoder.forward(latents),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum VAEEncoder {
+    Mock(MockVAEEncoder),
+    Autoencoder(Box<AutoencoderEncoder<crate::Memory>>),
+}
+
+impl VAEEncoder {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VAEEncoder::Mock(_) => "mock",
+            VAEEncoder::Autoencoder(_) => "autoencoder-kl",
+        }
+    }
+
+    // Latent distribution `[1, channels, h / 8, w / 8]` of `[1, 3, h, w]`
+    // pixels in [-1, 1], which `sample` turns into latents
+    pub fn moments(&self, pixels: &Tensor) -> Result<Tensor, String> {
+        match self {
+            VAEEncoder::Mock(encoder) => encoder.encode(pixels),
+            VAEEncoder::Autoencoder(encoder) => encoder.moments(pixels),
+        }
+    }
+
+    // Scaled `[1, latent_channels, h, w]` latents drawn from `moments` with
+    // `generator`; the mock's latents are not random
+    pub fn sample(
+        &self,
+        moments: &Tensor,
+        generator: &mut TorchGenerator,
+    ) -> Result<Tensor, String> {
+        match self {
+            VAEEncoder::Mock(_) => Ok(moments.clone()),
+            VAEEncoder::Autoencoder(encoder) => encoder.sample(moments, generator),
+        }
+    }
+}
+
+// Convert `[1, 3, h, w]` pixels in [-1, 1] to an 8-bit image
+pub fn to_image(pixels: &Tensor) -> RgbImage {
+    let [1, 3, height, width] = pixels.shape()[..] else {
+        panic!("pixels have shape {:?}", pixels.shape());
+    };
+    let plane = height * width;
+    let values = pixels.values();
+    let channel = |c: usize, i: usize| {
+        let v = (values[c * plane + i] / 2.0 + 0.5).clamp(0.0, 1.0);
+        (v * 255.0).round() as u8
+    };
+
+    let mut image = RgbImage::new(width as u32, height as u32);
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            image.set_pixel(
+                x as u32,
+                y as u32,
+                [channel(0, i), channel(1, i), channel(2, i)],
+            );
+        }
+    }
+    image
+}
+
+// A tiled encode or decode in progress, checkpointed between messages.
+// Tiles are squares of `tile` latents overlapping by `overlap`, or the
+// pixels above them when encoding; each tile's output is weighted by a ramp
+// that rises across the overlap with its neighbours, and the running
+// weighted sums are normalized once every tile is in.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TiledPass {
+    tile: u32,
+    overlap: u32,
+    // Top and left latent coordinates of the tile rows and columns
+    rows: Vec<u32>,
+    columns: Vec<u32>,
+    next_tile: u32,
+    // Values per latent along each axis of the input and the output: 1 and
+    // 8 when decoding, 8 and 1 when encoding
+    input_scale: u32,
+    output_scale: u32,
+    // [channels, height, width] weighted output sums, sized by the first
+    // tile, and the per-position weight totals
+    sums: Vec<f32>,
+    weights: Vec<f32>,
+    width: u32,
+    height: u32,
+}
+
+impl TiledPass {
+    // Plan the tiles decoding `[1, c, h, w]` latents. `tile` is in latents;
+    // a tile at least as large as the image decodes it in one pass.
+    pub fn decode(latent_shape: &[usize], tile: usize) -> Self {
+        Self::new(latent_shape[2], latent_shape[3], tile, 1, VAE_SCALE_FACTOR)
+    }
+
+    // Plan the tiles encoding `[1, 3, h, w]` pixels, `tile` latents apart
+    pub fn encode(pixel_shape: &[usize], tile: usize) -> Self {
+        let scale = VAE_SCALE_FACTOR as usize;
+        let (height, width) = (pixel_shape[2] / scale, pixel_shape[3] / scale);
+        Self::new(height, width, tile, VAE_SCALE_FACTOR, 1)
+    }
+
+    fn new(
+        latent_height: usize,
+        latent_width: usize,
+        tile: usize,
+        input_scale: u32,
+        output_scale: u32,
+    ) -> Self {
+        let tile = tile.min(latent_height.max(latent_width)).max(1);
+        let overlap = tile / 4;
+        let scale = output_scale as usize;
+        let (width, height) = (latent_width * scale, latent_height * scale);
+
+        Self {
+            tile: tile as u32,
+            overlap: overlap as u32,
+            rows: tile_starts(latent_height, tile, overlap),
+            columns: tile_starts(latent_width, tile, overlap),
+            next_tile: 0,
+            input_scale,
+            output_scale,
+            sums: Vec::new(),
+            weights: vec![0.0; width * height],
+            width: width as u32,
+            height: height as u32,
+        }
+    }
+
+    pub fn total_tiles(&self) -> u32 {
+        (self.rows.len() * self.columns.len()) as u32
+    }
+
+    pub fn tiles_done(&self) -> u32 {
+        self.next_tile
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next_tile >= self.total_tiles()
+    }
+
+    // Run `pass` on up to `max_tiles` more tiles of `input`. Decoding maps
+    // `[1, c, h, w]` latents to `[1, 3, h * 8, w * 8]` pixels, encoding the
+    // other way round.
+    pub fn run_tiles(
+        &mut self,
+        input: &Tensor,
+        max_tiles: usize,
+        pass: impl Fn(&Tensor) -> Result<Tensor, String>,
+    ) -> Result<(), String> {
+        let (input_scale, output_scale) = (self.input_scale as usize, self.output_scale as usize);
+        let (width, height) = (self.width as usize, self.height as usize);
+        let plane = width * height;
+        let (latent_height, latent_width) =
+            (input.dim(2) / input_scale, input.dim(3) / input_scale);
+
+        for _ in 0..max_tiles {
+            if self.is_finished() {
+                break;
+            }
+            let index = self.next_tile as usize;
+            let top = self.rows[index / self.columns.len()] as usize;
+            let left = self.columns[index % self.columns.len()] as usize;
+            let tile_height = (self.tile as usize).min(latent_height);
+            let tile_width = (self.tile as usize).min(latent_width);
+
+            let tile = input
+                .narrow(2, top * input_scale, tile_height * input_scale)
+                .narrow(3, left * input_scale, tile_width * input_scale)
+                .contiguous();
+            let output = pass(&tile)?;
+            let (out_height, out_width) = (tile_height * output_scale, tile_width * output_scale);
+            let channels = output.dim(1);
+            output.check_shape(&[1, channels, out_height, out_width])?;
+            if self.sums.is_empty() {
+                self.sums = vec![0.0; channels * plane];
+            } else if self.sums.len() != channels * plane {
+                return Err(format!("Tile {} has {} channels", index, channels));
+            }
+
+            let overlap = self.overlap as usize * output_scale;
+            let ramp_y = edge_ramp(
+                out_height,
+                overlap,
+                top > 0,
+                top + tile_height < latent_height,
+            );
+            let ramp_x = edge_ramp(
+                out_width,
+                overlap,
+                left > 0,
+                left + tile_width < latent_width,
+            );
+            let values = output.values();
+            let tile_plane = out_height * out_width;
+            for y in 0..out_height {
+                for x in 0..out_width {
+                    let weight = ramp_y[y] * ramp_x[x];
+                    let position = (top * output_scale + y) * width + left * output_scale + x;
+                    self.weights[position] += weight;
+                    for c in 0..channels {
+                        self.sums[c * plane + position] +=
+                            weight * values[c * tile_plane + y * out_width + x];
+                    }
+                }
+            }
+            self.next_tile += 1;
+        }
+        Ok(())
+    }
+
+    // Blended `[1, channels, height, width]` output of a finished pass
+    pub fn output(&self) -> Tensor {
+        let plane = self.weights.len();
+        let values = self
+            .sums
+            .iter()
+            .enumerate()
+            .map(|(i, &sum)| sum / self.weights[i % plane])
+            .collect();
+        let channels = self.sums.len() / plane;
+        Tensor::new(
+            &[1, channels, self.height as usize, self.width as usize],
+            values,
+        )
+    }
+}
+
+// Start of every tile along an axis of `len`, the last one flush with the end
+fn tile_starts(len: usize, tile: usize, overlap: usize) -> Vec<u32> {
+    if len <= tile {
+        return vec![0];
+    }
+    let stride = tile - overlap;
+    let mut starts: Vec<u32> = (0..)
+        .map(|i| i * stride)
+        .take_while(|&start| start + tile < len)
+        .map(|start| start as u32)
+        .collect();
+    starts.push((len - tile) as u32);
+    starts
+}
+
+// Blend weights along one tile axis: rising over `overlap` pixels from each
+// edge shared with a neighbouring tile, flat elsewhere
+fn edge_ramp(len: usize, overlap: usize, before: bool, after: bool) -> Vec<f32> {
+    (0..len)
+        .map(|i| {
+            let mut weight = 1.0f32;
+            if overlap > 0 {
+                if before {
+                    weight = weight.min((i as f32 + 0.5) / overlap as f32);
+                }
+                if after {
+                    weight = weight.min(((len - i) as f32 - 0.5) / overlap as f32);
+                }
+            }
+            weight.min(1.0)
+        })
+        .collect()
+}
+
+// Convert an 8-bit image to `[1, 3, h, w]` pixels in [-1, 1]
+pub fn from_image(image: &RgbImage) -> Tensor {
+    let plane = (image.width * image.height) as usize;
+    let mut pixels = vec![0.0; 3 * plane];
+    for (i, rgb) in image.pixels.chunks(3).enumerate() {
+        for (c, &value) in rgb.iter().enumerate() {
+            pixels[c * plane + i] = value as f32 / 127.5 - 1.0;
+        }
+    }
+    Tensor::new(&[1, 3, image.height as usize, image.width as usize], pixels)
+}
+
+#[derive(Clone)]
+pub struct MockVAEDecoder {
+    latent_channels: usize,
+}
+
+impl MockVAEDecoder {
+    pub fn new() -> Self {
+        Self { latent_channels: 4 }
+    }
+
+    fn forward(&self, latents: &Tensor) -> Result<Tensor, String> {
+        let (latent_height, latent_width) = match latents.shape()[..] {
+            [1, channels, h, w] if channels == self.latent_channels => (h as u32, w as u32),
+            _ => {
+                return Err(format!(
+                    "Expected latents of shape [1, {}, h, w], got {:?}",
+                    self.latent_channels,
+                    latents.shape()
+                ));
+            }
+        };
+
+        let width = latent_width * VAE_SCALE_FACTOR;
+        let height = latent_height * VAE_SCALE_FACTOR;
+        let latents = latents.values();
+
+        // Generate a pattern based on latents that looks more like generated art
+        let latent_sum = latents.iter().sum::<f32>() / latents.len() as f32;
+        let latent_variance = latents
+            .iter()
+            .map(|&x| (x - latent_sum).powi(2))
+            .sum::<f32>()
+            / latents.len() as f32;
+
+        let image = self.render(width, height, &latents, latent_sum, latent_variance);
+        Ok(from_image(&image))
+    }
+
+    fn render(
+        &self,
+        width: u32,
+        height: u32,
+        latents: &[f32],
+        avg: f32,
+        variance: f32,
+    ) -> RgbImage {
+        let mut image = RgbImage::new(width, height);
+
+        for y in 0..height {
+            for x in 0..width {
+                // Create interesting patterns based on the latent cell
+                // covering this pixel
+                let latent_idx = ((y / VAE_SCALE_FACTOR) * (width / VAE_SCALE_FACTOR)
+                    + x / VAE_SCALE_FACTOR) as usize;
+                let latent_val = latents[latent_idx];
+
+                // Generate colors based on position and latent values
+                let norm_x = x as f32 / width as f32;
+                let norm_y = y as f32 / height as f32;
+
+                let r = ((norm_x * 255.0) + (latent_val * 50.0) + (avg * 100.0)).clamp(0.0, 255.0)
+                    as u8;
+                let g = ((norm_y * 255.0) + (variance * 200.0) + (latent_val * 30.0))
+                    .clamp(0.0, 255.0) as u8;
+                let b = (((norm_x + norm_y) * 127.5) + (latent_val * 70.0)).clamp(0.0, 255.0) as u8;
+
+                image.set_pixel(x, y, [r, g, b]);
+            }
+        }
+
+        image
+    }
+}
+
+// Stand-in for the encoder until VAE weights are uploaded: each latent pixel
+// holds the average colour of the 8x8 cell it covers, so the init image
+// still shapes the result
+#[derive(Clone)]
+pub struct MockVAEEncoder {
+    latent_channels: usize,
+}
+
+impl MockVAEEncoder {
+    pub fn new() -> Self {
+        Self { latent_channels: 4 }
+    }
+
+    fn encode(&self, pixels: &Tensor) -> Result<Tensor, String> {
+        let (height, width) = pixel_size(pixels)?;
+        let scale = VAE_SCALE_FACTOR as usize;
+        let (latent_height, latent_width) = (height / scale, width / scale);
+
+        let values = pixels.values();
+        let mut latents = vec![0.0; self.latent_channels * latent_height * latent_width];
+        for c in 0..3 {
+            for y in 0..height {
+                for x in 0..width {
+                    let latent = (c * latent_height + y / scale) * latent_width + x / scale;
+                    latents[latent] += values[(c * height + y) * width + x];
+                }
+            }
+        }
+        let cell = (scale * scale) as f32;
+        Ok(Tensor::new(
+            &[1, self.latent_channels, latent_height, latent_width],
+            latents.into_iter().map(|v| v / cell).collect(),
+        ))
+    }
+}
+
+// Height and width of `[1, 3, h, w]` pixels that encode to whole latents
+fn pixel_size(pixels: &Tensor) -> Result<(usize, usize), String> {
+    let scale = VAE_SCALE_FACTOR as usize;
+    match pixels.shape()[..] {
+        [1, 3, height, width]
+            if height > 0 && width > 0 && height % scale == 0 && width % scale == 0 =>
+        {
+            Ok((height, width))
+        }
+        _ => Err(format!(
+            "Expected pixels of shape [1, 3, h, w] with h and w multiples of {}, got {:?}",
+            scale,
+            pixels.shape()
+        )),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VAEConfig {
+    pub latent_channels: usize,
+    pub out_channels: usize,
+    // Widths of the encoder stages, shallowest first; the decoder runs them
+    // in reverse
+    pub block_out_channels: Vec<usize>,
+    // Resnets per encoder stage; decoder stages have one more
+    pub layers_per_block: usize,
+    pub norm_num_groups: usize,
+    pub scaling_factor: f32,
+    // Mid-block attention uses the older query/key/value/proj_attn names
+    pub legacy_attention: bool,
+}
+
+impl VAEConfig {
+    // The Stable Diffusion 1.x and 2.x autoencoder
+    pub fn sd() -> Self {
+        Self {
+            latent_channels: 4,
+            out_channels: 3,
+            block_out_channels: vec![128, 256, 512, 512],
+            layers_per_block: 2,
+            norm_num_groups: DEFAULT_NORM_GROUPS,
+            scaling_factor: DEFAULT_SCALING_FACTOR,
+            legacy_attention: false,
+        }
+    }
+
+    // Read the decoder architecture off the weight names and shapes
+    pub fn infer<M: Memory>(tensors: &TensorStore<M>) -> Result<Self, String> {
+        let count = |name: &dyn Fn(usize) -> String| {
+            (0..).take_while(|&i| tensors.contains(&name(i))).count()
+        };
+
+        let latent_channels = tensors.dim("post_quant_conv.weight", 0)?;
+        let out_channels = tensors.dim("decoder.conv_out.weight", 0)?;
+
+        let blocks = count(&|i| format!("decoder.up_blocks.{}.resnets.0.conv1.weight", i));
+        if blocks == 0 {
+            return Err("VAE weights contain no decoder up blocks".to_string());
+        }
+        let mut block_out_channels = (0..blocks)
+            .map(|i| {
+                let name = format!("decoder.up_blocks.{}.resnets.0.conv1.weight", i);
+                tensors.dim(&name, 0)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        block_out_channels.reverse();
+        let layers_per_block =
+            count(&|j| format!("decoder.up_blocks.0.resnets.{}.conv1.weight", j)).saturating_sub(1);
+
+        let metadata = |key: &str| tensors.metadata().get(key).cloned().unwrap_or_default();
+        Ok(Self {
+            latent_channels,
+            out_channels,
+            block_out_channels,
+            layers_per_block,
+            norm_num_groups: metadata("norm_num_groups")
+                .parse()
+                .unwrap_or(DEFAULT_NORM_GROUPS),
+            scaling_factor: metadata("scaling_factor")
+                .parse()
+                .unwrap_or(DEFAULT_SCALING_FACTOR),
+            legacy_attention: tensors.contains("decoder.mid_block.attentions.0.query.weight"),
+        })
+    }
+
+    // How many times the decoder upsamples each latent pixel
+    pub fn upscale(&self) -> usize {
+        1 << (self.block_out_channels.len() - 1)
+    }
+
+    // Name and shape of every decoder weight, in the diffusers naming scheme
+    pub fn decoder_shapes(&self) -> Vec<(String, Vec<usize>)> {
+        let mut shapes = Vec::new();
+        let latent = self.latent_channels;
+        let blocks = self.block_out_channels.len();
+        let top = self.block_out_channels[blocks - 1];
+
+        conv_shapes(&mut shapes, "post_quant_conv", latent, latent, 1);
+        conv_shapes(&mut shapes, "decoder.conv_in", latent, top, 3);
+        self.mid_block_shapes(&mut shapes, "decoder.mid_block", top);
+
+        let mut channels = top;
+        for (i, &out) in self.block_out_channels.iter().rev().enumerate() {
+            for j in 0..=self.layers_per_block {
+                let resnet = format!("decoder.up_blocks.{}.resnets.{}", i, j);
+                resnet_shapes(&mut shapes, &resnet, channels, out);
+                channels = out;
+            }
+            if i + 1 < blocks {
+                let upsampler = format!("decoder.up_blocks.{}.upsamplers.0.conv", i);
+                conv_shapes(&mut shapes, &upsampler, out, out, 3);
+            }
+        }
+
+        let c0 = self.block_out_channels[0];
+        norm_shapes(&mut shapes, "decoder.conv_norm_out", c0);
+        conv_shapes(&mut shapes, "decoder.conv_out", c0, self.out_channels, 3);
+        shapes
+    }
+
+    // Architecture checks shared by the encoder and decoder
+    fn validate(&self) -> Result<(), String> {
+        if self.upscale() != VAE_SCALE_FACTOR as usize {
+            return Err(format!(
+                "VAE upsamples latents {}x, the pipeline expects {}x",
+                self.upscale(),
+                VAE_SCALE_FACTOR
+            ));
+        }
+        if let Some(&channels) = self
+            .block_out_channels
+            .iter()
+            .find(|&&c| c % self.norm_num_groups != 0)
+        {
+            return Err(format!(
+                "{} channels do not split into {} groups",
+                channels, self.norm_num_groups
+            ));
+        }
+        Ok(())
+    }
+
+    // Name and shape of every encoder weight, including quant_conv
+    pub fn encoder_shapes(&self) -> Vec<(String, Vec<usize>)> {
+        let mut shapes = Vec::new();
+        let blocks = self.block_out_channels.len();
+        let top = self.block_out_channels[blocks - 1];
+        let moments = 2 * self.latent_channels;
+
+        let c0 = self.block_out_channels[0];
+        conv_shapes(&mut shapes, "encoder.conv_in", self.out_channels, c0, 3);
+        let mut channels = c0;
+        for (i, &out) in self.block_out_channels.iter().enumerate() {
+            for j in 0..self.layers_per_block {
+                let resnet = format!("encoder.down_blocks.{}.resnets.{}", i, j);
+                resnet_shapes(&mut shapes, &resnet, channels, out);
+                channels = out;
+            }
+            if i + 1 < blocks {
+                let downsampler = format!("encoder.down_blocks.{}.downsamplers.0.conv", i);
+                conv_shapes(&mut shapes, &downsampler, out, out, 3);
+            }
+        }
+
+        self.mid_block_shapes(&mut shapes, "encoder.mid_block", top);
+        norm_shapes(&mut shapes, "encoder.conv_norm_out", top);
+        conv_shapes(&mut shapes, "encoder.conv_out", top, moments, 3);
+        conv_shapes(&mut shapes, "quant_conv", moments, moments, 1);
+        shapes
+    }
+
+    fn mid_block_shapes(&self, shapes: &mut Vec<(String, Vec<usize>)>, prefix: &str, c: usize) {
+        resnet_shapes(shapes, &format!("{}.resnets.0", prefix), c, c);
+        let attention = format!("{}.attentions.0", prefix);
+        norm_shapes(shapes, &format!("{}.group_norm", attention), c);
+        for projection in self.attention_names() {
+            linear_shapes(shapes, &format!("{}.{}", attention, projection), c, c);
+        }
+        resnet_shapes(shapes, &format!("{}.resnets.1", prefix), c, c);
+    }
+
+    // Query, key, value and output projections of the mid-block attention
+    fn attention_names(&self) -> [&'static str; 4] {
+        if self.legacy_attention {
+            ["query", "key", "value", "proj_attn"]
+        } else {
+            ["to_q", "to_k", "to_v", "to_out.0"]
+        }
+    }
+
+    // ResNet block without a timestep embedding
+    fn resnet<M: Memory>(
+        &self,
+        w: &mut Weights<M>,
+        prefix: &str,
+        input: &Tensor,
+    ) -> Result<Tensor, String> {
+        let groups = self.norm_num_groups;
+        let hidden = w
+            .group_norm(&format!("{}.norm1", prefix), input, groups, NORM_EPS)?
+            .silu();
+        let hidden = w.conv(&format!("{}.conv1", prefix), &hidden, 1)?;
+        let hidden = w
+            .group_norm(&format!("{}.norm2", prefix), &hidden, groups, NORM_EPS)?
+            .silu();
+        let hidden = w.conv(&format!("{}.conv2", prefix), &hidden, 1)?;
+
+        if hidden.dim(1) != input.dim(1) {
+            let shortcut = w.conv(&format!("{}.conv_shortcut", prefix), input, 1)?;
+            Ok(shortcut.add(&hidden))
+        } else {
+            Ok(input.add(&hidden))
+        }
+    }
+
+    // Single-head self-attention over all spatial positions
+    fn attention<M: Memory>(
+        &self,
+        w: &mut Weights<M>,
+        prefix: &str,
+        input: &Tensor,
+    ) -> Result<Tensor, String> {
+        let [batch, channels, height, width] = input.shape()[..] else {
+            unreachable!("attention input is NCHW");
+        };
+        let tokens = height * width;
+
+        let groups = self.norm_num_groups;
+        let hidden = w.group_norm(&format!("{}.group_norm", prefix), input, groups, NORM_EPS)?;
+        let hidden = hidden
+            .reshape(&[batch, channels, tokens])
+            .transpose(1, 2)
+            .contiguous();
+
+        let [query, key, value, output] = self.attention_names();
+        let query = w.linear(&format!("{}.{}", prefix, query), &hidden)?;
+        let key = w.linear(&format!("{}.{}", prefix, key), &hidden)?;
+        let value = w.linear(&format!("{}.{}", prefix, value), &hidden)?;
+        let attended = tensor::multi_head_attention(&query, &key, &value, 1, ATTENTION_CHUNK);
+        let attended = w.linear(&format!("{}.{}", prefix, output), &attended)?;
+
+        let attended = attended
+            .transpose(1, 2)
+            .reshape(&[batch, channels, height, width]);
+        Ok(attended.add(input))
+    }
+}
+
+fn linear_shapes(shapes: &mut Vec<(String, Vec<usize>)>, name: &str, input: usize, output: usize) {
+    shapes.push((format!("{}.weight", name), vec![output, input]));
+    shapes.push((format!("{}.bias", name), vec![output]));
+}
+
+fn conv_shapes(
+    shapes: &mut Vec<(String, Vec<usize>)>,
+    name: &str,
+    input: usize,
+    output: usize,
+    kernel: usize,
+) {
+    shapes.push((
+        format!("{}.weight", name),
+        vec![output, input, kernel, kernel],
+    ));
+    shapes.push((format!("{}.bias", name), vec![output]));
+}
+
+fn norm_shapes(shapes: &mut Vec<(String, Vec<usize>)>, name: &str, channels: usize) {
+    shapes.push((format!("{}.weight", name), vec![channels]));
+    shapes.push((format!("{}.bias", name), vec![channels]));
+}
+
+fn resnet_shapes(
+    shapes: &mut Vec<(String, Vec<usize>)>,
+    prefix: &str,
+    input: usize,
+    output: usize,
+) {
+    norm_shapes(shapes, &format!("{}.norm1", prefix), input);
+    conv_shapes(shapes, &format!("{}.conv1", prefix), input, output, 3);
+    norm_shapes(shapes, &format!("{}.norm2", prefix), output);
+    conv_shapes(shapes, &format!("{}.conv2", prefix), output, output, 3);
+    if input != output {
+        conv_shapes(
+            shapes,
+            &format!("{}.conv_shortcut", prefix),
+            input,
+            output,
+            1,
+        );
+    }
+}
+
+#[derive(Clone)]
+pub struct AutoencoderDecoder<M: Memory> {
+    config: VAEConfig,
+    tensors: TensorStore<M>,
+}
+
+impl<M: Memory> AutoencoderDecoder<M> {
+    // Check the uploaded weights against the architecture they imply
+    pub fn from_tensors(tensors: TensorStore<M>) -> Result<Self, String> {
+        let config = VAEConfig::infer(&tensors)?;
+        config.validate()?;
+        for (name, shape) in config.decoder_shapes() {
+            tensors.view_with_shape(&name, &shape)?;
+        }
+        Ok(Self { config, tensors })
+    }
+
+    pub fn config(&self) -> &VAEConfig {
+        &self.config
+    }
+
+    // Decode `[1, latent_channels, h, w]` latents to `[1, 3, h * 8, w * 8]`
+    // pixels in [-1, 1]
+    pub fn forward(&self, latents: &Tensor) -> Result<Tensor, String> {
+        let config = &self.config;
+        match latents.shape()[..] {
+            [1, channels, _, _] if channels == config.latent_channels => {}
+            _ => {
+                return Err(format!(
+                    "Expected latents of shape [1, {}, h, w], got {:?}",
+                    config.latent_channels,
+                    latents.shape()
+                ));
+            }
+        }
+
+        let mut weights = Weights::new(&self.tensors);
+        let w = &mut weights;
+        let blocks = config.block_out_channels.len();
+
+        let latents = latents.scale(1.0 / config.scaling_factor);
+        let latents = w.conv("post_quant_conv", &latents, 1)?;
+        let mut sample = w.conv("decoder.conv_in", &latents, 1)?;
+
+        sample = config.resnet(w, "decoder.mid_block.resnets.0", &sample)?;
+        sample = config.attention(w, "decoder.mid_block.attentions.0", &sample)?;
+        sample = config.resnet(w, "decoder.mid_block.resnets.1", &sample)?;
+
+        for i in 0..blocks {
+            for j in 0..=config.layers_per_block {
+                let resnet = format!("decoder.up_blocks.{}.resnets.{}", i, j);
+                sample = config.resnet(w, &resnet, &sample)?;
+            }
+            if i + 1 < blocks {
+                sample = sample.upsample_nearest2d(2);
+                let upsampler = format!("decoder.up_blocks.{}.upsamplers.0.conv", i);
+                sample = w.conv(&upsampler, &sample, 1)?;
+            }
+        }
+
+        let sample = w
+            .group_norm(
+                "decoder.conv_norm_out",
+                &sample,
+                config.norm_num_groups,
+                NORM_EPS,
+            )?
+            .silu();
+        w.conv("decoder.conv_out", &sample, 1)
+    }
+}
+
+#[derive(Clone)]
+pub struct AutoencoderEncoder<M: Memory> {
+    config: VAEConfig,
+    tensors: TensorStore<M>,
+}
+
+impl<M: Memory> AutoencoderEncoder<M> {
+    // The encoder shares its architecture with the decoder in the same file
+    pub fn from_tensors(tensors: TensorStore<M>) -> Result<Self, String> {
+        let config = VAEConfig::infer(&tensors)?;
+        config.validate()?;
+        for (name, shape) in config.encoder_shapes() {
+            tensors.view_with_shape(&name, &shape)?;
+        }
+        Ok(Self { config, tensors })
+    }
+
+    pub fn config(&self) -> &VAEConfig {
+        &self.config
+    }
+
+    // Encode `[1, 3, h, w]` pixels in [-1, 1] to a sample of the latent
+    // distribution, multiplied by the scaling factor like the latents the
+    // UNet denoises
+    pub fn encode(
+        &self,
+        pixels: &Tensor,
+        generator: &mut TorchGenerator,
+    ) -> Result<Tensor, String> {
+        self.sample(&self.moments(pixels)?, generator)
+    }
+
+    // Mean and log-variance `[1, 2 * latent_channels, h / 8, w / 8]` of the
+    // latent distribution of `[1, 3, h, w]` pixels
+    pub fn moments(&self, pixels: &Tensor) -> Result<Tensor, String> {
+        pixel_size(pixels)?;
+        let config = &self.config;
+        let mut weights = Weights::new(&self.tensors);
+        let w = &mut weights;
+        let blocks = config.block_out_channels.len();
+
+        let mut sample = w.conv("encoder.conv_in", pixels, 1)?;
+        for i in 0..blocks {
+            for j in 0..config.layers_per_block {
+                let resnet = format!("encoder.down_blocks.{}.resnets.{}", i, j);
+                sample = config.resnet(w, &resnet, &sample)?;
+            }
+            if i + 1 < blocks {
+                // Asymmetric padding, as in the original implementation
+                let downsampler = format!("encoder.down_blocks.{}.downsamplers.0.conv", i);
+                sample = w.conv_padded(&downsampler, &sample.pad_bottom_right(1, 1), 2, 0, true)?;
+            }
+        }
+
+        sample = config.resnet(w, "encoder.mid_block.resnets.0", &sample)?;
+        sample = config.attention(w, "encoder.mid_block.attentions.0", &sample)?;
+        sample = config.resnet(w, "encoder.mid_block.resnets.1", &sample)?;
+
+        let sample = w
+            .group_norm(
+                "encoder.conv_norm_out",
+                &sample,
+                config.norm_num_groups,
+                NORM_EPS,
+            )?
+            .silu();
+        let sample = w.conv("encoder.conv_out", &sample, 1)?;
+        w.conv("quant_conv", &sample, 1)
+    }
+
+    // Sample the diagonal Gaussian, with the log-variance clamped as in
+    // diffusers' DiagonalGaussianDistribution
+    pub fn sample(
+        &self,
+        moments: &Tensor,
+        generator: &mut TorchGenerator,
+    ) -> Result<Tensor, String> {
+        let config = &self.config;
+        let latent_channels = config.latent_channels;
+        moments.check_shape(&[1, 2 * latent_channels, moments.dim(2), moments.dim(3)])?;
+        let mean = moments.narrow(1, 0, latent_channels);
+        let logvar = moments.narrow(1, latent_channels, latent_channels);
+        let std = logvar.map(|v| (0.5 * v.clamp(-30.0, 20.0)).exp());
+        let noise = Tensor::new(mean.shape(), generator.randn(mean.numel()));
+        Ok(mean.add(&std.mul(&noise)).scale(config.scaling_factor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{safetensors, tensor_store};
+
+    fn micro_config() -> VAEConfig {
+        VAEConfig {
+            block_out_channels: vec![32, 32, 64, 64],
+            layers_per_block: 1,
+            ..VAEConfig::sd()
+        }
+    }
+
+    fn random_weights(config: &VAEConfig) -> Vec<u8> {
+        let mut generator = TorchGenerator::new(5);
+        let tensors: Vec<_> = config
+            .decoder_shapes()
+            .into_iter()
+            .chain(config.encoder_shapes())
+            .map(|(name, shape)| {
+                let size = shape.iter().product();
+                let values = generator.randn(size).iter().map(|v| v * 0.05).collect();
+                (name, shape, values)
+            })
+            .collect();
+        safetensors::serialize(&tensors)
+    }
+
+    #[test]
+    fn sd_decoder_layout_has_the_reference_parameter_count() {
+        let parameters: usize = VAEConfig::sd()
+            .decoder_shapes()
+            .iter()
+            .map(|(_, shape)| shape.iter().product::<usize>())
+            .sum();
+        assert_eq!(parameters, 49_490_199);
+
+        // The whole AutoencoderKL
+        let encoder: usize = VAEConfig::sd()
+            .encoder_shapes()
+            .iter()
+            .map(|(_, shape)| shape.iter().product::<usize>())
+            .sum();
+        assert_eq!(parameters + encoder, 83_653_863);
+    }
+
+    #[test]
+    fn encodes_pixels_to_an_eighth_of_the_size() {
+        let config = micro_config();
+        let store = tensor_store::from_bytes(&random_weights(&config));
+        let encoder = AutoencoderEncoder::from_tensors(store).unwrap();
+        assert_eq!(encoder.config(), &config);
+
+        let mut generator = TorchGenerator::new(1);
+        let pixels = Tensor::new(&[1, 3, 16, 24], generator.randn(3 * 16 * 24));
+        let latents = encoder.encode(&pixels, &mut generator).unwrap();
+        assert_eq!(latents.shape(), [1, 4, 2, 3]);
+        assert!(latents.values().iter().all(|v| v.is_finite()));
+        assert!(
+            encoder
+                .encode(&Tensor::zeros(&[1, 3, 12, 16]), &mut generator)
+                .is_err()
+        );
+
+        // The mock keeps each cell's average colour
+        let mut image = RgbImage::new(16, 8);
+        for x in 0..16 {
+            image.set_pixel(x, 0, [255, 0, if x < 8 { 0 } else { 255 }]);
+        }
+        let latents = MockVAEEncoder::new().encode(&from_image(&image)).unwrap();
+        assert_eq!(latents.shape(), [1, 4, 1, 2]);
+        let expected = [-0.75, -0.75, -1.0, -1.0, -1.0, -0.75, 0.0, 0.0];
+        for (a, b) in latents.values().iter().zip(expected) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn decodes_latents_to_eight_times_the_size() {
+        for config in [
+            micro_config(),
+            VAEConfig {
+                legacy_attention: true,
+                ..micro_config()
+            },
+        ] {
+            let store = tensor_store::from_bytes(&random_weights(&config));
+            let decoder = AutoencoderDecoder::from_tensors(store).unwrap();
+            assert_eq!(decoder.config(), &config);
+
+            let mut generator = TorchGenerator::new(0);
+            let latents = Tensor::new(&[1, 4, 2, 3], generator.randn(24));
+            let pixels = decoder.forward(&latents).unwrap();
+            assert_eq!(pixels.shape(), [1, 3, 16, 24]);
+            assert!(pixels.values().iter().all(|v| v.is_finite()));
+
+            let image = to_image(&pixels);
+            assert_eq!((image.width, image.height), (24, 16));
+            assert!(decoder.forward(&Tensor::zeros(&[1, 3, 2, 2])).is_err());
+        }
+    }
+
+    // Expected values from an independent float64 implementation of the
+    // diffusers AutoencoderKL modules, run on the same weights: the first
+    // pixels of the top row of each decoded channel, and every latent of an
+    // encoded image with the noise the generator goes on to draw
+    #[test]
+    fn matches_reference_values() {
+        let store = || tensor_store::from_bytes(&random_weights(&micro_config()));
+        let decoder = AutoencoderDecoder::from_tensors(store()).unwrap();
+        let latents = Tensor::new(&[1, 4, 2, 2], TorchGenerator::new(0).randn(16));
+        let pixels = decoder.forward(&latents).unwrap();
+        let expected = [
+            [
+                -0.118773, -0.081079, -0.074864, -0.072509, -0.080618, -0.078341, -0.084609,
+                -0.086863,
+            ],
+            [
+                0.144810, 0.137383, 0.137684, 0.135754, 0.141113, 0.142467, 0.139375, 0.139915,
+            ],
+            [
+                -0.000667, -0.008891, -0.007723, -0.010919, -0.010950, -0.007111, -0.005327,
+                -0.005080,
+            ],
+        ];
+        for (c, row) in expected.iter().enumerate() {
+            let top = &pixels.values()[c * 16 * 16..];
+            for (i, (a, b)) in top.iter().zip(row).enumerate() {
+                assert!((a - b).abs() < 1e-4, "decoded {}, {}: {} != {}", c, i, a, b);
+            }
+        }
+
+        let encoder = AutoencoderEncoder::from_tensors(store()).unwrap();
+        let mut generator = TorchGenerator::new(1);
+        let pixels = Tensor::new(&[1, 3, 16, 16], generator.randn(3 * 16 * 16));
+        let latents = encoder.encode(&pixels, &mut generator).unwrap();
+        let expected = [
+            -0.167360, 0.013126, 0.036735, 0.004004, 0.003829, -0.272242, -0.498146, -0.151308,
+            -0.079098, 0.279334, 0.084169, 0.234643, 0.059194, -0.170481, 0.184742, -0.027198,
+        ];
+        for (i, (a, b)) in latents.values().iter().zip(expected).enumerate() {
+            assert!((a - b).abs() < 1e-4, "encoded {}: {} != {}", i, a, b);
+        }
+    }
+
+    #[test]
+    fn tiles_cover_each_axis_with_overlap() {
+        assert_eq!(tile_starts(64, 32, 8), [0, 24, 32]);
+        assert_eq!(tile_starts(56, 32, 8), [0, 24]);
+        assert_eq!(tile_starts(20, 32, 8), [0]);
+
+        let ramp = edge_ramp(8, 4, true, false);
+        assert_eq!(ramp, [0.125, 0.375, 0.625, 0.875, 1.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn blended_tiles_reassemble_a_local_decoder() {
+        // A decoder whose pixels only depend on the latent beneath them is
+        // unaffected by tiling, whatever the seams
+        let decode =
+            |latents: &Tensor| Ok(latents.narrow(1, 0, 3).upsample_nearest2d(8).map(f32::tanh));
+        let mut generator = TorchGenerator::new(2);
+        let latents = Tensor::new(&[1, 4, 10, 13], generator.randn(4 * 10 * 13));
+
+        let mut tiled = TiledPass::decode(latents.shape(), 8);
+        assert_eq!(tiled.total_tiles(), 4);
+        while !tiled.is_finished() {
+            tiled.run_tiles(&latents, 3, decode).unwrap();
+        }
+        let whole = decode(&latents).unwrap();
+        for (a, b) in tiled.output().values().iter().zip(whole.values().iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+
+        // Likewise for an encoder, whose tiles are the pixels above them
+        let encode = |pixels: &Tensor| MockVAEEncoder::new().encode(pixels);
+        let pixels = Tensor::new(&[1, 3, 80, 104], generator.randn(3 * 80 * 104));
+        let mut tiled = TiledPass::encode(pixels.shape(), 8);
+        while !tiled.is_finished() {
+            tiled.run_tiles(&pixels, 1, encode).unwrap();
+        }
+        assert_eq!(tiled.tiles_done(), 4);
+        let whole = encode(&pixels).unwrap();
+        assert_eq!(tiled.output().shape(), whole.shape());
+        for (a, b) in tiled.output().values().iter().zip(whole.values().iter()) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn maps_unit_range_to_bytes() {
+        let pixels = Tensor::new(&[1, 3, 1, 2], vec![-1.0, 1.0, 0.0, -3.0, 2.0, 0.5]);
+        let image = to_image(&pixels);
+        assert_eq!(image.pixels, [0, 128, 255, 255, 0, 191]);
+    }
+}